@@ -3,7 +3,7 @@ use std::{ffi::OsStr, fmt::Display, path::Path, process::Command};
 use color_eyre::eyre::Result;
 use console::{style, Style};
 use tempfile::TempDir;
-use update_pr::{app::App, cli::Cli};
+use update_pr::Updater;
 
 lazy_static::lazy_static! {
     /// Style for the console output.
@@ -54,11 +54,9 @@ impl Runner {
             ))
         );
 
-        App::new(Cli {
-            working_dir: Some(local_dir.path().to_str().unwrap().to_string()),
-            delay: None,
-        })?
-        .run()?;
+        let outcome = Updater::open(local_dir.path())?.update()?;
+
+        println!("{}", STYLE.apply_to(format!("Update: {}", outcome)));
 
         println!(
             "{}",
@@ -80,9 +78,11 @@ impl Runner {
     fn setup_remote(&self) -> Result<()> {
         println!("{}", STYLE.apply_to("Setting up remote..."));
 
+        // The updater merges `main`, whatever the default branch of git is
         Command::new("git")
             .arg("init")
             .arg("--bare")
+            .arg("--initial-branch=main")
             .arg(self.remote_dir.path())
             .status()?;
 
@@ -94,6 +94,8 @@ impl Runner {
 
             Command::new("git")
                 .arg("push")
+                .arg("origin")
+                .arg("HEAD:main")
                 .current_dir(init_dir.path())
                 .status()?;
         }
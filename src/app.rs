@@ -1,123 +1,464 @@
-mod fetch;
-mod merge;
-mod push;
-mod repo;
-
-use color_eyre::eyre::Result;
-use fetch::Fetch;
-use git2::{Oid, Repository};
+pub mod auto_merge;
+pub mod ci;
+pub mod fetch;
+pub mod filters;
+pub mod hooks;
+pub mod lfs;
+pub mod lockfiles;
+pub mod merge;
+pub mod merge_drivers;
+pub mod merge_options;
+mod outcome;
+pub mod partial;
+pub mod pull_request;
+pub mod push;
+pub mod repo;
+pub mod rerere;
+pub mod submodules;
+pub mod targets;
+mod updater;
+pub mod worktrees;
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use clap::ValueEnum;
+use color_eyre::eyre::{Result, WrapErr};
+use console::style;
+use git2::{ErrorClass, ErrorCode};
 use humantime::Duration;
-use indicatif::ProgressBar;
-use merge::Merge;
-use push::Push;
-
-use crate::cli::Cli;
-
-static REMOTE_NAME: &str = "origin";
-static REMOTE_BRANCH: &str = "main";
-
+use tracing::Span;
+use tracing_appender::non_blocking::WorkerGuard;
+
+pub use outcome::{MergeOutcome, UpdateOutcome};
+#[cfg(test)]
+pub(crate) use updater::tests as fixture;
+pub use updater::{Credentials, Strategy, Updater};
+
+use filters::Filter;
+
+use crate::{
+    cli::Cli,
+    forge::{self, ForgeKind, GitHub, GitLab, Gitea},
+    logging,
+    progress::{
+        multi::{MultiDisplay, TargetReporter},
+        summary::{self, Status, Summary},
+        terminal::{TerminalReporter, Verbosity},
+    },
+    report::{Event, Reporter},
+    utils::git::redact_credentials,
+    webhooks::{WebhookFormat, Webhooks},
+};
+
+/// Runs an [`Updater`] for each repository given on the command line
 pub struct App {
-    repo: Repository,
+    targets: Vec<Target>,
+    reporter: Arc<TerminalReporter>,
+    verbosity: Verbosity,
+    jobs: usize,
     delay: Option<Duration>,
+    auto_merge: bool,
+    webhooks: Webhooks,
+    _log_guard: Option<WorkerGuard>,
+}
+
+/// A repository to update, or why it could not be opened
+struct Target {
+    name: String,
+    updater: Mutex<Result<Updater, git2::Error>>,
+    /// The line of the target when updates run in parallel
+    line: Option<Arc<TargetReporter>>,
 }
 
 impl App {
     pub fn new(cli: Cli) -> Result<Self> {
-        let working_dir = cli.working_dir.as_deref().unwrap_or(".");
+        cli.color.apply();
+        let log_guard = logging::init(cli.verbosity(), cli.log_file.as_deref(), cli.log_rotation)?;
+        let reporter = Arc::new(TerminalReporter::new().verbosity(cli.verbosity()));
+
+        let mut paths = cli.working_dirs.clone();
+        if let Some(manifest) = &cli.manifest {
+            paths.extend(
+                targets::read_manifest(manifest)
+                    .wrap_err_with(|| format!("could not read {}", manifest.display()))?,
+            );
+        }
+        if let Some(dir) = &cli.scan {
+            paths.extend(
+                targets::scan(dir).wrap_err_with(|| format!("could not scan {}", dir.display()))?,
+            );
+        }
+        if paths.is_empty() {
+            paths.push(PathBuf::from("."));
+        }
+
+        let names: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+        let display = (cli.jobs > 1 && paths.len() > 1).then(|| {
+            let width = names.iter().map(String::len).max().unwrap_or(0);
+            MultiDisplay::new(cli.verbosity()).name_width(width)
+        });
+
+        let targets = paths
+            .iter()
+            .zip(names)
+            .map(|(path, name)| {
+                let (updater, line) = match &display {
+                    Some(display) => {
+                        let line = Arc::new(display.reporter(&name));
+                        (Self::updater(&cli, path, line.clone()), Some(line))
+                    }
+                    None => (Self::updater(&cli, path, reporter.clone()), None),
+                };
+
+                Target {
+                    name,
+                    updater: Mutex::new(updater),
+                    line,
+                }
+            })
+            .collect();
 
         Ok(Self {
-            repo: Repository::open(working_dir)?,
+            targets,
+            reporter,
+            verbosity: cli.verbosity(),
+            jobs: cli.jobs,
             delay: cli.delay,
+            auto_merge: cli.auto_merge,
+            webhooks: Self::webhooks(&cli),
+            _log_guard: log_guard,
         })
     }
 
-    pub fn run(&self) -> Result<i32> {
-        let mut exit_code = 0;
+    /// Open the repository at `path` and configure its updater from the command line
+    pub(crate) fn updater<R: Reporter + 'static>(
+        cli: &Cli,
+        path: &Path,
+        reporter: R,
+    ) -> Result<Updater, git2::Error> {
+        let strategy = if cli.ff_only {
+            Strategy::FastForwardOnly
+        } else if cli.server_side {
+            Strategy::ServerSide
+        } else {
+            Strategy::Merge
+        };
+
+        let updater = match &cli.worktree {
+            Some(name) => Updater::open_worktree(path, name)?,
+            None => Updater::open(path)?,
+        };
+        let mut updater = updater
+            .reporter(reporter)
+            .remote(cli.remote.clone())
+            .base(cli.base.clone())
+            .strategy(strategy)
+            .deepen_limit(cli.deepen_limit)
+            .submodules(cli.submodules)
+            .no_verify(cli.no_verify)
+            .other_worktrees(cli.other_worktrees);
+        if let Some(tags) = cli.tags {
+            updater = updater.tags(tags);
+        }
+        if let Some(policy) = cli.gitlink_conflicts {
+            updater = updater.gitlink_conflicts(policy);
+        }
+        if cli.rerere {
+            updater = updater.rerere(true);
+        }
+        for option in Self::configured(updater.repository(), "update-pr.mergeOption")? {
+            updater = updater.merge_option(option);
+        }
+        for option in &cli.merge_option {
+            updater = updater.merge_option(option.clone());
+        }
+        for lockfile in Self::configured(updater.repository(), "update-pr.lockfile")? {
+            updater = updater.lockfile(lockfile);
+        }
+        for lockfile in &cli.lockfile {
+            updater = updater.lockfile(lockfile.clone());
+        }
+        if let Some(prune) = cli.prune() {
+            updater = updater.prune(prune);
+        }
+        let forge = if cli.github {
+            Some(Some(ForgeKind::GitHub))
+        } else {
+            cli.forge
+        };
+        let filters = Self::configured::<Filter>(updater.repository(), "update-pr.filter")?;
+        if let Some(kind) = forge {
+            updater = Self::with_forge(cli, updater, kind)?;
+
+            for filter in filters {
+                updater = updater.filter(filter);
+            }
+            for filter in &cli.filter {
+                updater = updater.filter(filter.clone());
+            }
+        } else if !filters.is_empty() {
+            // Like `--filter`, the configured filters need the pull request from the forge
+            tracing::warn!("update-pr.filter is ignored without a forge, use --forge");
+        }
+        if let Some(label) = &cli.conflict_label {
+            updater = updater.conflict_label(label);
+        }
+        if cli.auto_merge {
+            updater = updater
+                .merge_method(cli.merge_method)
+                .checks_poll(cli.checks_interval.into(), cli.checks_timeout.into());
+        }
+        if let Some(gate) = cli.ci_gate {
+            updater = updater.ci_gate(gate).ci_walk_limit(cli.ci_walk_limit);
+        }
 
-        loop {
-            if let Err(e) = self.try_git_ops() {
-                eprintln!("Error: {}", e);
-                exit_code = e.raw_code();
+        Ok(updater)
+    }
 
-                break;
+    /// Talk to the forge of the remote, `kind` being taken from the config or the url of the remote when not given
+    fn with_forge(
+        cli: &Cli,
+        updater: Updater,
+        kind: Option<ForgeKind>,
+    ) -> Result<Updater, git2::Error> {
+        let url = updater
+            .repository()
+            .find_remote(&cli.remote)?
+            .url()
+            .unwrap_or_default()
+            .to_string();
+
+        let configured = match updater.repository().config()?.get_string("update-pr.forge") {
+            Ok(value) => Some(ForgeKind::from_str(&value, true).map_err(|_| {
+                git2::Error::from_str(&format!("invalid update-pr.forge {:?}", value))
+            })?),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let kind = kind
+            .or(configured)
+            .or_else(|| ForgeKind::detect(&url))
+            .ok_or_else(|| {
+                git2::Error::from_str(&format!(
+                    "could not tell the forge of {} from its url, use --forge=<FORGE> or set update-pr.forge",
+                    cli.remote
+                ))
+            })?;
+
+        let updater = match kind {
+            ForgeKind::GitHub => {
+                let token = cli.github_token.as_deref().ok_or_else(|| {
+                    git2::Error::from_str(
+                        "GitHub needs a token, set GITHUB_TOKEN or --github-token",
+                    )
+                })?;
+                let api_url = match (&cli.github_api, forge::host(&url)) {
+                    (Some(api_url), _) => api_url.clone(),
+                    (None, Some(host)) if host != "github.com" => {
+                        format!("https://{}/api/v3", host)
+                    }
+                    _ => forge::github::DEFAULT_API_URL.to_string(),
+                };
+                updater.forge(GitHub::new(token).api_url(api_url))
             }
-
-            if let Some(delay) = self.delay {
-                wait(delay.as_secs());
-            } else {
-                break;
+            ForgeKind::GitLab => {
+                let token = cli.gitlab_token.as_deref().ok_or_else(|| {
+                    git2::Error::from_str(
+                        "GitLab needs a token, set GITLAB_TOKEN or --gitlab-token",
+                    )
+                })?;
+                let api_url = match (&cli.gitlab_api, forge::host(&url)) {
+                    (Some(api_url), _) => api_url.clone(),
+                    (None, Some(host)) => format!("https://{}/api/v4", host),
+                    (None, None) => forge::gitlab::DEFAULT_API_URL.to_string(),
+                };
+                updater.forge(GitLab::new(token).api_url(api_url))
             }
-        }
+            ForgeKind::Gitea => {
+                let token = cli.gitea_token.as_deref().ok_or_else(|| {
+                    git2::Error::from_str("Gitea needs a token, set GITEA_TOKEN or --gitea-token")
+                })?;
+                let api_url = match (&cli.gitea_api, forge::host(&url)) {
+                    (Some(api_url), _) => api_url.clone(),
+                    (None, Some(host)) => format!("https://{}/api/v1", host),
+                    (None, None) => forge::gitea::DEFAULT_API_URL.to_string(),
+                };
+                updater.forge(
+                    Gitea::new(token)
+                        .api_url(api_url)
+                        .style(cli.gitea_update_style),
+                )
+            }
+        };
 
-        Ok(exit_code)
+        Ok(updater)
     }
 
-    pub fn try_git_ops(&self) -> Result<(), git2::Error> {
-        self.pull_current_branch()?;
-        if self.merge_main()? {
-            self.push_current_branch()?;
+    /// The values of the multivar `name` in the git config of `repo`, e.g. the filters of `update-pr.filter`
+    fn configured<T: FromStr<Err = String>>(
+        repo: &git2::Repository,
+        name: &str,
+    ) -> Result<Vec<T>, git2::Error> {
+        let config = repo.config()?;
+        let mut values = Vec::new();
+
+        let mut entries = config.multivar(name, None)?;
+        while let Some(entry) = entries.next() {
+            let value = entry?.value().unwrap_or_default().to_string();
+            let value = value
+                .parse()
+                .map_err(|e: String| git2::Error::new(ErrorCode::Invalid, ErrorClass::Config, e))?;
+            values.push(value);
         }
 
-        Ok(())
+        Ok(values)
     }
 
-    fn merge_main(&self) -> Result<bool, git2::Error> {
-        let mut remote = self.repo.find_remote(REMOTE_NAME)?;
-        let fetch_commit = self.fetch(REMOTE_BRANCH, &mut remote)?;
+    /// The webhooks notified after updates, as configured on the command line
+    pub(crate) fn webhooks(cli: &Cli) -> Webhooks {
+        let mut webhooks = Webhooks::new()
+            .timeout(cli.webhook_timeout.into())
+            .retries(cli.webhook_retries);
+        if !cli.webhook_events.is_empty() {
+            webhooks = webhooks.events(&cli.webhook_events);
+        }
 
-        let head = self.repo.head()?;
-        let current_ref = head.name().unwrap();
+        let urls = [
+            (&cli.webhook, WebhookFormat::Json),
+            (&cli.slack_webhook, WebhookFormat::Slack),
+            (&cli.teams_webhook, WebhookFormat::Teams),
+        ];
+        for (urls, format) in urls {
+            for url in urls {
+                webhooks = webhooks.add(url, format);
+            }
+        }
+
+        webhooks
+    }
 
-        let current_oid = self.get_current_oid()?;
+    /// Update every repository, then again after each delay if one is set
+    ///
+    /// A repository failing does not stop the others. When several are updated,
+    /// a table of their results is printed after each attempt. The attempts
+    /// stop when every repository failed.
+    pub fn run(&self) -> Result<i32> {
+        let mut exit_code = 0;
 
-        self.merge(current_ref, fetch_commit)?;
+        for attempt in 1.. {
+            let span = tracing::info_span!("attempt", attempt);
+            let _enter = span.enter();
 
-        Ok(self.get_current_oid()? != current_oid)
-    }
+            let results = self.update_all(&span);
 
-    fn pull_current_branch(&self) -> Result<(), git2::Error> {
-        let mut remote = self.repo.find_remote(REMOTE_NAME)?;
+            let mut summary = Summary::new();
+            exit_code = 0;
+            for (target, result) in self.targets.iter().zip(&results) {
+                if let (Err(e), 0) = (result, exit_code) {
+                    exit_code = e.raw_code();
+                }
+                summary.push(&target.name, result);
+            }
 
-        let head = self.repo.head()?;
-        let current_ref = head.name().unwrap();
-        let fetch_commit = self.fetch(current_ref, &mut remote)?;
+            if self.targets.len() > 1 {
+                print!("\n{}", summary.render(self.verbosity));
+            }
 
-        self.merge(current_ref, fetch_commit)?;
+            let all_failed = summary.count(Status::Error) == self.targets.len();
+            match self.delay {
+                Some(delay) if !all_failed => wait(&self.reporter, delay),
+                _ => break,
+            }
+        }
 
-        Ok(())
+        Ok(exit_code)
     }
 
-    fn push_current_branch(&self) -> Result<(), git2::Error> {
-        let mut remote = self.repo.find_remote(REMOTE_NAME)?;
+    /// Update the repositories, `jobs` at a time, returning the results in order
+    fn update_all(&self, span: &Span) -> Vec<Result<UpdateOutcome, git2::Error>> {
+        if self.jobs <= 1 {
+            return self
+                .targets
+                .iter()
+                .map(|target| self.update_target(target, span))
+                .collect();
+        }
 
-        let head = self.repo.head()?;
-        let current_ref = head.name().unwrap();
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::with_capacity(self.targets.len()));
 
-        self.push(&[current_ref], &mut remote)?;
+        std::thread::scope(|scope| {
+            for _ in 0..self.jobs.min(self.targets.len()) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(target) = self.targets.get(i) else {
+                        break;
+                    };
 
-        Ok(())
-    }
+                    let result = self.update_target(target, span);
+                    results.lock().unwrap().push((i, result));
+                });
+            }
+        });
 
-    fn get_current_oid(&self) -> Result<Oid, git2::Error> {
-        self.repo.refname_to_id("HEAD")
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, result)| result).collect()
     }
-}
 
-fn wait(delay: u64) {
-    let pb = ProgressBar::new(delay).with_message("Waiting...");
+    fn update_target(&self, target: &Target, parent: &Span) -> Result<UpdateOutcome, git2::Error> {
+        let _span = tracing::info_span!(parent: parent, "target", name = %target.name).entered();
+
+        match &target.line {
+            Some(line) => line.start(),
+            None if self.targets.len() > 1 && self.verbosity > Verbosity::Quiet => {
+                println!("{}", style(&target.name).bold())
+            }
+            None => {}
+        }
+
+        let result = match &*target.updater.lock().unwrap() {
+            Ok(updater) if self.auto_merge => updater.update_and_merge(),
+            Ok(updater) => updater.update(),
+            Err(e) => Err(git2::Error::new(e.code(), e.class(), e.message())),
+        };
+
+        match &result {
+            Ok(outcome) => tracing::info!(%outcome, "update finished"),
+            Err(e) => {
+                let message = redact_credentials(e.message());
+                tracing::error!(code = e.raw_code(), "update failed: {}", message);
+
+                match &target.line {
+                    Some(line) => line.println(&format!("Error: {}", message)),
+                    None => eprintln!("Error: {}", message),
+                }
+            }
+        }
 
-    pb.set_style(
-        indicatif::ProgressStyle::default_bar()
-            .template("{spinner} {elapsed} {msg}")
-            .unwrap(),
-    );
-    pb.enable_steady_tick(std::time::Duration::from_millis(100));
+        self.webhooks.notify_result(&target.name, &result);
 
-    let stop_at = std::time::Instant::now() + std::time::Duration::from_secs(delay);
+        if let Some(line) = &target.line {
+            let (status, details) = summary::status(&result);
+            line.finish(format!("{} {}", status, details).trim_end());
+        }
 
-    while std::time::Instant::now() < stop_at {
-        std::thread::sleep(std::time::Duration::from_millis(100));
+        result
     }
+}
+
+fn wait(reporter: &dyn Reporter, delay: Duration) {
+    let duration: std::time::Duration = delay.into();
 
-    pb.finish();
+    reporter.report(&Event::Waiting { duration });
+    std::thread::sleep(duration);
+    reporter.report(&Event::WaitDone);
 }
@@ -0,0 +1,450 @@
+use std::time::{Duration, Instant};
+
+use git2::{ErrorClass, ErrorCode};
+
+use crate::{
+    forge::{CiStatus, ForgeError, MergeMethod},
+    report::Event,
+};
+
+use super::{ci::CiStatuses, fetch::Fetch, outcome::UpdateOutcome, MergeOutcome, Updater};
+
+/// How often the checks of the pull request are read
+pub const DEFAULT_CHECKS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the checks are waited for, base branch updates included
+pub const DEFAULT_CHECKS_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// How waiting for the checks of an updated pull request ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeAttempt {
+    /// The pull request `number` was merged
+    Merged(u64),
+    /// The base branch moved while the checks were running, so the branch must be updated again
+    BaseMoved,
+    /// The pull request cannot be merged, for this reason
+    Stopped(String),
+}
+
+/// Merges the pull request of the branch once it is up to date and its checks passed
+pub trait AutoMerge: CiStatuses + Fetch {
+    fn merge_method(&self) -> MergeMethod {
+        MergeMethod::default()
+    }
+
+    /// How often and how long to read the checks
+    fn checks_poll(&self) -> (Duration, Duration) {
+        (DEFAULT_CHECKS_INTERVAL, DEFAULT_CHECKS_TIMEOUT)
+    }
+
+    /// The branch the pull request is merged into
+    fn base_branch(&self) -> &str {
+        "main"
+    }
+
+    /// Wait for the checks of the branch left by `outcome`, then merge its pull request
+    ///
+    /// The branch must contain the commit of the base branch an update
+    /// merges, its tip or the one chosen by the CI gate, which is checked
+    /// again whenever the tip moves between reads of the checks. Checks
+    /// still running are waited for until `deadline`, a pull request still
+    /// without checks on the second read is not merged.
+    fn merge_when_green(
+        &self,
+        outcome: &UpdateOutcome,
+        deadline: Instant,
+    ) -> Result<MergeAttempt, git2::Error> {
+        let attempt = self.try_merge_when_green(outcome, deadline)?;
+        if let MergeAttempt::Stopped(reason) = &attempt {
+            tracing::info!("not merging: {}", reason);
+            self.reporter().report(&Event::AutoMergeStopped {
+                reason: reason.clone(),
+            });
+        }
+
+        Ok(attempt)
+    }
+
+    fn try_merge_when_green(
+        &self,
+        outcome: &UpdateOutcome,
+        deadline: Instant,
+    ) -> Result<MergeAttempt, git2::Error> {
+        match &outcome.merge {
+            MergeOutcome::Conflicts(_) => {
+                return Ok(MergeAttempt::Stopped(
+                    "the branch has conflicts".to_string(),
+                ))
+            }
+            MergeOutcome::Diverged => {
+                return Ok(MergeAttempt::Stopped(format!(
+                    "the branch diverged from {}",
+                    outcome.base
+                )))
+            }
+            MergeOutcome::Skipped(reason) => return Ok(MergeAttempt::Stopped(reason.clone())),
+            _ => {}
+        }
+
+        let forge = self
+            .forge()
+            .ok_or_else(|| git2::Error::from_str("merging pull requests needs a forge"))?;
+        let to_git_error = |e: ForgeError| {
+            git2::Error::new(
+                ErrorCode::GenericError,
+                ErrorClass::Net,
+                format!("could not merge the pull request: {}", e),
+            )
+        };
+        let repo = self.forge_repo().map_err(to_git_error)?;
+        let branch = outcome.branch.trim_start_matches("refs/heads/");
+        let head = outcome.head_after;
+        let (interval, timeout) = self.checks_poll();
+        let _span = tracing::info_span!("auto_merge", %head).entered();
+
+        // The tip fetched by the update, whose merged commit is in the branch
+        let mut checked_tip = self
+            .repo()
+            .refname_to_id(&format!(
+                "refs/remotes/{}/{}",
+                self.remote_name(),
+                self.base_branch()
+            ))
+            .ok();
+        let mut waiting = false;
+        let mut missing = false;
+        loop {
+            let mut remote = self.repo().find_remote(self.remote_name())?;
+            let tip = self.fetch(self.base_branch(), &mut remote)?.id();
+            if checked_tip != Some(tip) {
+                checked_tip = Some(tip);
+
+                // With a CI gate, the commit to merge can be older than the tip
+                if let Some(base) = self.green_base(self.base_branch(), tip)? {
+                    if base != head && !self.repo().graph_descendant_of(head, base)? {
+                        tracing::info!(%tip, %base, "the base branch moved");
+                        self.reporter().report(&Event::BaseMoved {
+                            base: self.base_branch().to_string(),
+                            oid: base,
+                        });
+                        return Ok(MergeAttempt::BaseMoved);
+                    }
+                }
+            }
+
+            let Some(pr) = forge
+                .find_pull_request(&repo, branch)
+                .map_err(to_git_error)?
+            else {
+                return Ok(MergeAttempt::Stopped(
+                    "the branch has no open pull request".to_string(),
+                ));
+            };
+
+            // The forge may not have seen the last push yet
+            let status = if pr.head_sha == head.to_string() {
+                self.ci_status(head)?
+            } else {
+                CiStatus::Pending
+            };
+            tracing::debug!(number = pr.number, %status, "read the checks");
+
+            match status {
+                CiStatus::Success => {
+                    let method = self.merge_method();
+                    if let Err(e) = forge.merge_pull_request(&repo, &pr, method) {
+                        return Ok(MergeAttempt::Stopped(format!(
+                            "{} refused to merge pull request #{}: {}",
+                            forge.name(),
+                            pr.number,
+                            e
+                        )));
+                    }
+                    tracing::info!(number = pr.number, ?method, "merged the pull request");
+                    self.reporter().report(&Event::PullRequestMerged {
+                        number: pr.number,
+                        method,
+                    });
+
+                    return Ok(MergeAttempt::Merged(pr.number));
+                }
+                CiStatus::Failure => {
+                    return Ok(MergeAttempt::Stopped(format!(
+                        "the checks of pull request #{} failed",
+                        pr.number
+                    )))
+                }
+                // The forge may not have started the checks of the push yet, read them once more
+                CiStatus::Missing if !missing => missing = true,
+                CiStatus::Missing => {
+                    return Ok(MergeAttempt::Stopped(format!(
+                        "pull request #{} has no checks to wait for",
+                        pr.number
+                    )))
+                }
+                CiStatus::Pending => {}
+            }
+
+            if Instant::now() >= deadline {
+                return Err(git2::Error::new(
+                    ErrorCode::GenericError,
+                    ErrorClass::Net,
+                    format!(
+                        "the checks of pull request #{} did not pass within {}",
+                        pr.number,
+                        humantime::format_duration(timeout)
+                    ),
+                ));
+            }
+            if !waiting {
+                waiting = true;
+                self.reporter().report(&Event::WaitingForChecks {
+                    number: pr.number,
+                    oid: head,
+                });
+            }
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+impl AutoMerge for Updater {
+    fn merge_method(&self) -> MergeMethod {
+        self.merge_method
+    }
+
+    fn checks_poll(&self) -> (Duration, Duration) {
+        (self.checks_interval, self.checks_timeout)
+    }
+
+    fn base_branch(&self) -> &str {
+        &self.base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use color_eyre::eyre::Result;
+    use git2::Repository;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        app::{
+            ci::CiGate,
+            fixture::{commit_file, git_cmd, setup_branches, setup_pr, Recorder, TestFixture},
+        },
+        forge::{fake::FakeApi, GitHub},
+        report::SilentReporter,
+    };
+
+    #[test]
+    fn test_merge_once_green() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "pr.txt")?;
+        let remote_dir = fixture.remote_dir.clone();
+        let setup_dir = fixture.base_dir.join("setup");
+        let head_of_pr = {
+            let remote_dir = remote_dir.clone();
+            move || {
+                Repository::open_bare(&remote_dir)
+                    .and_then(|repo| repo.refname_to_id("refs/heads/pr"))
+                    .unwrap()
+                    .to_string()
+            }
+        };
+
+        // The checks are pending the first time, and main moves meanwhile
+        let status_reads = Arc::new(AtomicUsize::new(0));
+        let api = FakeApi::start(move |request| {
+            let path = request.path.split('?').next().unwrap();
+
+            match request.method.as_str() {
+                "GET" if path.ends_with("/remote/pulls") => (
+                    200,
+                    json!([{
+                        "number": 12,
+                        "html_url": "https://github.com/org/remote/pull/12",
+                        "state": "open",
+                        "head": { "ref": "pr", "sha": head_of_pr() },
+                        "base": { "ref": "main", "sha": "def" },
+                    }]),
+                ),
+                "GET" if path.ends_with("/issues/12/comments") => (200, json!([])),
+                "GET" if path.ends_with("/check-runs") => {
+                    (200, json!({ "total_count": 0, "check_runs": [] }))
+                }
+                "GET" if path.ends_with("/status") => {
+                    if status_reads.fetch_add(1, Ordering::SeqCst) == 0 {
+                        commit_file(&setup_dir, "other.txt", "other\n").unwrap();
+                        git_cmd(&setup_dir)
+                            .args(["push", "origin", "HEAD:main"])
+                            .status()
+                            .unwrap();
+                        (200, json!({ "state": "pending", "total_count": 1 }))
+                    } else {
+                        (200, json!({ "state": "success", "total_count": 1 }))
+                    }
+                }
+                "PUT" if path.ends_with("/pulls/12/merge") => (200, json!({ "merged": true })),
+                _ => (404, json!({ "message": "Not Found" })),
+            }
+        })?;
+
+        Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .forge(GitHub::new("token").api_url(&api.url))
+            .merge_method(MergeMethod::Squash)
+            .checks_poll(Duration::from_millis(10), Duration::from_secs(30))
+            .update_and_merge()?;
+
+        let remote = Repository::open_bare(&remote_dir)?;
+        let main = remote.refname_to_id("refs/heads/main")?;
+        let pr = remote.refname_to_id("refs/heads/pr")?;
+        assert!(remote.graph_descendant_of(pr, main)?);
+
+        let requests = api.requests();
+        let merges: Vec<_> = requests
+            .iter()
+            .filter(|r| r.method == "PUT" && r.path.ends_with("/pulls/12/merge"))
+            .collect();
+        assert_eq!(merges.len(), 1);
+        assert_eq!(merges[0].body["sha"], pr.to_string());
+        assert_eq!(merges[0].body["merge_method"], "squash");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_with_ci_gate() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_branches(
+            &fixture,
+            &[],
+            &[("pr.txt", "pr\n")],
+            &[("green.txt", "green\n"), ("red.txt", "red\n")],
+        )?;
+        let remote_dir = fixture.remote_dir.clone();
+        let remote = Repository::open_bare(&remote_dir)?;
+        let red = remote.refname_to_id("refs/heads/main")?;
+        let green = remote.find_commit(red)?.parent_id(0)?;
+
+        // The tip of main fails its CI, everything else passes
+        let api = FakeApi::start(move |request| {
+            let path = request.path.split('?').next().unwrap();
+            let head_of_pr = Repository::open_bare(&remote_dir)
+                .and_then(|repo| repo.refname_to_id("refs/heads/pr"))
+                .unwrap();
+
+            match request.method.as_str() {
+                "GET" if path.ends_with("/remote/pulls") => (
+                    200,
+                    json!([{
+                        "number": 12,
+                        "html_url": "https://github.com/org/remote/pull/12",
+                        "state": "open",
+                        "head": { "ref": "pr", "sha": head_of_pr.to_string() },
+                        "base": { "ref": "main", "sha": red.to_string() },
+                    }]),
+                ),
+                "GET" if path.ends_with("/issues/12/comments") => (200, json!([])),
+                "GET" if path.ends_with("/check-runs") => {
+                    (200, json!({ "total_count": 0, "check_runs": [] }))
+                }
+                "GET" if path.ends_with(&format!("/commits/{}/status", red)) => {
+                    (200, json!({ "state": "failure", "total_count": 1 }))
+                }
+                "GET" if path.ends_with("/status") => {
+                    (200, json!({ "state": "success", "total_count": 1 }))
+                }
+                "PUT" if path.ends_with("/pulls/12/merge") => (200, json!({ "merged": true })),
+                _ => (404, json!({ "message": "Not Found" })),
+            }
+        })?;
+
+        Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .forge(GitHub::new("token").api_url(&api.url))
+            .ci_gate(CiGate::WalkBack)
+            .checks_poll(Duration::from_millis(10), Duration::from_secs(5))
+            .update_and_merge()?;
+
+        let pr = remote.refname_to_id("refs/heads/pr")?;
+        assert!(remote.graph_descendant_of(pr, green)?);
+        assert!(!remote.graph_descendant_of(pr, red)?);
+
+        let merges = api
+            .requests()
+            .iter()
+            .filter(|r| r.method == "PUT" && r.path.ends_with("/pulls/12/merge"))
+            .count();
+        assert_eq!(merges, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_without_checks() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "pr.txt")?;
+        let remote_dir = fixture.remote_dir.clone();
+
+        // No status nor check run ever reports on the pull request
+        let api = FakeApi::start(move |request| {
+            let path = request.path.split('?').next().unwrap();
+            let head_of_pr = Repository::open_bare(&remote_dir)
+                .and_then(|repo| repo.refname_to_id("refs/heads/pr"))
+                .unwrap();
+
+            match request.method.as_str() {
+                "GET" if path.ends_with("/remote/pulls") => (
+                    200,
+                    json!([{
+                        "number": 12,
+                        "html_url": "https://github.com/org/remote/pull/12",
+                        "state": "open",
+                        "head": { "ref": "pr", "sha": head_of_pr.to_string() },
+                        "base": { "ref": "main", "sha": "def" },
+                    }]),
+                ),
+                "GET" if path.ends_with("/issues/12/comments") => (200, json!([])),
+                "GET" if path.ends_with("/check-runs") => {
+                    (200, json!({ "total_count": 0, "check_runs": [] }))
+                }
+                "GET" if path.ends_with("/status") => {
+                    (200, json!({ "state": "pending", "total_count": 0 }))
+                }
+                _ => (404, json!({ "message": "Not Found" })),
+            }
+        })?;
+
+        let recorder = Arc::new(Recorder::default());
+        Updater::open(&usage_dir)?
+            .reporter(recorder.clone())
+            .forge(GitHub::new("token").api_url(&api.url))
+            .checks_poll(Duration::from_millis(10), Duration::from_secs(30))
+            .update_and_merge()?;
+
+        assert!(recorder
+            .0
+            .lock()
+            .unwrap()
+            .contains(&Event::AutoMergeStopped {
+                reason: "pull request #12 has no checks to wait for".to_string()
+            }));
+        let requests = api.requests();
+        let status_reads = requests
+            .iter()
+            .filter(|r| r.path.split('?').next().unwrap().ends_with("/status"))
+            .count();
+        assert_eq!(status_reads, 2);
+        assert!(!requests.iter().any(|r| r.method == "PUT"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use clap::ValueEnum;
+use git2::{ErrorClass, ErrorCode, Oid};
+
+use crate::{forge::CiStatus, report::Event};
+
+use super::{pull_request::PullRequests, Updater};
+
+/// How many commits of the base branch are checked at most when looking for one that passed its CI
+pub const DEFAULT_WALK_LIMIT: usize = 20;
+
+/// What to do when the tip of the base branch did not pass its CI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CiGate {
+    /// Merge the most recent commit that passed, following the first parents of the base branch
+    WalkBack,
+    /// Don't merge the base branch until its tip passes
+    Skip,
+}
+
+/// Merges only commits of the base branch whose CI passed, as reported by the forge
+pub trait CiStatuses: PullRequests {
+    /// What to do when the base branch did not pass its CI, nothing is checked by default
+    fn ci_gate(&self) -> Option<CiGate> {
+        None
+    }
+
+    fn ci_walk_limit(&self) -> usize {
+        DEFAULT_WALK_LIMIT
+    }
+
+    /// The commit of `base` to merge, `None` when none passed its CI
+    ///
+    /// This is `tip` itself when its CI passed or nothing is checked.
+    /// Otherwise the first-parent history of `tip` is walked back to the
+    /// most recent commit that passed, with [`CiGate::WalkBack`]. Pending
+    /// checks and commits without checks do not count as passed.
+    fn green_base(&self, base: &str, tip: Oid) -> Result<Option<Oid>, git2::Error> {
+        let Some(gate) = self.ci_gate() else {
+            return Ok(Some(tip));
+        };
+        let _span = tracing::info_span!("ci_gate", %tip, ?gate).entered();
+
+        let tip_status = self.ci_status(tip)?;
+        tracing::debug!(%tip_status, "checked the tip of the base branch");
+        if tip_status == CiStatus::Success {
+            return Ok(Some(tip));
+        }
+
+        let mut candidate = tip;
+        if gate == CiGate::WalkBack {
+            for _ in 1..self.ci_walk_limit() {
+                // Stops at the root commit or at the boundary of a shallow clone
+                let Ok(parent) = self
+                    .repo()
+                    .find_commit(candidate)
+                    .and_then(|commit| commit.parent(0))
+                else {
+                    break;
+                };
+                candidate = parent.id();
+
+                let status = self.ci_status(candidate)?;
+                tracing::debug!(oid = %candidate, %status, "checked a base commit");
+                if status == CiStatus::Success {
+                    tracing::info!(oid = %candidate, "merging the most recent green commit");
+                    self.reporter().report(&Event::BaseCommitChosen {
+                        base: base.to_string(),
+                        tip,
+                        tip_status,
+                        oid: candidate,
+                    });
+
+                    return Ok(Some(candidate));
+                }
+            }
+        }
+
+        tracing::info!(%tip_status, "not merging the base branch");
+        self.reporter().report(&Event::BaseSkipped {
+            base: base.to_string(),
+            tip,
+            tip_status,
+        });
+
+        Ok(None)
+    }
+
+    /// The CI status of `oid` on the forge
+    fn ci_status(&self, oid: Oid) -> Result<CiStatus, git2::Error> {
+        let forge = self.forge().ok_or_else(|| {
+            git2::Error::from_str("checking the CI of the base branch needs a forge")
+        })?;
+        let to_git_error = |e: &dyn std::fmt::Display| {
+            git2::Error::new(
+                ErrorCode::GenericError,
+                ErrorClass::Net,
+                format!("could not read the CI status of {}: {}", oid, e),
+            )
+        };
+
+        let repo = self.forge_repo().map_err(|e| to_git_error(&e))?;
+        forge
+            .ci_status(&repo, &oid.to_string())
+            .map_err(|e| to_git_error(&e))
+    }
+}
+
+impl CiStatuses for Updater {
+    fn ci_gate(&self) -> Option<CiGate> {
+        self.ci_gate
+    }
+
+    fn ci_walk_limit(&self) -> usize {
+        self.ci_walk_limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use color_eyre::eyre::Result;
+    use git2::Repository;
+    use serde_json::json;
+
+    use super::*;
+    use crate::app::fixture::{setup_branches, TestFixture};
+    use crate::{
+        app::MergeOutcome,
+        forge::{fake::FakeApi, GitHub},
+        report::SilentReporter,
+    };
+
+    /// A `pr` branch in `usage` and two new commits on `main`, the last one failing its CI
+    ///
+    /// Returns the `usage` directory and the two commits of `main`.
+    fn setup() -> Result<(PathBuf, Oid, Oid)> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_branches(
+            &fixture,
+            &[],
+            &[("pr.txt", "pr\n")],
+            &[("green.txt", "green\n"), ("red.txt", "red\n")],
+        )?;
+
+        let repo = Repository::open(&fixture.remote_dir)?;
+        let red = repo.refname_to_id("refs/heads/main")?;
+        let green = repo.find_commit(red)?.parent_id(0)?;
+
+        Ok((usage_dir, green, red))
+    }
+
+    /// Answers the commit statuses of GitHub, `green` passing and every other commit failing
+    fn fake_statuses(green: Oid) -> Result<FakeApi> {
+        let api = FakeApi::start(move |request| {
+            let path = request.path.split('?').next().unwrap();
+
+            if path.ends_with("/check-runs") {
+                (200, json!({ "total_count": 0, "check_runs": [] }))
+            } else if path.ends_with(&format!("/commits/{}/status", green)) {
+                (200, json!({ "state": "success", "total_count": 1 }))
+            } else if path.ends_with("/status") {
+                (200, json!({ "state": "failure", "total_count": 1 }))
+            } else {
+                (404, json!({ "message": "Not Found" }))
+            }
+        })?;
+
+        Ok(api)
+    }
+
+    #[test]
+    fn test_walk_back_to_green_commit() -> Result<()> {
+        let (usage_dir, green, red) = setup()?;
+        let api = fake_statuses(green)?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .forge(GitHub::new("token").api_url(&api.url))
+            .ci_gate(CiGate::WalkBack)
+            .update()?;
+
+        let MergeOutcome::Merged(merge) = outcome.merge else {
+            panic!("expected a merge, got {:?}", outcome.merge);
+        };
+        let repo = Repository::open(&usage_dir)?;
+        assert_eq!(repo.find_commit(merge)?.parent_id(1)?, green);
+        assert!(api
+            .requests()
+            .iter()
+            .any(|r| r.path.contains(&format!("/commits/{}/status", red))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_red_base() -> Result<()> {
+        let (usage_dir, green, _) = setup()?;
+        let api = fake_statuses(green)?;
+        let head_before = Repository::open(&usage_dir)?.refname_to_id("HEAD")?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .forge(GitHub::new("token").api_url(&api.url))
+            .ci_gate(CiGate::Skip)
+            .update()?;
+
+        assert!(matches!(outcome.merge, MergeOutcome::Skipped(_)));
+        assert!(!outcome.is_updated());
+        assert_eq!(outcome.head_after, head_before);
+
+        Ok(())
+    }
+}
@@ -1,64 +1,294 @@
-use console::{style, Emoji};
-use git2::{AnnotatedCommit, AutotagOption, FetchOptions, Reference, Remote, RemoteCallbacks};
+use std::collections::HashSet;
 
-use crate::{progress::fetch::FetchProgress, utils::git::ssh_creds};
+use clap::ValueEnum;
+use git2::{
+    AnnotatedCommit, AutotagOption, Direction, ErrorCode, FetchOptions, FetchPrune, Oid, Remote,
+    RemoteCallbacks,
+};
 
-use super::{repo::Repo, App};
+use crate::{report::Event, utils::git::redact_credentials};
 
-static LOOKING_GLASS: Emoji<'_, '_> = Emoji("🔍  ", "");
+use super::{repo::Repo, Updater};
+
+/// How deep a shallow history is fetched at most looking for a merge base, by default
+pub const DEFAULT_DEEPEN_LIMIT: i32 = 2048;
+
+/// The first depth fetched when deepening a shallow history, doubled at each attempt
+const DEEPEN_STEP: i32 = 32;
+
+/// Which tags are downloaded along with the fetched branches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TagPolicy {
+    /// No tags
+    None,
+    /// Tags pointing into the fetched history
+    Auto,
+    /// Every tag of the remote
+    All,
+}
+
+impl From<TagPolicy> for AutotagOption {
+    fn from(policy: TagPolicy) -> Self {
+        match policy {
+            TagPolicy::None => AutotagOption::None,
+            TagPolicy::Auto => AutotagOption::Auto,
+            TagPolicy::All => AutotagOption::All,
+        }
+    }
+}
 
 pub trait Fetch: Repo {
-    fn fetch(&self, refs: &str, remote: &mut Remote) -> Result<AnnotatedCommit, git2::Error> {
-        let pb = FetchProgress::new();
+    /// Which tags to download, `None` to follow `remote.<name>.tagOpt`
+    fn fetch_tags(&self) -> Option<TagPolicy> {
+        None
+    }
+
+    /// Whether to prune, `None` to follow `remote.<name>.prune` and `fetch.prune`
+    fn fetch_prune(&self) -> Option<bool> {
+        None
+    }
+
+    /// How deep a shallow history is fetched at most looking for a merge base
+    fn deepen_limit(&self) -> i32 {
+        DEFAULT_DEEPEN_LIMIT
+    }
+
+    /// Options shared by every fetch, reporting progress
+    fn fetch_options(&self) -> FetchOptions<'_> {
+        let reporter = self.reporter();
 
         let mut cb = RemoteCallbacks::new();
-        cb.credentials(ssh_creds).transfer_progress(|stats| {
-            pb.process(stats);
+        cb.credentials(move |url, username, allowed| self.credentials(url, username, allowed))
+            .transfer_progress(move |stats| {
+                reporter.report(&Event::FetchProgress(stats.into()));
 
-            true
-        });
+                true
+            });
 
         let mut fo = FetchOptions::new();
         fo.remote_callbacks(cb);
-        // Always fetch all tags.
-        // Perform a download and also update tips
-        fo.download_tags(AutotagOption::All);
-
-        pb.println(format!(
-            "{}Fetching {} for repo from {}...",
-            LOOKING_GLASS,
-            style(refs).italic().dim().blue(),
-            style(remote.name().unwrap()).italic().bold().green()
-        ))
-        .unwrap();
-
-        if let Err(e) = remote.fetch(&[refs], Some(&mut fo), None) {
+        // Unspecified lets libgit2 follow remote.<name>.tagOpt
+        fo.download_tags(
+            self.fetch_tags()
+                .map_or(AutotagOption::Unspecified, Into::into),
+        );
+        // Pruning is done separately, over every remote-tracking branch
+        fo.prune(FetchPrune::Off);
+
+        fo
+    }
+
+    /// Fetch a single branch into its remote-tracking branch and resolve it
+    ///
+    /// Only `+refs/heads/<branch>:refs/remotes/<remote>/<branch>` is fetched, so
+    /// each call transfers as little as possible.
+    ///
+    /// * `refs` - The branch to fetch, either short or as `refs/heads/<branch>`
+    fn fetch(&self, refs: &str, remote: &mut Remote) -> Result<AnnotatedCommit<'_>, git2::Error> {
+        let reporter = self.reporter();
+        let remote_name = remote.name().unwrap().to_string();
+        let _span = tracing::info_span!(
+            "fetch",
+            remote = %remote_name,
+            url = %redact_credentials(remote.url().unwrap_or_default()),
+            refspec = refs,
+        )
+        .entered();
+
+        let branch = refs.strip_prefix("refs/heads/").unwrap_or(refs);
+        let tracking_ref = format!("refs/remotes/{}/{}", remote_name, branch);
+        let refspec = format!("+refs/heads/{}:{}", branch, tracking_ref);
+
+        let mut fo = self.fetch_options();
+
+        reporter.report(&Event::FetchStarted {
+            remote: remote_name.clone(),
+            refspec: refs.to_string(),
+        });
+
+        if let Err(e) = remote.fetch(&[&refspec], Some(&mut fo), None) {
             match e.class() {
-                git2::ErrorClass::Net => println!("Error: {}", e),
+                git2::ErrorClass::Net => {
+                    tracing::warn!(
+                        "fetch failed, using local state: {}",
+                        redact_credentials(e.message())
+                    );
+                    reporter.report(&Event::FetchFailed {
+                        remote: remote_name.clone(),
+                        refspec: refs.to_string(),
+                        message: redact_credentials(e.message()),
+                    })
+                }
                 _ => return Err(e),
             }
         }
-        if !refs.starts_with("refs/heads") {
-            let refs_head = self.repo().find_reference(
-                format!("refs/remotes/{}/{}", remote.name().unwrap(), refs).as_str(),
-            )?;
-            let com = self.repo().reference_to_annotated_commit(&refs_head)?;
 
-            pb.println(format!("{:?}", com.refname().unwrap())).unwrap();
+        let fetch_head = self.repo().find_reference(&tracking_ref)?;
+        let fetch_commit = self.repo().reference_to_annotated_commit(&fetch_head)?;
+        tracing::debug!(oid = %fetch_commit.id(), "fetched");
+
+        reporter.report(&Event::FetchDone {
+            remote: remote_name,
+            refspec: refs.to_string(),
+            oid: fetch_commit.id(),
+        });
+
+        Ok(fetch_commit)
+    }
+
+    /// Deepen a shallow history until `local` and `other` have a merge base
+    ///
+    /// The branches are fetched again with a depth doubling at each attempt, up
+    /// to [`Fetch::deepen_limit`]. Does nothing when the repository is not shallow.
+    ///
+    /// * `branches` - The branches leading to `local` and `other`, either short or full
+    fn deepen_until_merge_base(
+        &self,
+        branches: &[&str],
+        local: Oid,
+        other: Oid,
+        remote: &mut Remote,
+    ) -> Result<(), git2::Error> {
+        let repo = self.repo();
+        if !repo.is_shallow() {
+            return Ok(());
+        }
+
+        let remote_name = remote.name().unwrap().to_string();
+        let refspecs: Vec<String> = branches
+            .iter()
+            .map(|refs| {
+                let branch = refs.strip_prefix("refs/heads/").unwrap_or(refs);
+                format!("+refs/heads/{0}:refs/remotes/{1}/{0}", branch, remote_name)
+            })
+            .collect();
+
+        let limit = self.deepen_limit();
+        let mut depth = 0;
+        loop {
+            match repo.merge_base(local, other) {
+                Ok(_) => return Ok(()),
+                Err(e) if e.code() != ErrorCode::NotFound => return Err(e),
+                Err(_) if depth >= limit => {
+                    return Err(git2::Error::from_str(&format!(
+                        "no merge base found within {} commits of the shallow history",
+                        limit
+                    )))
+                }
+                Err(_) => {}
+            }
+
+            depth = (depth.max(DEEPEN_STEP / 2) * 2).min(limit);
+            tracing::info!(depth, "deepening shallow history");
+            self.reporter().report(&Event::Deepening {
+                remote: remote_name.clone(),
+                depth,
+            });
+
+            let mut fo = self.fetch_options();
+            fo.depth(depth);
+            remote.fetch(&refspecs, Some(&mut fo), None)?;
+        }
+    }
+
+    /// Whether remote-tracking branches of `remote` should be pruned
+    fn should_prune(&self, remote: &Remote) -> bool {
+        if let Some(prune) = self.fetch_prune() {
+            return prune;
+        }
+
+        let Ok(config) = self.repo().config() else {
+            return false;
+        };
+
+        config
+            .get_bool(&format!(
+                "remote.{}.prune",
+                remote.name().unwrap_or_default()
+            ))
+            .or_else(|_| config.get_bool("fetch.prune"))
+            .unwrap_or(false)
+    }
+
+    /// Delete the remote-tracking branches whose branch no longer exists on the remote
+    ///
+    /// Returns the deleted references. Like fetches, network errors are reported
+    /// and leave the local state untouched.
+    fn prune_remote(&self, remote: &mut Remote) -> Result<Vec<String>, git2::Error> {
+        let remote_name = remote.name().unwrap().to_string();
+        let _span = tracing::info_span!("prune", remote = %remote_name).entered();
+
+        let mut cb = RemoteCallbacks::new();
+        cb.credentials(|url, username, allowed| self.credentials(url, username, allowed));
+
+        let heads = match remote.connect_auth(Direction::Fetch, Some(cb), None) {
+            Ok(connection) => connection
+                .list()?
+                .iter()
+                .map(|head| head.name().to_string())
+                .collect::<HashSet<_>>(),
+            Err(e) if e.class() == git2::ErrorClass::Net => {
+                tracing::warn!(
+                    "could not list remote branches: {}",
+                    redact_credentials(e.message())
+                );
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let repo = self.repo();
+        let mut pruned = Vec::new();
+        for reference in repo.references_glob(&format!("refs/remotes/{}/*", remote_name))? {
+            let mut reference = reference?;
+            if reference.kind() != Some(git2::ReferenceType::Direct) {
+                continue;
+            }
+            let Some(name) = reference.name().map(str::to_string) else {
+                continue;
+            };
+
+            let is_stale = remote
+                .refspecs()
+                .filter(|spec| spec.direction() == Direction::Fetch && spec.dst_matches(&name))
+                .any(|spec| {
+                    spec.rtransform(&name)
+                        .ok()
+                        .and_then(|src| src.as_str().map(|src| !heads.contains(src)))
+                        .unwrap_or(false)
+                });
+
+            if is_stale {
+                reference.delete()?;
+                tracing::info!(reference = %name, "pruned");
+                pruned.push(name);
+            }
+        }
+
+        if !pruned.is_empty() {
+            self.reporter().report(&Event::Pruned {
+                remote: remote_name,
+                refs: pruned.clone(),
+            });
         }
 
-        let fetch_head = if Reference::is_valid_name(refs) {
-            self.repo().find_reference(refs)
-        } else {
-            self.repo().find_reference(
-                format!("refs/remotes/{}/{}", remote.name().unwrap(), refs).as_str(),
-            )
-        }?;
-        self.repo().reference_to_annotated_commit(&fetch_head)
+        Ok(pruned)
     }
 }
 
-impl Fetch for App {}
+impl Fetch for Updater {
+    fn fetch_tags(&self) -> Option<TagPolicy> {
+        self.tags
+    }
+
+    fn fetch_prune(&self) -> Option<bool> {
+        self.prune
+    }
+
+    fn deepen_limit(&self) -> i32 {
+        self.deepen_limit
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -175,4 +405,94 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_fetch_narrow_and_prune() -> Result<()> {
+        let base_dir = testdir!();
+
+        //
+        // setup remote with `main` and `gone`
+        //
+        let remote_dir = base_dir.join("remote");
+        create_dir(&remote_dir)?;
+
+        Command::new("git")
+            .current_dir(&remote_dir)
+            .arg("init")
+            .arg("--bare")
+            .arg("--initial-branch=main")
+            .status()?;
+
+        let commit_dir = base_dir.join("base-commit");
+        create_dir(&commit_dir)?;
+
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .current_dir(&commit_dir)
+                .args(args)
+                .status()
+        };
+
+        git(&["clone", remote_dir.to_str().unwrap(), "."])?;
+        File::create(commit_dir.join("README.md"))?.write_all(b"# Test\n")?;
+        git(&["add", "README.md"])?;
+        git(&["commit", "-m", "Initial commit"])?;
+        git(&["push", "origin", "HEAD:main", "HEAD:gone"])?;
+
+        //
+        // setup local repo
+        //
+        let usage_dir = base_dir.join("usage");
+        create_dir(&usage_dir)?;
+
+        Command::new("git")
+            .current_dir(&usage_dir)
+            .arg("clone")
+            .arg(&remote_dir)
+            .arg(".")
+            .status()?;
+
+        //
+        // add a branch and a tag outside of main, and delete `gone`
+        //
+        git(&["checkout", "-b", "other"])?;
+        File::create(commit_dir.join("other.txt"))?.write_all(b"other\n")?;
+        git(&["add", "other.txt"])?;
+        git(&["commit", "-m", "other"])?;
+        git(&["tag", "v-other"])?;
+        git(&["push", "origin", "other", "v-other", ":gone"])?;
+
+        let app = TestApp {
+            repo: Repository::open(&usage_dir)?,
+        };
+        let mut remote = app.repo().find_remote("origin")?;
+
+        app.fetch("main", &mut remote)?;
+
+        // Only main was fetched
+        assert!(app
+            .repo()
+            .find_reference("refs/remotes/origin/other")
+            .is_err());
+        assert!(app.repo().find_reference("refs/tags/v-other").is_err());
+        assert!(app
+            .repo()
+            .find_reference("refs/remotes/origin/gone")
+            .is_ok());
+
+        assert_eq!(
+            app.prune_remote(&mut remote)?,
+            vec!["refs/remotes/origin/gone".to_string()]
+        );
+        assert!(app
+            .repo()
+            .find_reference("refs/remotes/origin/gone")
+            .is_err());
+        assert!(app
+            .repo()
+            .find_reference("refs/remotes/origin/main")
+            .is_ok());
+
+        Ok(())
+    }
 }
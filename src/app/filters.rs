@@ -0,0 +1,331 @@
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use clap::ValueEnum;
+use git2::{ErrorClass, ErrorCode};
+
+use crate::{
+    forge::{ForgeError, PullRequest, ReviewDecision},
+    report::Event,
+};
+
+use super::{pull_request::PullRequests, Updater};
+
+/// A rule the pull request of a branch must match for the branch to be updated
+///
+/// Rules are written as in `update-pr.filter` or `--filter`:
+///
+/// - `draft`: the pull request is a draft
+/// - `label:<names>`: it has one of these comma-separated labels
+/// - `author:<logins>`: one of these users opened it, `@me` being the owner of the token
+/// - `review:<decisions>`: its reviews are `approved`, `changes-requested` or `pending`
+/// - `age>7d` or `age<30d`: it was opened longer or less than this ago
+///
+/// Any rule is negated by a leading `!`, e.g. `!draft` or `!label:do-not-update`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    source: String,
+    negated: bool,
+    rule: Rule,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Rule {
+    Draft,
+    Label(Vec<String>),
+    Author(Vec<String>),
+    Review(Vec<ReviewDecision>),
+    OlderThan(Duration),
+    NewerThan(Duration),
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let source = source.trim();
+        let (negated, text) = match source.strip_prefix('!') {
+            Some(text) => (true, text.trim_start()),
+            None => (false, source),
+        };
+
+        let list = |values: &str| -> Result<Vec<String>, String> {
+            let values: Vec<String> = values
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+                .collect();
+            if values.is_empty() {
+                return Err(format!("{:?} lists nothing", source));
+            }
+            Ok(values)
+        };
+        let duration = |value: &str| {
+            humantime::parse_duration(value.trim())
+                .map_err(|e| format!("invalid age in {:?}: {}", source, e))
+        };
+
+        let rule = if text == "draft" {
+            Rule::Draft
+        } else if let Some(labels) = text.strip_prefix("label:") {
+            Rule::Label(list(labels)?)
+        } else if let Some(authors) = text.strip_prefix("author:") {
+            Rule::Author(list(authors)?)
+        } else if let Some(decisions) = text.strip_prefix("review:") {
+            Rule::Review(
+                list(decisions)?
+                    .iter()
+                    .map(|decision| ReviewDecision::from_str(decision, true))
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| {
+                        format!(
+                            "invalid review in {:?}, expected approved, changes-requested or pending",
+                            source
+                        )
+                    })?,
+            )
+        } else if let Some(age) = text.strip_prefix("age>") {
+            Rule::OlderThan(duration(age)?)
+        } else if let Some(age) = text.strip_prefix("age<") {
+            Rule::NewerThan(duration(age)?)
+        } else {
+            return Err(format!(
+                "unknown filter {:?}, expected draft, label:, author:, review:, age> or age<",
+                source
+            ));
+        };
+
+        Ok(Self {
+            source: source.to_string(),
+            negated,
+            rule,
+        })
+    }
+}
+
+/// What a filter is matched against, the review decision and the current user being only read when needed
+pub(crate) struct Candidate<'a> {
+    pub pr: &'a PullRequest,
+    pub review_decision: &'a dyn Fn() -> Result<ReviewDecision, ForgeError>,
+    pub current_user: &'a dyn Fn() -> Result<String, ForgeError>,
+    pub now: SystemTime,
+}
+
+impl Filter {
+    pub(crate) fn matches(&self, candidate: &Candidate) -> Result<bool, ForgeError> {
+        let pr = candidate.pr;
+        let age = || {
+            pr.created_at
+                .and_then(|created_at| candidate.now.duration_since(created_at).ok())
+        };
+
+        let matches = match &self.rule {
+            Rule::Draft => pr.draft,
+            Rule::Label(labels) => labels.iter().any(|label| pr.labels.contains(label)),
+            Rule::Author(authors) => {
+                let mut matches = false;
+                for author in authors {
+                    let author = match author.as_str() {
+                        "@me" => (candidate.current_user)()?,
+                        author => author.to_string(),
+                    };
+                    if author.eq_ignore_ascii_case(&pr.author) {
+                        matches = true;
+                        break;
+                    }
+                }
+                matches
+            }
+            Rule::Review(decisions) => decisions.contains(&(candidate.review_decision)()?),
+            Rule::OlderThan(duration) => age().is_some_and(|age| age > *duration),
+            Rule::NewerThan(duration) => age().is_some_and(|age| age < *duration),
+        };
+
+        Ok(matches != self.negated)
+    }
+}
+
+/// Leaves alone the branches whose pull request doesn't match the filters
+pub trait Filters: PullRequests {
+    /// The rules the pull request must match, none by default
+    fn filters(&self) -> &[Filter] {
+        &[]
+    }
+
+    /// Why the branch must not be updated, `None` when its pull request matches every filter
+    ///
+    /// Branches without an open pull request are skipped too, as the filters
+    /// cannot tell anything about them.
+    fn skip_reason(&self, branch: &str) -> Result<Option<String>, git2::Error> {
+        let (Some(forge), false) = (self.forge(), self.filters().is_empty()) else {
+            return Ok(None);
+        };
+        let to_git_error = |e: ForgeError| {
+            git2::Error::new(
+                ErrorCode::GenericError,
+                ErrorClass::Net,
+                format!("could not check the pull request: {}", e),
+            )
+        };
+
+        let repo = self.forge_repo().map_err(to_git_error)?;
+        let branch = branch.trim_start_matches("refs/heads/");
+        let Some(pr) = forge
+            .find_pull_request(&repo, branch)
+            .map_err(to_git_error)?
+        else {
+            let reason = "the branch has no open pull request".to_string();
+            self.reporter().report(&Event::PullRequestSkipped {
+                number: None,
+                reason: reason.clone(),
+            });
+            return Ok(Some(reason));
+        };
+
+        let candidate = Candidate {
+            pr: &pr,
+            review_decision: &|| forge.review_decision(&repo, pr.number),
+            current_user: &|| forge.current_user(),
+            now: SystemTime::now(),
+        };
+        for filter in self.filters() {
+            if !filter.matches(&candidate).map_err(to_git_error)? {
+                let reason = format!(
+                    "pull request #{} does not match {}",
+                    pr.number, filter.source
+                );
+                tracing::info!(number = pr.number, filter = %filter.source, "skipping the branch");
+                self.reporter().report(&Event::PullRequestSkipped {
+                    number: Some(pr.number),
+                    reason: reason.clone(),
+                });
+
+                return Ok(Some(reason));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Filters for Updater {
+    fn filters(&self) -> &[Filter] {
+        &self.filters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use color_eyre::eyre::Result;
+    use serde_json::json;
+
+    use super::*;
+    use crate::app::fixture::{git_cmd, TestFixture};
+    use crate::{
+        app::MergeOutcome,
+        forge::{fake::FakeApi, GitHub, PullRequestState},
+        report::SilentReporter,
+    };
+
+    /// A clone on a `pr` branch
+    fn setup() -> Result<PathBuf> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = fixture.get_repo_dir("usage")?;
+        git_cmd(&usage_dir)
+            .args(["checkout", "-b", "pr"])
+            .status()?;
+
+        Ok(usage_dir)
+    }
+
+    fn pull_request() -> PullRequest {
+        PullRequest {
+            number: 12,
+            url: "https://github.com/org/api/pull/12".to_string(),
+            base: "main".to_string(),
+            head_sha: "abc".to_string(),
+            state: PullRequestState::Open,
+            draft: false,
+            author: "ann".to_string(),
+            labels: vec!["backend".to_string(), "do-not-update".to_string()],
+            created_at: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(100_000)),
+        }
+    }
+
+    fn matches(filter: &str, pr: &PullRequest) -> bool {
+        let candidate = Candidate {
+            pr,
+            review_decision: &|| Ok(ReviewDecision::ChangesRequested),
+            current_user: &|| Ok("Ann".to_string()),
+            now: SystemTime::UNIX_EPOCH + Duration::from_secs(100_000 + 3 * 86_400),
+        };
+
+        filter
+            .parse::<Filter>()
+            .unwrap()
+            .matches(&candidate)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_filters() {
+        let pr = pull_request();
+
+        assert!(matches("!draft", &pr));
+        assert!(!matches("draft", &pr));
+        assert!(matches("label:frontend, backend", &pr));
+        assert!(!matches("!label:do-not-update", &pr));
+        assert!(matches("author:@me", &pr));
+        assert!(!matches("author:bob", &pr));
+        assert!(matches("review:changes-requested,pending", &pr));
+        assert!(!matches("review:approved", &pr));
+        assert!(matches("age>2d", &pr));
+        assert!(matches("age<1week", &pr));
+        assert!(!matches("!age<1week", &pr));
+
+        assert!("label:".parse::<Filter>().is_err());
+        assert!("review:lgtm".parse::<Filter>().is_err());
+        assert!("stale".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn test_skip_draft() -> Result<()> {
+        let usage_dir = setup()?;
+        let api = FakeApi::start(|request| {
+            if request.path.contains("/remote/pulls?") {
+                (
+                    200,
+                    json!([{
+                        "number": 12,
+                        "html_url": "https://github.com/org/remote/pull/12",
+                        "state": "open",
+                        "draft": true,
+                        "head": { "ref": "pr", "sha": "abc" },
+                        "base": { "ref": "main", "sha": "def" },
+                    }]),
+                )
+            } else {
+                (404, json!({ "message": "Not Found" }))
+            }
+        })?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .forge(GitHub::new("token").api_url(&api.url))
+            .filter("!draft".parse().unwrap())
+            .update()?;
+
+        assert_eq!(
+            outcome.merge,
+            MergeOutcome::Skipped("pull request #12 does not match !draft".to_string())
+        );
+        assert_eq!(api.requests().len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    ffi::OsStr,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use git2::{ErrorClass, ErrorCode};
+
+use super::{repo::Repo, Updater};
+
+pub trait Hooks: Repo {
+    /// Whether hooks should be skipped, the equivalent of `--no-verify`
+    fn no_verify(&self) -> bool {
+        false
+    }
+
+    /// Directory holding the hooks, honoring `core.hooksPath`
+    fn hooks_dir(&self) -> PathBuf {
+        let repo = self.repo();
+
+        let hooks_path = repo
+            .config()
+            .and_then(|config| config.get_path("core.hooksPath"))
+            .ok();
+
+        match hooks_path {
+            // Relative paths are resolved from where hooks run, the top of the working tree
+            Some(path) if path.is_relative() => {
+                repo.workdir().unwrap_or_else(|| repo.path()).join(path)
+            }
+            Some(path) => path,
+            None => repo.path().join("hooks"),
+        }
+    }
+
+    /// Run the hook `name` if it exists and is executable
+    ///
+    /// A hook exiting with a non-zero status is reported as an error with code
+    /// [`ErrorCode::User`] and class [`ErrorClass::Callback`].
+    ///
+    /// * `name` - The name of the hook, e.g. `pre-commit`
+    /// * `args` - The arguments git passes to the hook
+    /// * `stdin` - The data git writes to the hook's standard input
+    fn run_hook(
+        &self,
+        name: &str,
+        args: &[&OsStr],
+        stdin: Option<&str>,
+    ) -> Result<(), git2::Error> {
+        if self.no_verify() {
+            return Ok(());
+        }
+
+        let path = self.hooks_dir().join(name);
+        if !is_executable(&path) {
+            return Ok(());
+        }
+
+        tracing::debug!(hook = name, ?args, "running hook");
+
+        let repo = self.repo();
+        let mut cmd = Command::new(&path);
+        cmd.args(args)
+            .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+            .env("GIT_DIR", repo.path())
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            });
+
+        let mut child = cmd.spawn().map_err(|e| hook_error(name, &e.to_string()))?;
+
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            // The hook may exit without reading its input, so a broken pipe is not an error
+            if let Err(e) = pipe.write_all(input.as_bytes()) {
+                if e.kind() != std::io::ErrorKind::BrokenPipe {
+                    return Err(hook_error(name, &e.to_string()));
+                }
+            }
+        }
+
+        let status = child.wait().map_err(|e| hook_error(name, &e.to_string()))?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            tracing::warn!(hook = name, %status, "hook failed");
+            Err(hook_error(name, &format!("exited with {}", status)))
+        }
+    }
+}
+
+impl Hooks for Updater {
+    fn no_verify(&self) -> bool {
+        self.no_verify
+    }
+}
+
+/// Whether the error was produced by a failing hook
+pub fn is_hook_error(e: &git2::Error) -> bool {
+    e.code() == ErrorCode::User && e.class() == ErrorClass::Callback
+}
+
+fn hook_error(name: &str, reason: &str) -> git2::Error {
+    git2::Error::new(
+        ErrorCode::User,
+        ErrorClass::Callback,
+        format!("{} hook failed: {}", name, reason),
+    )
+}
+
+#[cfg(unix)]
+fn is_executable(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &std::path::Path) -> bool {
+    path.is_file()
+}
@@ -0,0 +1,345 @@
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use git2::{AttrCheckFlags, Delta, ErrorClass, ErrorCode, FileMode, Remote, Tree};
+
+use crate::report::Event;
+
+use super::{hooks::Hooks, Updater};
+
+/// The smudge filter `git lfs install` configures, used when none is set
+const DEFAULT_SMUDGE: &str = "git-lfs smudge -- %f";
+
+/// Support for repositories storing files in Git LFS
+///
+/// libgit2 runs no filter drivers, so checkouts leave LFS pointers in the
+/// working tree and pushes don't upload LFS objects. The smudge filter and
+/// `git lfs pre-push` are run for it, the same way git would.
+pub trait Lfs: Hooks {
+    /// Whether the repository tracks files with LFS, per `.gitattributes` or `info/attributes`
+    fn uses_lfs(&self) -> bool {
+        let repo = self.repo();
+
+        let mut attributes = vec![repo.path().join("info").join("attributes")];
+        if let Some(workdir) = repo.workdir() {
+            attributes.push(workdir.join(".gitattributes"));
+        }
+
+        attributes.iter().any(|path| {
+            std::fs::read_to_string(path)
+                .map(|content| content.contains("filter=lfs"))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Replace the LFS pointers checked out going from `from` to `to` by their content
+    ///
+    /// Runs `filter.lfs.smudge`, or `git-lfs smudge` when it isn't configured,
+    /// for every added or modified path with the `lfs` filter attribute.
+    fn smudge_lfs_files(&self, from: &Tree, to: &Tree) -> Result<(), git2::Error> {
+        let repo = self.repo();
+        let Some(workdir) = repo.workdir() else {
+            return Ok(());
+        };
+
+        let diff = repo.diff_tree_to_tree(Some(from), Some(to), None)?;
+        let mut files = Vec::new();
+        for delta in diff.deltas() {
+            let file = delta.new_file();
+            let is_blob = matches!(file.mode(), FileMode::Blob | FileMode::BlobExecutable);
+
+            if delta.status() == Delta::Deleted || !is_blob {
+                continue;
+            }
+            let Some(path) = file.path() else {
+                continue;
+            };
+            if repo.get_attr(path, "filter", AttrCheckFlags::FILE_THEN_INDEX)? == Some("lfs") {
+                files.push((path.to_path_buf(), file.id()));
+            }
+        }
+
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        let _span = tracing::info_span!("lfs_smudge", count = files.len()).entered();
+
+        let smudge = match repo.config()?.get_string("filter.lfs.smudge") {
+            Ok(smudge) => smudge,
+            Err(_) => {
+                ensure_git_lfs()?;
+                DEFAULT_SMUDGE.to_string()
+            }
+        };
+
+        for (path, oid) in &files {
+            tracing::debug!(path = %path.display(), "smudging");
+
+            let pointer = repo.find_blob(*oid)?;
+            let content = run_filter(&smudge, path, workdir, pointer.content())?;
+            std::fs::write(workdir.join(path), content)
+                .map_err(|e| lfs_error(&format!("could not write {}: {}", path.display(), e)))?;
+        }
+
+        self.reporter().report(&Event::LfsCheckout {
+            paths: files
+                .iter()
+                .map(|(path, _)| path.to_string_lossy().to_string())
+                .collect(),
+        });
+
+        Ok(())
+    }
+
+    /// Upload the LFS objects about to be pushed with `git lfs pre-push`
+    ///
+    /// Skipped when the `pre-push` hook, as installed by `git lfs install`,
+    /// already does it.
+    ///
+    /// * `input` - The lines given to the `pre-push` hook
+    fn lfs_pre_push(&self, input: &str, remote: &Remote) -> Result<(), git2::Error> {
+        if !self.uses_lfs() {
+            return Ok(());
+        }
+
+        let hook = std::fs::read_to_string(self.hooks_dir().join("pre-push")).unwrap_or_default();
+        if !self.no_verify() && hook.contains("git lfs pre-push") {
+            tracing::debug!("git lfs pre-push is run by the pre-push hook");
+            return Ok(());
+        }
+
+        ensure_git_lfs()?;
+
+        let repo = self.repo();
+        let remote_name = remote.name().unwrap_or_default();
+        let _span = tracing::info_span!("lfs_pre_push", remote = remote_name).entered();
+
+        let mut child = Command::new("git-lfs")
+            .arg("pre-push")
+            .arg(remote_name)
+            .arg(remote.url().unwrap_or(remote_name))
+            .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+            .env("GIT_DIR", repo.path())
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| lfs_error(&format!("could not run git-lfs: {}", e)))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(input.as_bytes())
+                .map_err(|e| lfs_error(&e.to_string()))?;
+        }
+
+        let status = child.wait().map_err(|e| lfs_error(&e.to_string()))?;
+        if !status.success() {
+            return Err(lfs_error(&format!(
+                "git lfs pre-push exited with {}",
+                status
+            )));
+        }
+
+        tracing::info!("uploaded LFS objects");
+
+        Ok(())
+    }
+}
+
+impl Lfs for Updater {}
+
+/// Fail unless the `git-lfs` command can be run
+fn ensure_git_lfs() -> Result<(), git2::Error> {
+    let installed = Command::new("git-lfs")
+        .arg("version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+
+    if installed {
+        Ok(())
+    } else {
+        Err(lfs_error(
+            "the repository uses Git LFS but git-lfs is not installed, see https://git-lfs.com",
+        ))
+    }
+}
+
+/// Run the filter `command` on `input` the way git does, with `%f` replaced by the quoted path
+fn run_filter(
+    command: &str,
+    path: &Path,
+    workdir: &Path,
+    input: &[u8],
+) -> Result<Vec<u8>, git2::Error> {
+    let quoted = format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"));
+    let command = command.replace("%f", &quoted);
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .current_dir(workdir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| lfs_error(&format!("could not run `{}`: {}", command, e)))?;
+
+    // Write from another thread, the filter may start writing before it read everything
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| lfs_error(&e.to_string()))?;
+    let _ = writer.join();
+
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(lfs_error(&format!(
+            "smudge filter failed for {}: `{}` exited with {}",
+            path.display(),
+            command,
+            output.status
+        )))
+    }
+}
+
+fn lfs_error(message: &str) -> git2::Error {
+    git2::Error::new(ErrorCode::GenericError, ErrorClass::Filter, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::Result;
+
+    use super::*;
+    use crate::app::{
+        fixture::{git_cmd, TestApp, TestFixture},
+        merge::Merge,
+    };
+
+    const POINTER: &str = "version https://git-lfs.github.com/spec/v1\noid sha256:abc\nsize 5\n";
+
+    /// Push an LFS tracked `data.bin` to main, stored as a pointer since git-lfs isn't used
+    fn push_lfs_file(fixture: &TestFixture) -> Result<()> {
+        let alt_dir = fixture.get_repo_dir("alt")?;
+
+        std::fs::write(
+            alt_dir.join(".gitattributes"),
+            "*.bin filter=lfs diff=lfs merge=lfs -text\n",
+        )?;
+        std::fs::write(alt_dir.join("data.bin"), POINTER)?;
+
+        git_cmd(&alt_dir).arg("add").arg(".").status()?;
+        git_cmd(&alt_dir)
+            .arg("commit")
+            .arg("-m")
+            .arg("add data.bin")
+            .status()?;
+        git_cmd(&alt_dir)
+            .arg("push")
+            .arg("origin")
+            .arg("HEAD:main")
+            .status()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fast_forward_smudges_lfs_files() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+        push_lfs_file(&fixture)?;
+
+        // A stand-in for git-lfs, the content of the file is its pointer in upper case
+        git_cmd(&usage_dir)
+            .arg("config")
+            .arg("filter.lfs.smudge")
+            .arg("tr a-z A-Z")
+            .status()?;
+        git_cmd(&usage_dir)
+            .arg("fetch")
+            .arg("origin")
+            .arg("main")
+            .status()?;
+
+        let app = TestApp::open(&usage_dir)?;
+        assert!(!app.uses_lfs());
+
+        app.merge("main", app.get_fetch_commit()?)?;
+
+        assert!(app.uses_lfs());
+        assert_eq!(
+            std::fs::read_to_string(usage_dir.join("data.bin"))?,
+            POINTER.to_uppercase()
+        );
+        assert_eq!(
+            std::fs::read_to_string(usage_dir.join("README.md"))?,
+            "# Test\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failing_smudge_rolls_back() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+        push_lfs_file(&fixture)?;
+
+        git_cmd(&usage_dir)
+            .arg("config")
+            .arg("filter.lfs.smudge")
+            .arg("exit 1")
+            .status()?;
+        git_cmd(&usage_dir)
+            .arg("fetch")
+            .arg("origin")
+            .arg("main")
+            .status()?;
+
+        let app = TestApp::open(&usage_dir)?;
+        let head_before = app.repo.refname_to_id("HEAD")?;
+
+        assert!(app.merge("main", app.get_fetch_commit()?).is_err());
+        assert_eq!(app.repo.refname_to_id("HEAD")?, head_before);
+        assert!(!usage_dir.join("data.bin").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_git_lfs() -> Result<()> {
+        if ensure_git_lfs().is_ok() {
+            // Only testable where git-lfs isn't installed
+            return Ok(());
+        }
+
+        let fixture = TestFixture::new()?.setup()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+        push_lfs_file(&fixture)?;
+
+        git_cmd(&usage_dir)
+            .arg("fetch")
+            .arg("origin")
+            .arg("main")
+            .status()?;
+
+        let app = TestApp::open(&usage_dir)?;
+
+        let err = app.merge("main", app.get_fetch_commit()?).unwrap_err();
+
+        assert_eq!(err.class(), ErrorClass::Filter);
+        assert!(err.message().contains("git-lfs is not installed"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use git2::{ErrorClass, ErrorCode, Index, IndexEntry, Oid};
+
+use crate::{report::Event, utils::git::path_matches};
+
+use super::{merge::restore, repo::Repo, submodules::INDEX_ENTRY_STAGE_MASK, Updater};
+
+/// Files regenerated by a command rather than merged, such as `Cargo.lock`
+///
+/// Written as in `update-pr.lockfile` or `--lockfile`, `<pattern>=<command>`,
+/// e.g. `Cargo.lock=cargo update --workspace`. A pattern without a slash
+/// matches the name of the file in any directory, otherwise the whole path.
+/// The command is run by `sh` in the directory of the lockfile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lockfile {
+    pattern: String,
+    command: String,
+}
+
+impl FromStr for Lockfile {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        match source.split_once('=') {
+            Some((pattern, command))
+                if !pattern.trim().is_empty() && !command.trim().is_empty() =>
+            {
+                Ok(Self {
+                    pattern: pattern.trim().to_string(),
+                    command: command.trim().to_string(),
+                })
+            }
+            _ => Err(format!(
+                "invalid lockfile {:?}, expected <pattern>=<command>",
+                source
+            )),
+        }
+    }
+}
+
+impl Lockfile {
+    fn matches(&self, path: &str) -> bool {
+        path_matches(&self.pattern, path)
+    }
+}
+
+/// Resolves conflicts in lockfiles by running the command regenerating them
+pub trait Lockfiles: Repo {
+    /// The lockfiles regenerated on conflicts, none by default
+    fn lockfiles(&self) -> &[Lockfile] {
+        &[]
+    }
+
+    /// Resolve the conflicts of `idx` when they are all in lockfiles, returning whether they were
+    ///
+    /// The lockfiles of the base branch, `theirs`, are checked out with the
+    /// rest of the merge, then regenerated and staged in `idx`. When a
+    /// command fails, the files of the merge are put back as in `head` and
+    /// the error is returned.
+    fn regenerate_lockfiles(&self, idx: &mut Index, head: Oid) -> Result<bool, git2::Error> {
+        if self.lockfiles().is_empty() || !idx.has_conflicts() {
+            return Ok(false);
+        }
+        let repo = self.repo();
+        let Some(workdir) = repo.workdir() else {
+            return Ok(false);
+        };
+
+        let mut conflicts: Vec<(IndexEntry, &Lockfile)> = Vec::new();
+        for conflict in idx.conflicts()? {
+            let conflict = conflict?;
+            let path = conflict
+                .our
+                .as_ref()
+                .or(conflict.their.as_ref())
+                .or(conflict.ancestor.as_ref())
+                .map(|entry| String::from_utf8_lossy(&entry.path).to_string())
+                .unwrap_or_default();
+
+            let lockfile = self.lockfiles().iter().find(|l| l.matches(&path));
+            match (lockfile, conflict.their) {
+                (Some(lockfile), Some(theirs)) => conflicts.push((theirs, lockfile)),
+                _ => {
+                    tracing::debug!(%path, "not only lockfiles are conflicting");
+                    return Ok(false);
+                }
+            }
+        }
+
+        let _span = tracing::info_span!("lockfiles", count = conflicts.len()).entered();
+        for (entry, _) in &mut conflicts {
+            let path = PathBuf::from(String::from_utf8_lossy(&entry.path).to_string());
+            idx.conflict_remove(&path)?;
+            entry.flags &= !INDEX_ENTRY_STAGE_MASK;
+            idx.add(entry)?;
+        }
+        // Fails rather than overwriting uncommitted changes
+        repo.checkout_index(Some(idx), None)?;
+
+        // Put the working tree back as it was if a command fails
+        let head = repo.find_commit(head)?;
+        let merged = repo.find_tree(idx.write_tree_to(repo)?)?;
+        let rollback = |e: git2::Error| {
+            restore(repo, &head, &merged).map_or_else(|reset_err| reset_err, |_| e)
+        };
+
+        let mut regenerated: Vec<(PathBuf, &str)> = Vec::new();
+        for (entry, lockfile) in conflicts {
+            let path = PathBuf::from(String::from_utf8_lossy(&entry.path).to_string());
+            let dir = workdir.join(path.parent().unwrap_or(Path::new("")));
+
+            // A command regenerating several lockfiles of a directory is run once
+            if !regenerated.iter().any(|(done, command)| {
+                done.parent() == path.parent() && *command == lockfile.command
+            }) {
+                run_command(&lockfile.command, &dir).map_err(rollback)?;
+            }
+
+            let oid = repo.blob_path(&workdir.join(&path)).map_err(rollback)?;
+            let mut entry = entry;
+            entry.id = oid;
+            idx.add(&entry)?;
+            tracing::info!(path = %path.display(), command = %lockfile.command, "regenerated lockfile");
+
+            self.reporter().report(&Event::LockfileRegenerated {
+                path: path.to_string_lossy().to_string(),
+                command: lockfile.command.clone(),
+            });
+            regenerated.push((path, &lockfile.command));
+        }
+
+        Ok(true)
+    }
+}
+
+impl Lockfiles for Updater {
+    fn lockfiles(&self) -> &[Lockfile] {
+        &self.lockfiles
+    }
+}
+
+/// Run `command` with `sh` in `dir`, its output going to the log
+fn run_command(command: &str, dir: &Path) -> Result<(), git2::Error> {
+    tracing::debug!(%command, dir = %dir.display(), "running lockfile command");
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| lockfile_error(&format!("could not run `{}`: {}", command, e)))?;
+    tracing::trace!(
+        stdout = %String::from_utf8_lossy(&output.stdout),
+        stderr = %String::from_utf8_lossy(&output.stderr),
+        "lockfile command output"
+    );
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(lockfile_error(&format!(
+            "`{}` exited with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+fn lockfile_error(message: &str) -> git2::Error {
+    git2::Error::new(ErrorCode::GenericError, ErrorClass::Merge, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use color_eyre::eyre::Result;
+    use git2::Repository;
+
+    use super::*;
+    use crate::app::fixture::{setup_branches, TestFixture};
+    use crate::{app::MergeOutcome, report::SilentReporter};
+
+    /// Concatenates the dependencies listed in `deps`, like a package manager would
+    const REGENERATE: &str = "deps.lock=cat deps/* | sort > deps.lock";
+
+    /// A `pr` branch in `usage` and a commit on `main` each adding a dependency, which conflicts in `deps.lock`
+    ///
+    /// `pr` also changes `file.txt` like `main` when `conflicting` is set.
+    fn setup(conflicting: bool) -> Result<PathBuf> {
+        let fixture = TestFixture::new()?.setup()?;
+        let mut pr = vec![("deps/b", "b\n"), ("deps.lock", "a\nb\n")];
+        if conflicting {
+            pr.push(("file.txt", "pr\n"));
+        }
+
+        setup_branches(
+            &fixture,
+            &[
+                ("deps/a", "a\n"),
+                ("deps.lock", "a\n"),
+                ("file.txt", "base\n"),
+            ],
+            &pr,
+            &[
+                ("deps/c", "c\n"),
+                ("deps.lock", "a\nc\n"),
+                ("file.txt", "main\n"),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_matches() {
+        let lockfile: Lockfile = "Cargo.lock=cargo update --workspace".parse().unwrap();
+        assert_eq!(lockfile.command, "cargo update --workspace");
+        assert!(lockfile.matches("Cargo.lock"));
+        assert!(lockfile.matches("crates/api/Cargo.lock"));
+        assert!(!lockfile.matches("Cargo.toml"));
+
+        let lockfile: Lockfile = "web/*.json=npm install".parse().unwrap();
+        assert!(lockfile.matches("web/package-lock.json"));
+        assert!(!lockfile.matches("package-lock.json"));
+
+        assert!("Cargo.lock".parse::<Lockfile>().is_err());
+        assert!("=cargo update".parse::<Lockfile>().is_err());
+    }
+
+    #[test]
+    fn test_regenerate() -> Result<()> {
+        let usage_dir = setup(false)?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .lockfile(REGENERATE.parse().unwrap())
+            .update()?;
+
+        let MergeOutcome::Merged(merge) = outcome.merge else {
+            panic!("expected a merge, got {:?}", outcome.merge);
+        };
+        let repo = Repository::open(&usage_dir)?;
+        let tree = repo.find_commit(merge)?.tree()?;
+        let blob = repo.find_blob(tree.get_path(Path::new("deps.lock"))?.id())?;
+        assert_eq!(blob.content(), b"a\nb\nc\n");
+        assert!(repo.statuses(None)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_other_conflicts() -> Result<()> {
+        let usage_dir = setup(true)?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .lockfile(REGENERATE.parse().unwrap())
+            .update()?;
+
+        assert_eq!(
+            outcome.merge,
+            MergeOutcome::Conflicts(vec!["deps.lock".to_string(), "file.txt".to_string()])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failing_command() -> Result<()> {
+        let usage_dir = setup(false)?;
+        let head_before = Repository::open(&usage_dir)?.refname_to_id("HEAD")?;
+        // An uncommitted change the merge doesn't touch
+        fs::write(usage_dir.join("deps/a"), "mine\n")?;
+
+        let result = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .lockfile("deps.lock=exit 3".parse().unwrap())
+            .update();
+
+        assert!(result.is_err());
+        let repo = Repository::open(&usage_dir)?;
+        assert_eq!(repo.refname_to_id("HEAD")?, head_before);
+        assert!(!usage_dir.join("deps/c").exists());
+        assert_eq!(fs::read_to_string(usage_dir.join("deps/a"))?, "mine\n");
+        assert_eq!(fs::read_to_string(usage_dir.join("deps.lock"))?, "a\nb\n");
+
+        Ok(())
+    }
+}
@@ -1,25 +1,58 @@
-use super::{repo::Repo, App};
+use std::ffi::OsStr;
 
-pub trait Merge: Repo {
+use git2::{build::CheckoutBuilder, Commit, Repository, ResetType, Tree};
+
+use crate::report::{Analysis, Event};
+
+use super::{
+    hooks::Hooks, lfs::Lfs, lockfiles::Lockfiles, merge_drivers::MergeDrivers,
+    merge_options::MergeTuning, outcome::MergeOutcome, partial::Partial, rerere::Rerere,
+    submodules::Submodules, Updater,
+};
+
+pub trait Merge:
+    Hooks + Lfs + Lockfiles + MergeDrivers + MergeTuning + Partial + Rerere + Submodules
+{
     /// Merge the current branch with the remote branch
     ///
-    /// * `remote_branch` - The name of the remote branch to merge from
+    /// * `remote_branch` - The name of the branch to update, either short or a full reference
     /// * `fetch_commit` - The commit to merge
     fn merge(
         &self,
         remote_branch: &str,
         fetch_commit: git2::AnnotatedCommit<'_>,
-    ) -> Result<(), git2::Error> {
+    ) -> Result<MergeOutcome, git2::Error> {
         let repo = self.repo();
+        let _span = tracing::info_span!(
+            "merge",
+            branch = remote_branch,
+            commit = %fetch_commit.id()
+        )
+        .entered();
 
         // 1. do a merge analysis
         let analysis = repo.merge_analysis(&[&fetch_commit])?;
+        tracing::debug!(analysis = ?analysis.0, "merge analysis");
+
+        self.reporter().report(&Event::MergeAnalysis {
+            branch: remote_branch.to_string(),
+            analysis: if analysis.0.is_fast_forward() {
+                Analysis::FastForward
+            } else if analysis.0.is_normal() {
+                Analysis::Normal
+            } else {
+                Analysis::UpToDate
+            },
+        });
 
         // 2. Do the appropriate merge
         if analysis.0.is_fast_forward() {
-            println!("Doing a fast forward");
             // do a fast forward
-            let refname = format!("refs/heads/{}", remote_branch);
+            let refname = if remote_branch.starts_with("refs/") {
+                remote_branch.to_string()
+            } else {
+                format!("refs/heads/{}", remote_branch)
+            };
             match repo.find_reference(&refname) {
                 Ok(mut r) => {
                     self.fast_forward(&mut r, &fetch_commit)?;
@@ -43,21 +76,21 @@ pub trait Merge: Repo {
                     ))?;
                 }
             };
+            Ok(MergeOutcome::FastForward(fetch_commit.id()))
         } else if analysis.0.is_normal() {
             // do a normal merge
             let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-            self.normal_merge(&head_commit, &fetch_commit)?;
+            self.normal_merge(&head_commit, &fetch_commit)
         } else {
-            println!("Nothing to do...");
+            Ok(MergeOutcome::UpToDate)
         }
-        Ok(())
     }
 
     fn normal_merge(
         &self,
         local: &git2::AnnotatedCommit,
         remote: &git2::AnnotatedCommit,
-    ) -> Result<(), git2::Error> {
+    ) -> Result<MergeOutcome, git2::Error> {
         let repo = self.repo();
 
         let local_tree = repo.find_commit(local.id())?.tree()?;
@@ -65,31 +98,95 @@ pub trait Merge: Repo {
         let ancestor = repo
             .find_commit(repo.merge_base(local.id(), remote.id())?)?
             .tree()?;
-        let mut idx = repo.merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
+        self.fetch_missing_blobs(&ancestor, &local_tree)?;
+        self.fetch_missing_blobs(&ancestor, &remote_tree)?;
+        let mut idx = repo.merge_trees(
+            &ancestor,
+            &local_tree,
+            &remote_tree,
+            Some(&self.tree_merge_options()),
+        )?;
+        self.merge_with_drivers(&mut idx)?;
+        self.merge_with_path_options(&mut idx)?;
+        self.resolve_gitlink_conflicts(&mut idx)?;
+        self.reuse_resolutions(&mut idx)?;
+        let regenerated = self.regenerate_lockfiles(&mut idx, local.id())?;
 
         if idx.has_conflicts() {
-            println!("Merge conflicts detected...");
+            let paths = conflicted_paths(&idx)?;
+            tracing::warn!(?paths, "merge conflicts");
+            self.reporter().report(&Event::Conflict {
+                paths: paths.clone(),
+            });
+
             repo.checkout_index(Some(&mut idx), None)?;
-            return Ok(());
+            self.checkout_binary_conflicts(&idx)?;
+            self.record_conflicts(&idx, local.id(), remote.id())?;
+            return Ok(MergeOutcome::Conflicts(paths));
         }
         let result_tree = repo.find_tree(idx.write_tree_to(repo)?)?;
-        // now create the merge commit
-        let msg = format!("Merge '{}' into {}", get_name(remote), get_name(local));
-        let sig = repo.signature()?;
         let local_commit = repo.find_commit(local.id())?;
         let remote_commit = repo.find_commit(remote.id())?;
+
+        // Stage the result so hooks see the same state as they would with `git merge`,
+        // failing rather than overwriting uncommitted changes. The index is read
+        // again first, as git may have changed it since the repository was opened
+        let mut index = repo.index()?;
+        index.read(true)?;
+        if regenerated {
+            // The working tree holds the merge already, with the regenerated lockfiles
+            index.read_tree(&result_tree)?;
+            index.write()?;
+        } else {
+            repo.checkout_tree(result_tree.as_object(), None)?;
+        }
+
+        // Put the branch back where it was if a blocking hook rejects the merge, or a later step fails
+        let rollback = |e: git2::Error| {
+            restore(repo, &local_commit, &result_tree).map_or_else(|reset_err| reset_err, |_| e)
+        };
+
+        self.run_hook("pre-commit", &[], None).map_err(rollback)?;
+
+        // commit-msg receives the message in a file and may rewrite it
+        let msg_path = repo.path().join("COMMIT_EDITMSG");
+        let msg = format!("Merge '{}' into {}", get_name(remote), get_name(local));
+        std::fs::write(&msg_path, &msg)
+            .map_err(|e| rollback(git2::Error::from_str(&e.to_string())))?;
+        self.run_hook("commit-msg", &[msg_path.as_os_str()], None)
+            .map_err(rollback)?;
+        let msg = std::fs::read_to_string(&msg_path).unwrap_or(msg);
+
+        // now create the merge commit
+        let sig = repo.signature().map_err(rollback)?;
         // Do our merge commit and set current branch head to that commit.
-        let _merge_commit = repo.commit(
-            Some("HEAD"),
-            &sig,
-            &sig,
-            &msg,
-            &result_tree,
-            &[&local_commit, &remote_commit],
-        )?;
+        let merge_commit = repo
+            .commit(
+                Some("HEAD"),
+                &sig,
+                &sig,
+                &msg,
+                &result_tree,
+                &[&local_commit, &remote_commit],
+            )
+            .map_err(rollback)?;
         // Set working tree to match head.
-        repo.checkout_head(None)?;
-        Ok(())
+        repo.checkout_head(None).map_err(rollback)?;
+        tracing::info!(oid = %merge_commit, "created merge commit");
+        self.smudge_lfs_files(&local_tree, &result_tree)
+            .map_err(rollback)?;
+        self.update_changed_submodules(&local_tree, &result_tree)
+            .map_err(rollback)?;
+
+        self.reporter().report(&Event::CommitCreated {
+            oid: merge_commit,
+            message: msg,
+        });
+
+        // post-merge cannot affect the outcome of the merge, so its status is ignored
+        let _ = self.run_hook("post-merge", &[OsStr::new("0")], None);
+
+        Ok(MergeOutcome::Merged(merge_commit))
     }
 
     fn fast_forward(
@@ -104,7 +201,23 @@ pub trait Merge: Repo {
             None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
         };
         let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
-        println!("{}", msg);
+        self.reporter().report(&Event::FastForward {
+            branch: name.clone(),
+            oid: rc.id(),
+        });
+        tracing::info!(oid = %rc.id(), "fast-forward {}", name);
+        let current = match lb.target() {
+            Some(current) => Some(repo.find_commit(current)?),
+            None => None,
+        };
+        let current_tree = match &current {
+            Some(current) => Some(current.tree()?),
+            None => None,
+        };
+        let target_tree = repo.find_commit(rc.id())?.tree()?;
+        if let Some(current_tree) = &current_tree {
+            self.fetch_missing_blobs(current_tree, &target_tree)?;
+        }
         lb.set_target(rc.id(), &msg)?;
         repo.set_head(&name)?;
         repo.checkout_head(Some(
@@ -114,11 +227,80 @@ pub trait Merge: Repo {
                 // but this is just an example so maybe not.
                 .force(),
         ))?;
+        if let (Some(current), Some(current_tree)) = (&current, &current_tree) {
+            // Put the branch back where it was if LFS files or submodules cannot be checked out
+            let rollback = |e: git2::Error| {
+                repo.reset(current.as_object(), ResetType::Hard, None)
+                    .map_or_else(|reset_err| reset_err, |_| e)
+            };
+
+            self.smudge_lfs_files(current_tree, &target_tree)
+                .map_err(rollback)?;
+            self.update_changed_submodules(current_tree, &target_tree)
+                .map_err(rollback)?;
+        }
+
+        // git runs post-merge after fast-forwards as well
+        let _ = self.run_hook("post-merge", &[OsStr::new("0")], None);
+
         Ok(())
     }
 }
 
-impl Merge for App {}
+impl Merge for Updater {}
+
+/// The paths with conflicts in a merged index, in index order
+fn conflicted_paths(idx: &git2::Index) -> Result<Vec<String>, git2::Error> {
+    let mut paths: Vec<String> = Vec::new();
+
+    for conflict in idx.conflicts()? {
+        let conflict = conflict?;
+        let entry = conflict
+            .our
+            .or(conflict.their)
+            .or(conflict.ancestor)
+            .expect("a conflict has at least one side");
+        let path = String::from_utf8_lossy(&entry.path).to_string();
+
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Put `HEAD` and the index back at `commit`, and the files a merge into `merged` changed back in the working tree
+///
+/// The merge was checked out safely, so these files had no uncommitted
+/// changes, and the changes to the other files are kept.
+pub(super) fn restore(
+    repo: &Repository,
+    commit: &Commit,
+    merged: &Tree,
+) -> Result<(), git2::Error> {
+    let diff = repo.diff_tree_to_tree(Some(&commit.tree()?), Some(merged), None)?;
+    repo.reset(commit.as_object(), ResetType::Mixed, None)?;
+    if diff.deltas().len() == 0 {
+        return Ok(());
+    }
+
+    // Files added by the merge are untracked once the index is reset
+    let mut checkout = CheckoutBuilder::new();
+    checkout
+        .force()
+        .remove_untracked(true)
+        .disable_pathspec_match(true);
+    for delta in diff.deltas() {
+        for file in [delta.old_file(), delta.new_file()] {
+            if let Some(path) = file.path() {
+                checkout.path(path);
+            }
+        }
+    }
+
+    repo.checkout_head(Some(&mut checkout))
+}
 
 fn get_name(commit: &git2::AnnotatedCommit) -> String {
     commit
@@ -136,32 +318,10 @@ mod tests {
     };
 
     use color_eyre::eyre::Result;
-    use git2::{AnnotatedCommit, Repository};
     use testdir::testdir;
 
     use super::*;
-
-    pub struct TestApp {
-        pub repo: Repository,
-    }
-
-    impl TestApp {
-        pub fn get_fetch_commit(&self) -> Result<AnnotatedCommit<'_>> {
-            let reference = self.repo.find_reference("refs/remotes/origin/main")?;
-
-            let ret = self.repo.reference_to_annotated_commit(&reference)?;
-
-            Ok(ret)
-        }
-    }
-
-    impl Repo for TestApp {
-        fn repo(&self) -> &Repository {
-            &self.repo
-        }
-    }
-
-    impl Merge for TestApp {}
+    use crate::app::{fixture::TestApp, repo::Repo};
 
     fn git_cmd(dir: &Path) -> Command {
         let mut cmd = Command::new("git");
@@ -287,9 +447,7 @@ mod tests {
                 .status()?;
         }
 
-        let app = TestApp {
-            repo: Repository::open(&usage_dir)?,
-        };
+        let app = TestApp::open(&usage_dir)?;
         let fetch_commit = app.get_fetch_commit()?;
 
         let analysis = app.repo().merge_analysis(&[&fetch_commit])?;
@@ -343,9 +501,7 @@ mod tests {
                 .status()?;
         }
 
-        let app = TestApp {
-            repo: Repository::open(&usage_dir)?,
-        };
+        let app = TestApp::open(&usage_dir)?;
 
         let fetch_commit = app.get_fetch_commit()?;
 
@@ -363,4 +519,177 @@ mod tests {
 
         Ok(())
     }
+
+    #[cfg(unix)]
+    fn write_hook(repo_dir: &Path, name: &str, script: &str) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let hook_path = repo_dir.join(".git").join("hooks").join(name);
+        std::fs::write(&hook_path, script)?;
+        std::fs::set_permissions(&hook_path, std::fs::Permissions::from_mode(0o755))?;
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_normal_merge_hooks() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+
+        // Create a commit in the repo we want to merge to
+        {
+            let file_path = usage_dir.join("file.txt");
+            let mut file = File::create(&file_path)?;
+            file.write_all(b"Hello World")?;
+
+            git_cmd(&usage_dir).arg("add").arg(&file_path).status()?;
+            git_cmd(&usage_dir)
+                .arg("commit")
+                .arg("-m")
+                .arg("add file.txt")
+                .status()?;
+        }
+
+        // Create and push a commit in the repo we want to merge from
+        {
+            let alt_dir = fixture.get_repo_dir("alt")?;
+
+            let file_path = alt_dir.join("file2.txt");
+            let mut file = File::create(&file_path)?;
+            file.write_all(b"Hello World")?;
+
+            git_cmd(&alt_dir).arg("add").arg(&file_path).status()?;
+            git_cmd(&alt_dir)
+                .arg("commit")
+                .arg("-m")
+                .arg("add file2.txt")
+                .status()?;
+            git_cmd(&alt_dir)
+                .arg("push")
+                .arg("origin")
+                .arg("HEAD:main")
+                .status()?;
+        }
+
+        // Fetch remote changes
+        {
+            git_cmd(&usage_dir)
+                .arg("fetch")
+                .arg("origin")
+                .arg("main")
+                .status()?;
+        }
+
+        let app = TestApp::open(&usage_dir)?;
+        let head_id = app.repo().head()?.peel_to_commit()?.id();
+
+        // A failing pre-commit hook rolls the merge back
+        write_hook(&usage_dir, "pre-commit", "#!/bin/sh\nexit 1\n")?;
+
+        let err = app.merge("main", app.get_fetch_commit()?).unwrap_err();
+
+        assert!(crate::app::hooks::is_hook_error(&err));
+        assert_eq!(app.repo().head()?.peel_to_commit()?.id(), head_id);
+        assert!(!usage_dir.join("file2.txt").exists());
+
+        // commit-msg may rewrite the message
+        std::fs::remove_file(usage_dir.join(".git/hooks/pre-commit"))?;
+        write_hook(
+            &usage_dir,
+            "commit-msg",
+            "#!/bin/sh\necho 'Merged by hook' > \"$1\"\n",
+        )?;
+
+        app.merge("main", app.get_fetch_commit()?)?;
+
+        let merge_commit = app.repo().head()?.peel_to_commit()?;
+        assert_eq!(merge_commit.parent_count(), 2);
+        assert_eq!(merge_commit.message(), Some("Merged by hook\n"));
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_failed_merge_keeps_uncommitted_changes() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+        std::fs::write(usage_dir.join("file.txt"), "Hello World")?;
+        git_cmd(&usage_dir).arg("add").arg("file.txt").status()?;
+        git_cmd(&usage_dir)
+            .arg("commit")
+            .arg("-m")
+            .arg("add file.txt")
+            .status()?;
+
+        let alt_dir = fixture.get_repo_dir("alt")?;
+        std::fs::write(alt_dir.join("file2.txt"), "Hello World")?;
+        git_cmd(&alt_dir).arg("add").arg("file2.txt").status()?;
+        git_cmd(&alt_dir)
+            .arg("commit")
+            .arg("-m")
+            .arg("add file2.txt")
+            .status()?;
+        git_cmd(&alt_dir)
+            .arg("push")
+            .arg("origin")
+            .arg("HEAD:main")
+            .status()?;
+        git_cmd(&usage_dir)
+            .arg("fetch")
+            .arg("origin")
+            .arg("main")
+            .status()?;
+
+        let app = TestApp::open(&usage_dir)?;
+        let head_id = app.repo().head()?.peel_to_commit()?.id();
+        std::fs::write(usage_dir.join("README.md"), "# Changed\n")?;
+
+        // A rejected merge leaves the other uncommitted changes alone
+        write_hook(&usage_dir, "pre-commit", "#!/bin/sh\nexit 1\n")?;
+        assert!(app.merge("main", app.get_fetch_commit()?).is_err());
+        std::fs::remove_file(usage_dir.join(".git/hooks/pre-commit"))?;
+
+        assert_eq!(app.repo().head()?.peel_to_commit()?.id(), head_id);
+        assert!(!usage_dir.join("file2.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(usage_dir.join("README.md"))?,
+            "# Changed\n"
+        );
+
+        // So does a merge failing to commit
+        git_cmd(&usage_dir)
+            .arg("config")
+            .arg("user.name")
+            .arg("")
+            .status()?;
+        assert!(app.merge("main", app.get_fetch_commit()?).is_err());
+        git_cmd(&usage_dir)
+            .arg("config")
+            .arg("--unset")
+            .arg("user.name")
+            .status()?;
+
+        assert_eq!(app.repo().head()?.peel_to_commit()?.id(), head_id);
+        assert!(!usage_dir.join("file2.txt").exists());
+
+        // A file in the way of the merge is not overwritten
+        std::fs::write(usage_dir.join("file2.txt"), "Mine")?;
+        assert!(app.merge("main", app.get_fetch_commit()?).is_err());
+
+        assert_eq!(app.repo().head()?.peel_to_commit()?.id(), head_id);
+        assert_eq!(
+            std::fs::read_to_string(usage_dir.join("file2.txt"))?,
+            "Mine"
+        );
+        assert_eq!(
+            std::fs::read_to_string(usage_dir.join("README.md"))?,
+            "# Changed\n"
+        );
+
+        Ok(())
+    }
 }
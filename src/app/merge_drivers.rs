@@ -0,0 +1,412 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use git2::{
+    AttrCheckFlags, AttrValue, ErrorClass, ErrorCode, FileFavor, Index, IndexEntry, Repository,
+};
+
+use crate::report::Event;
+
+use super::{merge_options::MergeTuning, submodules::INDEX_ENTRY_STAGE_MASK, Updater};
+
+/// Length of conflict markers when `conflict-marker-size` is not set
+const DEFAULT_MARKER_SIZE: usize = 7;
+
+/// Files the versions of a conflict are written to for a custom driver, in the git directory
+const DRIVER_FILES: [&str; 3] = [
+    "UPDATE_PR_MERGE_BASE",
+    "UPDATE_PR_MERGE_OURS",
+    "UPDATE_PR_MERGE_THEIRS",
+];
+
+/// How a file changed on both branches is merged, following its `merge` attribute
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Driver {
+    /// The usual three-way merge, already done by `merge_trees`
+    Text,
+    /// Keep our version and leave the conflict
+    Binary,
+    /// Keep the lines of both sides
+    Union,
+    /// The command of `merge.<name>.driver`
+    Custom { name: String, command: String },
+}
+
+/// Merges conflicting files with the merge drivers set in `.gitattributes`, like `git merge`
+///
+/// libgit2 only knows the built-in drivers, the commands configured in
+/// `merge.<driver>.driver` are run here.
+pub trait MergeDrivers: MergeTuning {
+    /// Merge the conflicting files of `idx` again with the driver of their `merge` attribute
+    ///
+    /// The files merged without conflicts are staged in `idx`, the other
+    /// conflicts are left untouched.
+    fn merge_with_drivers(&self, idx: &mut Index) -> Result<(), git2::Error> {
+        if !idx.has_conflicts() {
+            return Ok(());
+        }
+        let repo = self.repo();
+
+        let mut merged: Vec<(IndexEntry, Vec<u8>, String)> = Vec::new();
+        for conflict in idx.conflicts()? {
+            let conflict = conflict?;
+            // Files deleted on one side are not merged by drivers
+            let (Some(ours), Some(theirs)) = (conflict.our, conflict.their) else {
+                continue;
+            };
+            let path = String::from_utf8_lossy(&ours.path).to_string();
+            let ancestor = match conflict.ancestor {
+                Some(ancestor) => ancestor,
+                None => empty_entry(repo, &ours)?,
+            };
+
+            let driver = driver(repo, &path)?;
+            tracing::debug!(%path, ?driver, "merge driver");
+            let content = match &driver {
+                // Binary conflicts are written out by `checkout_binary_conflicts`
+                Driver::Text | Driver::Binary => continue,
+                Driver::Union => {
+                    let mut opts = self.file_merge_options(&path);
+                    opts.favor(FileFavor::Union);
+                    let result =
+                        repo.merge_file_from_index(&ancestor, &ours, &theirs, Some(&mut opts))?;
+                    if !result.is_automergeable() {
+                        continue;
+                    }
+                    result.content().to_vec()
+                }
+                Driver::Custom { command, .. } => {
+                    match run_driver(repo, command, &path, [&ancestor, &ours, &theirs])? {
+                        Some(content) => content,
+                        None => continue,
+                    }
+                }
+            };
+
+            let name = match driver {
+                Driver::Custom { name, .. } => name,
+                _ => "union".to_string(),
+            };
+            merged.push((ours, content, name));
+        }
+
+        for (mut entry, content, driver) in merged {
+            let path = String::from_utf8_lossy(&entry.path).to_string();
+
+            idx.conflict_remove(Path::new(&path))?;
+            entry.flags &= !INDEX_ENTRY_STAGE_MASK;
+            entry.id = repo.blob(&content)?;
+            entry.file_size = content.len() as u32;
+            idx.add(&entry)?;
+            tracing::info!(%path, %driver, "merged with the merge driver");
+
+            self.reporter()
+                .report(&Event::MergedWithDriver { path, driver });
+        }
+
+        Ok(())
+    }
+
+    /// Write our version of the conflicting files with the `binary` driver to the working tree
+    ///
+    /// Like `git merge`, the conflicts stay in `idx` and the files get no conflict markers.
+    fn checkout_binary_conflicts(&self, idx: &Index) -> Result<(), git2::Error> {
+        let repo = self.repo();
+        let Some(workdir) = repo.workdir() else {
+            return Ok(());
+        };
+
+        for conflict in idx.conflicts()? {
+            let Some(ours) = conflict?.our else {
+                continue;
+            };
+            let path = String::from_utf8_lossy(&ours.path).to_string();
+            if driver(repo, &path)? != Driver::Binary {
+                continue;
+            }
+            tracing::debug!(%path, "keeping our version of the binary conflict");
+            fs::write(workdir.join(&path), repo.find_blob(ours.id)?.content()).map_err(io_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MergeDrivers for Updater {}
+
+/// The driver of the file at `path`, from its `merge` attribute or `merge.default`
+///
+/// Like git, drivers missing from the configuration fall back to `text`.
+fn driver(repo: &Repository, path: &str) -> Result<Driver, git2::Error> {
+    let attr = repo.get_attr(Path::new(path), "merge", AttrCheckFlags::FILE_THEN_INDEX)?;
+    let config = repo.config()?;
+
+    let name = match AttrValue::from_string(attr) {
+        AttrValue::True => return Ok(Driver::Text),
+        AttrValue::False => return Ok(Driver::Binary),
+        AttrValue::String(name) => name.to_string(),
+        AttrValue::Bytes(_) => return Ok(Driver::Text),
+        AttrValue::Unspecified => match config.get_string("merge.default") {
+            Ok(name) => name,
+            Err(_) => return Ok(Driver::Text),
+        },
+    };
+
+    Ok(match name.as_str() {
+        "text" => Driver::Text,
+        "binary" => Driver::Binary,
+        "union" => Driver::Union,
+        _ => match config.get_string(&format!("merge.{}.driver", name)) {
+            Ok(command) => Driver::Custom { name, command },
+            Err(_) => {
+                tracing::warn!(%path, driver = %name, "merge driver not configured, merging as text");
+                Driver::Text
+            }
+        },
+    })
+}
+
+/// An entry for an empty file at the path of `entry`, the ancestor of files added on both sides
+fn empty_entry(repo: &Repository, entry: &IndexEntry) -> Result<IndexEntry, git2::Error> {
+    Ok(IndexEntry {
+        ctime: entry.ctime,
+        mtime: entry.mtime,
+        dev: 0,
+        ino: 0,
+        mode: entry.mode,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id: repo.blob(b"")?,
+        flags: 0,
+        flags_extended: 0,
+        path: entry.path.clone(),
+    })
+}
+
+/// Run the driver `command` on the `base`, `ours` and `theirs` versions of `path`
+///
+/// Returns the merged content, or `None` when the driver left conflicts.
+fn run_driver(
+    repo: &Repository,
+    command: &str,
+    path: &str,
+    versions: [&IndexEntry; 3],
+) -> Result<Option<Vec<u8>>, git2::Error> {
+    let files: Vec<PathBuf> = DRIVER_FILES.iter().map(|f| repo.path().join(f)).collect();
+    let result = (|| {
+        for (file, entry) in files.iter().zip(versions) {
+            fs::write(file, repo.find_blob(entry.id)?.content()).map_err(io_error)?;
+        }
+
+        let marker_size = repo
+            .get_attr(
+                Path::new(path),
+                "conflict-marker-size",
+                AttrCheckFlags::FILE_THEN_INDEX,
+            )?
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_MARKER_SIZE);
+        let command = substitute(command, &files, marker_size, path);
+        tracing::debug!(%command, "running merge driver");
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .current_dir(repo.workdir().unwrap_or(repo.path()))
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| {
+                git2::Error::new(
+                    ErrorCode::GenericError,
+                    ErrorClass::Merge,
+                    format!("could not run merge driver `{}`: {}", command, e),
+                )
+            })?;
+        tracing::trace!(
+            stdout = %String::from_utf8_lossy(&output.stdout),
+            stderr = %String::from_utf8_lossy(&output.stderr),
+            "merge driver output"
+        );
+
+        if output.status.success() {
+            fs::read(&files[1]).map(Some).map_err(io_error)
+        } else {
+            tracing::debug!(%path, status = %output.status, "the merge driver left conflicts");
+            Ok(None)
+        }
+    })();
+
+    for file in &files {
+        let _ = fs::remove_file(file);
+    }
+
+    result
+}
+
+/// Replace `%O`, `%A`, `%B`, `%L` and `%P` in `command` like git does
+fn substitute(command: &str, files: &[PathBuf], marker_size: usize, path: &str) -> String {
+    let mut result = String::with_capacity(command.len());
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('O') => result.push_str(&quote(&files[0].to_string_lossy())),
+            Some('A') => result.push_str(&quote(&files[1].to_string_lossy())),
+            Some('B') => result.push_str(&quote(&files[2].to_string_lossy())),
+            Some('L') => result.push_str(&marker_size.to_string()),
+            Some('P') => result.push_str(&quote(path)),
+            Some('%') => result.push('%'),
+            Some(other) => {
+                result.push('%');
+                result.push(other);
+            }
+            None => result.push('%'),
+        }
+    }
+
+    result
+}
+
+/// Quote `value` for `sh`
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn io_error(e: std::io::Error) -> git2::Error {
+    git2::Error::from_str(&e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::Result;
+
+    use super::*;
+    use crate::app::fixture::{git_cmd, setup_branches, TestFixture};
+    use crate::{app::MergeOutcome, report::SilentReporter};
+
+    /// A `pr` branch in `usage` and a commit on `main` each adding a line at the end of `name`
+    ///
+    /// `attributes` is committed in `.gitattributes` first.
+    fn setup(attributes: &str, name: &str) -> Result<PathBuf> {
+        let fixture = TestFixture::new()?.setup()?;
+
+        setup_branches(
+            &fixture,
+            &[(".gitattributes", attributes), (name, "b\n")],
+            &[(name, "b\nc\n")],
+            &[(name, "b\na\n")],
+        )
+    }
+
+    fn merged_file(usage_dir: &Path, merge: MergeOutcome, name: &str) -> Result<Vec<u8>> {
+        let MergeOutcome::Merged(merge) = merge else {
+            panic!("expected a merge, got {:?}", merge);
+        };
+        let repo = Repository::open(usage_dir)?;
+        let tree = repo.find_commit(merge)?.tree()?;
+        let blob = repo.find_blob(tree.get_path(Path::new(name))?.id())?;
+
+        Ok(blob.content().to_vec())
+    }
+
+    #[test]
+    fn test_substitute() {
+        let files = DRIVER_FILES.map(PathBuf::from);
+
+        assert_eq!(
+            substitute("merge %O %A %B -L %L %P 100%% %x", &files, 7, "it's.json"),
+            "merge 'UPDATE_PR_MERGE_BASE' 'UPDATE_PR_MERGE_OURS' 'UPDATE_PR_MERGE_THEIRS' \
+             -L 7 'it'\\''s.json' 100% %x"
+        );
+    }
+
+    #[test]
+    fn test_union() -> Result<()> {
+        let usage_dir = setup("CHANGELOG.md merge=union\n", "CHANGELOG.md")?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .update()?;
+
+        assert_eq!(
+            merged_file(&usage_dir, outcome.merge, "CHANGELOG.md")?,
+            b"b\nc\na\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_driver() -> Result<()> {
+        let usage_dir = setup("*.schema merge=schema\n", "api.schema")?;
+        git_cmd(&usage_dir)
+            .args([
+                "config",
+                "merge.schema.driver",
+                "sort -u %A %B -o %A && echo %L %P >> %A",
+            ])
+            .status()?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .update()?;
+
+        assert_eq!(
+            merged_file(&usage_dir, outcome.merge, "api.schema")?,
+            b"a\nb\nc\n7 api.schema\n"
+        );
+        for file in DRIVER_FILES {
+            assert!(!usage_dir.join(".git").join(file).exists());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary() -> Result<()> {
+        let usage_dir = setup("*.txt merge=binary\n", "notes.txt")?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .update()?;
+
+        assert_eq!(
+            outcome.merge,
+            MergeOutcome::Conflicts(vec!["notes.txt".to_string()])
+        );
+        assert_eq!(fs::read_to_string(usage_dir.join("notes.txt"))?, "b\nc\n");
+        let repo = Repository::open(&usage_dir)?;
+        let conflicts = repo.index()?.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].our.is_some() && conflicts[0].their.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failing_driver() -> Result<()> {
+        let usage_dir = setup("*.schema merge=schema\n", "api.schema")?;
+        git_cmd(&usage_dir)
+            .args(["config", "merge.schema.driver", "exit 1"])
+            .status()?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .update()?;
+
+        assert_eq!(
+            outcome.merge,
+            MergeOutcome::Conflicts(vec!["api.schema".to_string()])
+        );
+
+        Ok(())
+    }
+}
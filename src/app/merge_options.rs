@@ -0,0 +1,389 @@
+use std::{path::Path, str::FromStr};
+
+use git2::{FileFavor, Index, IndexEntry, MergeFileOptions, MergeOptions};
+
+use crate::{report::Event, utils::git::path_matches};
+
+use super::{repo::Repo, submodules::INDEX_ENTRY_STAGE_MASK, Updater};
+
+/// An option of the merge, as given to `git merge -X`, for every file or only the ones matching a pattern
+///
+/// Written as in `update-pr.mergeOption` or `--merge-option`, the option
+/// optionally preceded by `<pattern>:`, e.g. `patience` or `*.md:union`:
+///
+/// - `ours`, `theirs` or `union`: how conflicting hunks are resolved
+/// - `patience`, `minimal` or `diff-algorithm=<myers|minimal|patience>`: how files are compared
+/// - `ignore-space-change`, `ignore-all-space` or `ignore-space-at-eol`: which whitespace changes are ignored
+/// - `find-renames[=<percent>]` or `no-renames`: how renamed files are found, for every file only
+///
+/// Patterns match like in `.gitignore`, and the options of a file are
+/// applied after the ones of every file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeOption {
+    source: String,
+    pattern: Option<String>,
+    option: Tuning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tuning {
+    Favor(Favor),
+    DiffAlgorithm(DiffAlgorithm),
+    IgnoreSpaceChange,
+    IgnoreAllSpace,
+    IgnoreSpaceAtEol,
+    FindRenames(Option<u32>),
+    NoRenames,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Favor {
+    Ours,
+    Theirs,
+    Union,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiffAlgorithm {
+    Myers,
+    Minimal,
+    Patience,
+}
+
+impl FromStr for MergeOption {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let source = source.trim();
+        let (pattern, text) = match source.rsplit_once(':') {
+            Some((pattern, text)) => (Some(pattern.trim().to_string()), text.trim()),
+            None => (None, source),
+        };
+
+        let diff_algorithm = |name: &str| match name {
+            "myers" | "default" => Ok(DiffAlgorithm::Myers),
+            "minimal" => Ok(DiffAlgorithm::Minimal),
+            "patience" => Ok(DiffAlgorithm::Patience),
+            _ => Err(format!(
+                "unsupported diff algorithm in {:?}, expected myers, minimal or patience",
+                source
+            )),
+        };
+
+        let option = match text {
+            "ours" => Tuning::Favor(Favor::Ours),
+            "theirs" => Tuning::Favor(Favor::Theirs),
+            "union" => Tuning::Favor(Favor::Union),
+            "patience" | "minimal" => Tuning::DiffAlgorithm(diff_algorithm(text)?),
+            "ignore-space-change" => Tuning::IgnoreSpaceChange,
+            "ignore-all-space" => Tuning::IgnoreAllSpace,
+            "ignore-space-at-eol" => Tuning::IgnoreSpaceAtEol,
+            "find-renames" => Tuning::FindRenames(None),
+            "no-renames" => Tuning::NoRenames,
+            text => {
+                if let Some(name) = text.strip_prefix("diff-algorithm=") {
+                    Tuning::DiffAlgorithm(diff_algorithm(name)?)
+                } else if let Some(percent) = text.strip_prefix("find-renames=") {
+                    let percent = percent
+                        .trim_end_matches('%')
+                        .parse()
+                        .ok()
+                        .filter(|percent| *percent <= 100)
+                        .ok_or_else(|| format!("invalid similarity in {:?}", source))?;
+                    Tuning::FindRenames(Some(percent))
+                } else {
+                    return Err(format!("unknown merge option {:?}", source));
+                }
+            }
+        };
+
+        if pattern.is_some() && matches!(option, Tuning::FindRenames(_) | Tuning::NoRenames) {
+            return Err(format!(
+                "{:?} cannot be set for some files only, renames are found across the whole tree",
+                source
+            ));
+        }
+
+        Ok(Self {
+            source: source.to_string(),
+            pattern,
+            option,
+        })
+    }
+}
+
+impl MergeOption {
+    /// Whether the option applies to the file at `path`
+    fn matches(&self, path: &str) -> bool {
+        self.pattern
+            .as_deref()
+            .is_none_or(|pattern| path_matches(pattern, path))
+    }
+
+    fn apply_to_tree(&self, opts: &mut MergeOptions) {
+        match self.option {
+            Tuning::Favor(favor) => {
+                opts.file_favor(favor.into());
+            }
+            Tuning::DiffAlgorithm(algorithm) => {
+                opts.patience(algorithm == DiffAlgorithm::Patience)
+                    .minimal(algorithm == DiffAlgorithm::Minimal);
+            }
+            Tuning::IgnoreSpaceChange => {
+                opts.ignore_whitespace_change(true);
+            }
+            Tuning::IgnoreAllSpace => {
+                opts.ignore_whitespace(true);
+            }
+            Tuning::IgnoreSpaceAtEol => {
+                opts.ignore_whitespace_eol(true);
+            }
+            Tuning::FindRenames(threshold) => {
+                opts.find_renames(true);
+                if let Some(threshold) = threshold {
+                    opts.rename_threshold(threshold);
+                }
+            }
+            Tuning::NoRenames => {
+                opts.find_renames(false);
+            }
+        }
+    }
+
+    fn apply_to_file(&self, opts: &mut MergeFileOptions) {
+        match self.option {
+            Tuning::Favor(favor) => {
+                opts.favor(favor.into());
+            }
+            Tuning::DiffAlgorithm(algorithm) => {
+                opts.patience(algorithm == DiffAlgorithm::Patience)
+                    .minimal(algorithm == DiffAlgorithm::Minimal);
+            }
+            Tuning::IgnoreSpaceChange => {
+                opts.ignore_whitespace_change(true);
+            }
+            Tuning::IgnoreAllSpace => {
+                opts.ignore_whitespace(true);
+            }
+            Tuning::IgnoreSpaceAtEol => {
+                opts.ignore_whitespace_eol(true);
+            }
+            Tuning::FindRenames(_) | Tuning::NoRenames => {}
+        }
+    }
+}
+
+impl From<Favor> for FileFavor {
+    fn from(favor: Favor) -> Self {
+        match favor {
+            Favor::Ours => FileFavor::Ours,
+            Favor::Theirs => FileFavor::Theirs,
+            Favor::Union => FileFavor::Union,
+        }
+    }
+}
+
+/// Tunes how libgit2 merges trees and files, like the strategy options of `git merge`
+pub trait MergeTuning: Repo {
+    /// The options of the merge, none by default
+    fn merge_options(&self) -> &[MergeOption] {
+        &[]
+    }
+
+    /// The options to merge the trees with, following `merge.renames` and `merge.renameLimit`
+    fn tree_merge_options(&self) -> MergeOptions {
+        let mut opts = MergeOptions::new();
+
+        if let Ok(config) = self.repo().config() {
+            if let Ok(false) = config.get_bool("merge.renames") {
+                opts.find_renames(false);
+            }
+            if let Ok(limit) = config.get_i32("merge.renameLimit") {
+                opts.target_limit(limit.max(0) as u32);
+            }
+        }
+        for option in self.merge_options().iter().filter(|o| o.pattern.is_none()) {
+            option.apply_to_tree(&mut opts);
+        }
+
+        opts
+    }
+
+    /// The options to merge the file at `path` with, the ones of every file then the ones of its path
+    fn file_merge_options(&self, path: &str) -> MergeFileOptions {
+        let mut opts = MergeFileOptions::new();
+
+        let (global, patterned): (Vec<&MergeOption>, Vec<&MergeOption>) = self
+            .merge_options()
+            .iter()
+            .partition(|o| o.pattern.is_none());
+        for option in global {
+            option.apply_to_file(&mut opts);
+        }
+        for option in patterned {
+            if option.matches(path) {
+                option.apply_to_file(&mut opts);
+            }
+        }
+
+        opts
+    }
+
+    /// Merge the conflicting files of `idx` again with the options of their path
+    ///
+    /// The files merged without conflicts are staged in `idx`, the other
+    /// conflicts are left untouched.
+    fn merge_with_path_options(&self, idx: &mut Index) -> Result<(), git2::Error> {
+        if self.merge_options().iter().all(|o| o.pattern.is_none()) {
+            return Ok(());
+        }
+        let repo = self.repo();
+
+        let mut merged: Vec<(IndexEntry, Vec<u8>, Vec<&str>)> = Vec::new();
+        for conflict in idx.conflicts()? {
+            let conflict = conflict?;
+            let (Some(ancestor), Some(ours), Some(theirs)) =
+                (conflict.ancestor, conflict.our, conflict.their)
+            else {
+                continue;
+            };
+            let path = String::from_utf8_lossy(&ours.path).to_string();
+
+            let options: Vec<&MergeOption> = self
+                .merge_options()
+                .iter()
+                .filter(|o| o.pattern.is_some() && o.matches(&path))
+                .collect();
+            if options.is_empty() {
+                continue;
+            }
+
+            let mut opts = self.file_merge_options(&path);
+            let result = repo.merge_file_from_index(&ancestor, &ours, &theirs, Some(&mut opts))?;
+            if result.is_automergeable() {
+                let sources = options.iter().map(|o| o.source.as_str()).collect();
+                merged.push((ours, result.content().to_vec(), sources));
+            } else {
+                tracing::debug!(%path, "still conflicting with the options of the file");
+            }
+        }
+
+        for (mut entry, content, sources) in merged {
+            let path = String::from_utf8_lossy(&entry.path).to_string();
+
+            idx.conflict_remove(Path::new(&path))?;
+            entry.flags &= !INDEX_ENTRY_STAGE_MASK;
+            entry.id = repo.blob(&content)?;
+            entry.file_size = content.len() as u32;
+            idx.add(&entry)?;
+            tracing::info!(%path, options = ?sources, "merged with the options of the file");
+
+            self.reporter().report(&Event::MergedWithOptions {
+                path,
+                options: sources.join(", "),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl MergeTuning for Updater {
+    fn merge_options(&self) -> &[MergeOption] {
+        &self.merge_options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use color_eyre::eyre::Result;
+    use git2::Repository;
+
+    use super::*;
+    use crate::app::fixture::{git_cmd, setup_branches, TestFixture};
+    use crate::{app::MergeOutcome, report::SilentReporter};
+
+    /// A `pr` branch in `usage` and a commit on `main` changing `file.txt` from `base` to `pr` and `main`
+    fn setup(base: &str, pr: &str, main: &str) -> Result<PathBuf> {
+        let fixture = TestFixture::new()?.setup()?;
+
+        setup_branches(
+            &fixture,
+            &[("file.txt", base)],
+            &[("file.txt", pr)],
+            &[("file.txt", main)],
+        )
+    }
+
+    fn merged_file(usage_dir: &Path, merge: MergeOutcome) -> Result<Vec<u8>> {
+        let MergeOutcome::Merged(merge) = merge else {
+            panic!("expected a merge, got {:?}", merge);
+        };
+        let repo = Repository::open(usage_dir)?;
+        let tree = repo.find_commit(merge)?.tree()?;
+        let blob = repo.find_blob(tree.get_path(Path::new("file.txt"))?.id())?;
+
+        Ok(blob.content().to_vec())
+    }
+
+    #[test]
+    fn test_parse() {
+        let option: MergeOption = "*.md:union".parse().unwrap();
+        assert_eq!(option.pattern.as_deref(), Some("*.md"));
+        assert_eq!(option.option, Tuning::Favor(Favor::Union));
+
+        let option: MergeOption = "diff-algorithm=patience".parse().unwrap();
+        assert_eq!(option.pattern, None);
+        assert_eq!(
+            option.option,
+            Tuning::DiffAlgorithm(DiffAlgorithm::Patience)
+        );
+        assert_eq!(
+            "find-renames=70%".parse::<MergeOption>().unwrap().option,
+            Tuning::FindRenames(Some(70))
+        );
+
+        assert!("diff-algorithm=histogram".parse::<MergeOption>().is_err());
+        assert!("docs/*:no-renames".parse::<MergeOption>().is_err());
+        assert!("recursive".parse::<MergeOption>().is_err());
+    }
+
+    #[test]
+    fn test_ignore_space_at_eol() -> Result<()> {
+        let usage_dir = setup("a\nb\nc\n", "a \nb \nc \n", "a\nB\nc\n")?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .merge_option("ignore-space-at-eol".parse().unwrap())
+            .update()?;
+
+        let content = merged_file(&usage_dir, outcome.merge)?;
+        assert!(String::from_utf8(content)?.contains("B\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_option() -> Result<()> {
+        let usage_dir = setup("a\n", "a\npr\n", "a\nmain\n")?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .merge_option("*.md:ours".parse().unwrap())
+            .update()?;
+        assert!(outcome.has_conflicts());
+
+        git_cmd(&usage_dir).args(["reset", "--hard"]).status()?;
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .merge_option("*.md:ours".parse().unwrap())
+            .merge_option("*.txt:union".parse().unwrap())
+            .update()?;
+
+        assert_eq!(merged_file(&usage_dir, outcome.merge)?, b"a\npr\nmain\n");
+
+        Ok(())
+    }
+}
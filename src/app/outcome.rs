@@ -0,0 +1,89 @@
+use std::fmt::Display;
+
+use git2::Oid;
+
+/// What happened when a commit was merged into the current branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// The branch already contains the commit
+    UpToDate,
+    /// The branch was moved forward to the commit
+    FastForward(Oid),
+    /// A merge commit was created
+    Merged(Oid),
+    /// The branch was rebased onto the commit, moving it to this new head
+    Rebased(Oid),
+    /// The merge stopped on conflicts in these paths, which were left in the working tree
+    Conflicts(Vec<String>),
+    /// The branch has diverged and the strategy does not allow a merge commit
+    Diverged,
+    /// Nothing was merged, for this reason
+    Skipped(String),
+}
+
+impl MergeOutcome {
+    /// Whether the merge moved the branch
+    pub fn is_changed(&self) -> bool {
+        matches!(
+            self,
+            Self::FastForward(_) | Self::Merged(_) | Self::Rebased(_)
+        )
+    }
+}
+
+impl Display for MergeOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UpToDate => write!(f, "up to date"),
+            Self::FastForward(oid) => write!(f, "fast-forwarded to {}", oid),
+            Self::Merged(oid) => write!(f, "merged in {}", oid),
+            Self::Rebased(oid) => write!(f, "rebased to {}", oid),
+            Self::Conflicts(paths) => write!(f, "conflicts in {}", paths.join(", ")),
+            Self::Diverged => write!(f, "diverged"),
+            Self::Skipped(reason) => write!(f, "skipped, {}", reason),
+        }
+    }
+}
+
+/// Everything [`Updater::update`](super::Updater::update) did to the branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateOutcome {
+    /// The branch that was updated, e.g. `refs/heads/my-pr`
+    pub branch: String,
+    /// The base branch that was merged into it, e.g. `main`
+    pub base: String,
+    /// HEAD before the update
+    pub head_before: Oid,
+    /// HEAD after the update
+    pub head_after: Oid,
+    /// The result of pulling the branch from the remote
+    pub pull: MergeOutcome,
+    /// The result of merging the base branch
+    pub merge: MergeOutcome,
+    /// Whether the branch was pushed back to the remote
+    pub pushed: bool,
+}
+
+impl UpdateOutcome {
+    /// Whether the base branch was brought into the branch and pushed
+    pub fn is_updated(&self) -> bool {
+        self.pushed
+    }
+
+    /// Whether merging the base branch stopped on conflicts
+    pub fn has_conflicts(&self) -> bool {
+        matches!(self.merge, MergeOutcome::Conflicts(_))
+    }
+}
+
+impl Display for UpdateOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} {}", self.branch, self.base, self.merge)?;
+
+        if self.pushed {
+            write!(f, ", pushed")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use git2::{FileMode, Oid, Tree};
+
+use super::{repo::Repo, Updater};
+
+/// Let libgit2 open partial clones made by older versions of git
+///
+/// They are marked with the `partialclone` extension, unknown to libgit2.
+/// Partial clones made by recent versions of git are opened without it.
+///
+/// # Safety
+///
+/// This changes the extensions libgit2 accepts for the whole process, so it
+/// must be called before any repository is opened, by this crate or
+/// anything else using libgit2, and not while other threads use libgit2.
+pub unsafe fn allow_partial_clone() -> Result<(), git2::Error> {
+    git2::opts::set_extensions(&["partialclone"])
+}
+
+/// Support for partial clones, e.g. `git clone --filter=blob:none`
+///
+/// libgit2 cannot download the objects a partial clone is missing on demand, so
+/// the blobs needed by a merge or checkout are fetched beforehand with the git
+/// command line, which knows how to ask the promisor remote for them.
+pub trait Partial: Repo {
+    /// The remote promising the objects missing from a partial clone, if it is one
+    fn promisor_remote(&self) -> Option<String> {
+        let config = self.repo().config().ok()?;
+
+        if let Ok(name) = config.get_string("extensions.partialclone") {
+            return Some(name);
+        }
+
+        let mut entries = config.entries(Some(r"remote\..*\.promisor")).ok()?;
+        while let Some(Ok(entry)) = entries.next() {
+            if config.get_bool(entry.name()?).unwrap_or(false) {
+                return entry
+                    .name()?
+                    .strip_prefix("remote.")?
+                    .strip_suffix(".promisor")
+                    .map(str::to_string);
+            }
+        }
+
+        None
+    }
+
+    /// Download the blobs a partial clone is missing to go from `from` to `to`
+    ///
+    /// Does nothing outside of partial clones.
+    fn fetch_missing_blobs(&self, from: &Tree, to: &Tree) -> Result<(), git2::Error> {
+        let Some(remote) = self.promisor_remote() else {
+            return Ok(());
+        };
+
+        let repo = self.repo();
+        let odb = repo.odb()?;
+        let diff = repo.diff_tree_to_tree(Some(from), Some(to), None)?;
+
+        let mut missing: Vec<Oid> = Vec::new();
+        for delta in diff.deltas() {
+            for file in [delta.old_file(), delta.new_file()] {
+                // Submodules are other repositories, their commits are never here
+                if file.mode() == FileMode::Commit || file.id().is_zero() {
+                    continue;
+                }
+                if !missing.contains(&file.id()) && !odb.exists(file.id()) {
+                    missing.push(file.id());
+                }
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let _span =
+            tracing::info_span!("fetch_missing_blobs", %remote, count = missing.len()).entered();
+        tracing::info!("fetching blobs missing from the partial clone");
+
+        // The same command git runs to lazily fetch objects from a promisor remote
+        let mut child = Command::new("git")
+            .arg("-c")
+            .arg("fetch.negotiationAlgorithm=noop")
+            .arg("fetch")
+            .arg(&remote)
+            .args([
+                "--no-tags",
+                "--no-write-fetch-head",
+                "--recurse-submodules=no",
+                "--filter=blob:none",
+                "--stdin",
+            ])
+            .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+            .env("GIT_DIR", repo.path())
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| git2::Error::from_str(&format!("could not run git: {}", e)))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            let oids: String = missing.iter().map(|oid| format!("{}\n", oid)).collect();
+            stdin
+                .write_all(oids.as_bytes())
+                .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+        }
+
+        let status = child
+            .wait()
+            .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+        if !status.success() {
+            return Err(git2::Error::from_str(&format!(
+                "could not fetch {} missing blobs from {}: git exited with {}",
+                missing.len(),
+                remote,
+                status
+            )));
+        }
+
+        // Make the pack written by git visible to libgit2
+        odb.refresh()
+    }
+}
+
+impl Partial for Updater {}
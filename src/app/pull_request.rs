@@ -0,0 +1,832 @@
+use git2::{DiffOptions, Oid, Sort};
+
+use crate::{
+    forge::{BranchUpdate, Forge, ForgeError, RepoId},
+    report::Event,
+};
+
+use super::{outcome::UpdateOutcome, repo::Repo, MergeOutcome, Updater};
+
+/// Marks the comment left on conflicting pull requests, so it is found again
+pub const CONFLICT_MARKER: &str = "<!-- update-pr:conflicts -->";
+
+/// How many commits of the base branch are listed for each conflicting file
+const COMMITS_PER_FILE: usize = 5;
+
+/// What happened when the forge was asked to merge the base branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForgeMerge {
+    /// The branch has no open pull request
+    NoPullRequest,
+    /// The branch already contains the base branch
+    UpToDate,
+    /// The forge cannot merge the base branch without conflicts
+    Conflict,
+    /// The forge merged the base branch, moving the branch to this commit
+    Merged(Oid),
+    /// The forge rebased the branch onto the base branch, moving it to this commit
+    Rebased(Oid),
+    /// The pull request is not at the commit of the local branch, so it was left alone
+    Diverged,
+    /// The forge cannot update pull requests
+    Unsupported,
+}
+
+/// Keeps the pull request of the updated branch informed of its conflicts
+pub trait PullRequests: Repo {
+    /// The forge hosting the pull requests, none by default
+    fn forge(&self) -> Option<&dyn Forge> {
+        None
+    }
+
+    /// The label put on pull requests while they have conflicts, none by default
+    fn conflict_label(&self) -> Option<&str> {
+        None
+    }
+
+    /// The remote the pull request branches are pushed to
+    fn remote_name(&self) -> &str {
+        "origin"
+    }
+
+    /// Comment on the pull request of the branch when the update left conflicts
+    ///
+    /// The comment is updated on the next conflicts instead of being posted
+    /// again, and deleted once the branch merges cleanly. Failures are reported
+    /// without failing the update.
+    fn sync_pull_request(&self, outcome: &UpdateOutcome) {
+        let Some(forge) = self.forge() else {
+            return;
+        };
+
+        let _span = tracing::info_span!("pull_request", branch = %outcome.branch).entered();
+
+        if let Err(e) = self.try_sync_pull_request(forge, outcome) {
+            tracing::warn!("could not update the pull request: {}", e);
+            self.reporter().report(&Event::ForgeFailed {
+                message: format!("could not update the pull request: {}", e),
+            });
+        }
+    }
+
+    fn try_sync_pull_request(
+        &self,
+        forge: &dyn Forge,
+        outcome: &UpdateOutcome,
+    ) -> Result<(), ForgeError> {
+        let repo = self.forge_repo()?;
+        let branch = outcome.branch.trim_start_matches("refs/heads/");
+
+        let Some(pr) = forge.find_pull_request(&repo, branch)? else {
+            tracing::debug!("no open pull request");
+            return Ok(());
+        };
+
+        let existing = forge
+            .comments(&repo, pr.number)?
+            .into_iter()
+            .find(|comment| comment.body.contains(CONFLICT_MARKER));
+
+        match &outcome.merge {
+            MergeOutcome::Conflicts(paths) => {
+                let body = self
+                    .conflict_comment(outcome, paths)
+                    .map_err(|e| ForgeError::new(e.message()))?;
+
+                match existing {
+                    Some(comment) if comment.body == body => {}
+                    Some(comment) => forge.update_comment(&repo, pr.number, comment.id, &body)?,
+                    None => {
+                        forge.create_comment(&repo, pr.number, &body)?;
+                    }
+                }
+                if let Some(label) = self.conflict_label() {
+                    forge.add_label(&repo, pr.number, label)?;
+                }
+                tracing::info!(number = pr.number, "commented the conflicts");
+
+                self.reporter().report(&Event::PullRequestCommented {
+                    number: pr.number,
+                    url: pr.url,
+                });
+            }
+            _ => {
+                if let Some(label) = self.conflict_label() {
+                    forge.remove_label(&repo, pr.number, label)?;
+                }
+                if let Some(comment) = existing {
+                    forge.delete_comment(&repo, pr.number, comment.id)?;
+                    tracing::info!(number = pr.number, "removed the conflict comment");
+
+                    self.reporter()
+                        .report(&Event::PullRequestCommentRemoved { number: pr.number });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Have the forge bring the base branch into the pull request of `branch`
+    ///
+    /// This is only done if the pull request is still at `head`, the commit
+    /// of the local branch, then this waits for its new head.
+    fn merge_on_forge(
+        &self,
+        forge: &dyn Forge,
+        branch: &str,
+        head: Oid,
+    ) -> Result<ForgeMerge, ForgeError> {
+        let repo = self.forge_repo()?;
+        let branch = branch.trim_start_matches("refs/heads/");
+
+        let Some(pr) = forge.find_pull_request(&repo, branch)? else {
+            return Ok(ForgeMerge::NoPullRequest);
+        };
+        let _span = tracing::info_span!("update_branch", number = pr.number).entered();
+
+        if pr.head_sha != head.to_string() {
+            tracing::info!(head = %pr.head_sha, "the pull request differs from the local branch");
+            return Ok(ForgeMerge::Diverged);
+        }
+
+        let (head, rebased) = match forge.update_branch(&repo, &pr)? {
+            BranchUpdate::UpToDate => return Ok(ForgeMerge::UpToDate),
+            BranchUpdate::Conflict => return Ok(ForgeMerge::Conflict),
+            BranchUpdate::Unsupported => return Ok(ForgeMerge::Unsupported),
+            BranchUpdate::Merged(head) => (head, false),
+            BranchUpdate::Rebased(head) => (head, true),
+        };
+        let oid = Oid::from_str(&head)
+            .map_err(|_| ForgeError::new(format!("invalid head commit {:?}", head)))?;
+        tracing::info!(%oid, rebased, "branch updated by {}", forge.name());
+
+        if rebased {
+            self.reporter().report(&Event::ForgeRebased {
+                number: pr.number,
+                oid,
+            });
+            Ok(ForgeMerge::Rebased(oid))
+        } else {
+            self.reporter().report(&Event::ForgeMerged {
+                number: pr.number,
+                oid,
+            });
+            Ok(ForgeMerge::Merged(oid))
+        }
+    }
+
+    /// The repository on the forge the remote points to
+    fn forge_repo(&self) -> Result<RepoId, ForgeError> {
+        let remote = self
+            .repo()
+            .find_remote(self.remote_name())
+            .map_err(|e| ForgeError::new(e.message()))?;
+        let url = remote.url().unwrap_or_default();
+
+        RepoId::from_url(url).ok_or_else(|| {
+            ForgeError::new(format!(
+                "no repository found in the url of {}",
+                self.remote_name()
+            ))
+        })
+    }
+
+    /// The comment listing the conflicting `paths`, the base commits changing them and how to resolve them
+    fn conflict_comment(
+        &self,
+        outcome: &UpdateOutcome,
+        paths: &[String],
+    ) -> Result<String, git2::Error> {
+        let repo = self.repo();
+        let remote = self.remote_name();
+        let branch = outcome.branch.trim_start_matches("refs/heads/");
+        let base_ref = format!("refs/remotes/{}/{}", remote, outcome.base);
+        let base_tip = repo.refname_to_id(&base_ref)?;
+
+        let mut body = format!(
+            "{}\n### Merge conflicts with `{}`\n\n\
+             Merging `{}` ({}) into `{}` stopped on conflicts in these files:\n\n",
+            CONFLICT_MARKER,
+            outcome.base,
+            outcome.base,
+            short(base_tip),
+            branch
+        );
+
+        for path in paths {
+            let commits = self.commits_changing(base_tip, outcome.head_after, path)?;
+            if commits.is_empty() {
+                body.push_str(&format!("- `{}`\n", path));
+                continue;
+            }
+
+            body.push_str(&format!(
+                "- `{}`, changed on `{}` by:\n",
+                path, outcome.base
+            ));
+            for (oid, summary) in commits.iter().take(COMMITS_PER_FILE) {
+                body.push_str(&format!("  - {} {}\n", short(*oid), summary));
+            }
+            if commits.len() > COMMITS_PER_FILE {
+                body.push_str(&format!(
+                    "  - and {} more\n",
+                    commits.len() - COMMITS_PER_FILE
+                ));
+            }
+        }
+
+        body.push_str(&format!(
+            "\nTo resolve them:\n\n\
+             ```sh\n\
+             git fetch {remote}\n\
+             git checkout {branch}\n\
+             git merge {remote}/{base}\n\
+             # fix the conflicts, then\n\
+             git add <files>\n\
+             git commit\n\
+             git push\n\
+             ```\n\n\
+             _This comment is kept up to date by update-pr and removed once the conflicts are gone._\n",
+            remote = remote,
+            branch = branch,
+            base = outcome.base,
+        ));
+
+        Ok(body)
+    }
+
+    /// The commits reachable from `tip` but not from `head` changing `path`, newest first
+    fn commits_changing(
+        &self,
+        tip: Oid,
+        head: Oid,
+        path: &str,
+    ) -> Result<Vec<(Oid, String)>, git2::Error> {
+        let repo = self.repo();
+
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        walk.push(tip)?;
+        walk.hide(head)?;
+
+        let mut commits = Vec::new();
+        for oid in walk {
+            let commit = repo.find_commit(oid?)?;
+            let parent_tree = match commit.parents().next() {
+                Some(parent) => Some(parent.tree()?),
+                None => None,
+            };
+
+            let mut opts = DiffOptions::new();
+            opts.pathspec(path).disable_pathspec_match(true);
+            let diff = repo.diff_tree_to_tree(
+                parent_tree.as_ref(),
+                Some(&commit.tree()?),
+                Some(&mut opts),
+            )?;
+
+            if diff.deltas().len() > 0 {
+                commits.push((
+                    commit.id(),
+                    commit.summary().unwrap_or_default().to_string(),
+                ));
+            }
+        }
+
+        Ok(commits)
+    }
+}
+
+fn short(oid: Oid) -> String {
+    oid.to_string()[..7].to_string()
+}
+
+impl PullRequests for Updater {
+    fn forge(&self) -> Option<&dyn Forge> {
+        self.forge.as_deref()
+    }
+
+    fn conflict_label(&self) -> Option<&str> {
+        self.conflict_label.as_deref()
+    }
+
+    fn remote_name(&self) -> &str {
+        &self.remote
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use color_eyre::eyre::Result;
+    use git2::Repository;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        app::{
+            fixture::{git_cmd, setup_pr, TestFixture},
+            Strategy,
+        },
+        forge::{fake::FakeApi, GitHub, GitLab, Gitea},
+        report::SilentReporter,
+    };
+
+    /// Serves pull request 12 and keeps its comments
+    fn fake_github() -> Result<FakeApi> {
+        let comments = Arc::new(Mutex::new(Vec::<Value>::new()));
+
+        let api = FakeApi::start(move |request| {
+            let mut comments = comments.lock().unwrap();
+            let path = request.path.split('?').next().unwrap();
+
+            match request.method.as_str() {
+                "GET" if path.ends_with("/remote/pulls") => (
+                    200,
+                    json!([{
+                        "number": 12,
+                        "html_url": "https://github.com/org/remote/pull/12",
+                        "state": "open",
+                        "head": { "ref": "pr", "sha": "abc" },
+                        "base": { "ref": "main", "sha": "def" },
+                    }]),
+                ),
+                "GET" if path.ends_with("/issues/12/comments") => (200, json!(*comments)),
+                "POST" if path.ends_with("/issues/12/comments") => {
+                    let comment = json!({ "id": 7, "body": request.body["body"] });
+                    comments.push(comment.clone());
+                    (201, comment)
+                }
+                "DELETE" if path.ends_with("/issues/comments/7") => {
+                    comments.clear();
+                    (204, Value::Null)
+                }
+                "POST" if path.ends_with("/issues/12/labels") => (200, json!([])),
+                "DELETE" if path.ends_with("/issues/12/labels/needs-rebase") => (200, json!([])),
+                _ => (404, json!({ "message": "Not Found" })),
+            }
+        })?;
+
+        Ok(api)
+    }
+
+    #[test]
+    fn test_conflict_comment() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "file.txt")?;
+        let api = fake_github()?;
+
+        let updater = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .forge(GitHub::new("token").api_url(&api.url))
+            .conflict_label("needs-rebase");
+
+        let outcome = updater.update()?;
+        assert!(outcome.has_conflicts());
+
+        let requests = api.requests();
+        let posted: Vec<_> = requests
+            .iter()
+            .filter(|r| r.method == "POST" && r.path.ends_with("/issues/12/comments"))
+            .collect();
+        assert_eq!(posted.len(), 1);
+
+        let body = posted[0].body["body"].as_str().unwrap();
+        assert!(body.starts_with(CONFLICT_MARKER));
+        assert!(body.contains("- `file.txt`, changed on `main` by:"));
+        assert!(body.contains("update file.txt"));
+        assert!(body.contains("git merge origin/main"));
+        assert!(requests
+            .iter()
+            .any(|r| r.path.ends_with("/issues/12/labels")
+                && r.body["labels"] == json!(["needs-rebase"])));
+
+        // The same conflicts again do not post another comment
+        updater.sync_pull_request(&outcome);
+        let posted = api
+            .requests()
+            .iter()
+            .filter(|r| r.method == "POST" && r.path.ends_with("/issues/12/comments"))
+            .count();
+        assert_eq!(posted, 1);
+
+        // Resolve the conflicts by hand
+        git_cmd(&usage_dir).args(["reset", "--hard"]).status()?;
+        git_cmd(&usage_dir)
+            .args(["merge", "-X", "theirs", "origin/main"])
+            .status()?;
+
+        let outcome = updater.update()?;
+        assert!(!outcome.has_conflicts());
+
+        let requests = api.requests();
+        assert!(requests
+            .iter()
+            .any(|r| r.method == "DELETE" && r.path.ends_with("/issues/comments/7")));
+        assert!(requests
+            .iter()
+            .any(|r| r.method == "DELETE" && r.path.ends_with("/labels/needs-rebase")));
+
+        Ok(())
+    }
+
+    /// Serves pull request 12 of the `pr` branch, merging `main` into it in a clone of the remote when asked
+    fn fake_update_branch(fixture: &TestFixture) -> Result<FakeApi> {
+        let remote_dir = fixture.remote_dir.clone();
+        let server_dir = fixture.base_dir.join("server");
+        git_cmd(&fixture.base_dir)
+            .arg("clone")
+            .arg(&remote_dir)
+            .arg(&server_dir)
+            .status()?;
+
+        let api = FakeApi::start(move |request| {
+            let path = request.path.split('?').next().unwrap();
+            let head = Repository::open(&remote_dir)
+                .and_then(|repo| repo.refname_to_id("refs/heads/pr"))
+                .unwrap()
+                .to_string();
+            let pr = json!({
+                "number": 12,
+                "html_url": "https://github.com/org/remote/pull/12",
+                "state": "open",
+                "head": { "ref": "pr", "sha": head },
+                "base": { "ref": "main", "sha": "def" },
+            });
+
+            match request.method.as_str() {
+                "GET" if path.ends_with("/remote/pulls") => (200, json!([pr])),
+                "GET" if path.ends_with("/remote/pulls/12") => (200, pr),
+                "PUT" if path.ends_with("/remote/pulls/12/update-branch") => {
+                    if request.body["expected_head_sha"] != json!(head) {
+                        return (422, json!({ "message": "expected head sha didn't match" }));
+                    }
+
+                    git_cmd(&server_dir)
+                        .args(["fetch", "origin"])
+                        .status()
+                        .unwrap();
+                    git_cmd(&server_dir)
+                        .args(["checkout", "-B", "pr", "origin/pr"])
+                        .status()
+                        .unwrap();
+                    let merged = git_cmd(&server_dir)
+                        .args(["merge", "--no-edit", "origin/main"])
+                        .status()
+                        .unwrap();
+                    if !merged.success() {
+                        git_cmd(&server_dir)
+                            .args(["merge", "--abort"])
+                            .status()
+                            .unwrap();
+                        return (
+                            422,
+                            json!({ "message": "merge conflict between base and head" }),
+                        );
+                    }
+                    git_cmd(&server_dir)
+                        .args(["push", "origin", "pr"])
+                        .status()
+                        .unwrap();
+
+                    (202, json!({ "message": "Updating pull request branch." }))
+                }
+                "GET" if path.ends_with("/issues/12/comments") => (200, json!([])),
+                "POST" if path.ends_with("/issues/12/comments") => {
+                    (201, json!({ "id": 7, "body": request.body["body"] }))
+                }
+                _ => (404, json!({ "message": "Not Found" })),
+            }
+        })?;
+
+        Ok(api)
+    }
+
+    #[test]
+    fn test_server_side_update() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "pr.txt")?;
+        let api = fake_update_branch(&fixture)?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .strategy(Strategy::ServerSide)
+            .forge(
+                GitHub::new("token")
+                    .api_url(&api.url)
+                    .poll(Duration::from_millis(10), Duration::from_secs(5)),
+            )
+            .update()?;
+
+        let remote_head = Repository::open(&fixture.remote_dir)?.refname_to_id("refs/heads/pr")?;
+        assert_eq!(outcome.merge, MergeOutcome::Merged(remote_head));
+        assert_eq!(outcome.pull, MergeOutcome::FastForward(remote_head));
+        assert_eq!(outcome.head_after, remote_head);
+        assert!(outcome.is_updated());
+
+        // The merge commit comes from the server
+        let repo = Repository::open(&usage_dir)?;
+        assert_eq!(repo.find_commit(remote_head)?.parent_count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_server_side_conflict_falls_back() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "file.txt")?;
+        let api = fake_update_branch(&fixture)?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .strategy(Strategy::ServerSide)
+            .forge(GitHub::new("token").api_url(&api.url))
+            .update()?;
+
+        assert_eq!(
+            outcome.merge,
+            MergeOutcome::Conflicts(vec!["file.txt".to_string()])
+        );
+        assert!(api
+            .requests()
+            .iter()
+            .any(|r| r.method == "PUT" && r.path.ends_with("/update-branch")));
+
+        Ok(())
+    }
+
+    /// Serves merge request 3 of the `pr` branch, rebasing it onto `main` in a clone of the remote when asked
+    fn fake_gitlab(fixture: &TestFixture) -> Result<FakeApi> {
+        let remote_dir = fixture.remote_dir.clone();
+        let server_dir = fixture.base_dir.join("server");
+        git_cmd(&fixture.base_dir)
+            .arg("clone")
+            .arg(&remote_dir)
+            .arg(&server_dir)
+            .status()?;
+        let notes = Arc::new(Mutex::new(Vec::<Value>::new()));
+
+        let api = FakeApi::start(move |request| {
+            let mut notes = notes.lock().unwrap();
+            let path = request.path.split('?').next().unwrap();
+            let repo = Repository::open(&remote_dir).unwrap();
+            let head = repo.refname_to_id("refs/heads/pr").unwrap();
+            let main = repo.refname_to_id("refs/heads/main").unwrap();
+            let diverged = !repo.graph_descendant_of(head, main).unwrap();
+            let mr = json!({
+                "iid": 3,
+                "project_id": 1,
+                "source_project_id": 1,
+                "web_url": "https://gitlab.com/org/remote/-/merge_requests/3",
+                "target_branch": "main",
+                "sha": head.to_string(),
+                "state": "opened",
+                "draft": false,
+                "has_conflicts": false,
+                "diverged_commits_count": if diverged { 1 } else { 0 },
+                "rebase_in_progress": false,
+                "merge_error": null,
+            });
+
+            match request.method.as_str() {
+                "GET" if path.ends_with("/merge_requests") => (200, json!([mr])),
+                "GET" if path.ends_with("/merge_requests/3") => (200, mr),
+                "PUT" if path.ends_with("/merge_requests/3/rebase") => {
+                    git_cmd(&server_dir)
+                        .args(["fetch", "origin"])
+                        .status()
+                        .unwrap();
+                    git_cmd(&server_dir)
+                        .args(["checkout", "-B", "pr", "origin/pr"])
+                        .status()
+                        .unwrap();
+                    git_cmd(&server_dir)
+                        .args(["rebase", "origin/main"])
+                        .status()
+                        .unwrap();
+                    git_cmd(&server_dir)
+                        .args(["push", "--force", "origin", "pr"])
+                        .status()
+                        .unwrap();
+
+                    (202, json!({ "rebase_in_progress": true }))
+                }
+                "GET" if path.ends_with("/merge_requests/3/notes") => (200, json!(*notes)),
+                "POST" if path.ends_with("/merge_requests/3/notes") => {
+                    let note = json!({ "id": 9, "body": request.body["body"] });
+                    notes.push(note.clone());
+                    (201, note)
+                }
+                "PUT" if path.ends_with("/merge_requests/3") => (200, mr),
+                _ => (404, json!({ "message": "404 Not Found" })),
+            }
+        })?;
+
+        Ok(api)
+    }
+
+    #[test]
+    fn test_gitlab_conflict_note() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "file.txt")?;
+        let api = fake_gitlab(&fixture)?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .forge(GitLab::new("token").api_url(&api.url))
+            .conflict_label("needs-rebase")
+            .update()?;
+        assert!(outcome.has_conflicts());
+
+        let requests = api.requests();
+        let note = requests
+            .iter()
+            .find(|r| r.method == "POST" && r.path.ends_with("/merge_requests/3/notes"))
+            .unwrap();
+        assert!(note.body["body"]
+            .as_str()
+            .unwrap()
+            .starts_with(CONFLICT_MARKER));
+        assert!(requests
+            .iter()
+            .any(|r| r.method == "PUT" && r.body["add_labels"] == json!("needs-rebase")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gitlab_server_side_rebase() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "pr.txt")?;
+        let api = fake_gitlab(&fixture)?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .strategy(Strategy::ServerSide)
+            .forge(
+                GitLab::new("token")
+                    .api_url(&api.url)
+                    .poll(Duration::from_millis(10), Duration::from_secs(5)),
+            )
+            .update()?;
+
+        let remote = Repository::open(&fixture.remote_dir)?;
+        let remote_head = remote.refname_to_id("refs/heads/pr")?;
+        assert_eq!(outcome.merge, MergeOutcome::Rebased(remote_head));
+        assert_eq!(outcome.head_after, remote_head);
+        assert!(outcome.is_updated());
+
+        // The history stays linear
+        let repo = Repository::open(&usage_dir)?;
+        let head = repo.find_commit(remote_head)?;
+        assert_eq!(head.parent_count(), 1);
+        assert_eq!(head.parent_id(0)?, remote.refname_to_id("refs/heads/main")?);
+        assert!(api
+            .requests()
+            .iter()
+            .any(|r| r.method == "PUT" && r.path.ends_with("/merge_requests/3/rebase")));
+
+        Ok(())
+    }
+
+    /// Serves pull request 5 of the `pr` branch on Gitea, updating it in a clone of the remote when asked
+    fn fake_gitea(fixture: &TestFixture) -> Result<FakeApi> {
+        let remote_dir = fixture.remote_dir.clone();
+        let server_dir = fixture.base_dir.join("server");
+        git_cmd(&fixture.base_dir)
+            .arg("clone")
+            .arg(&remote_dir)
+            .arg(&server_dir)
+            .status()?;
+        // The repository the remote url points to
+        let full_name = remote_dir.to_str().unwrap().trim_matches('/').to_string();
+
+        let api = FakeApi::start(move |request| {
+            let path = request.path.split('?').next().unwrap();
+            let repo = Repository::open(&remote_dir).unwrap();
+            let head = repo.refname_to_id("refs/heads/pr").unwrap();
+            let main = repo.refname_to_id("refs/heads/main").unwrap();
+            let pr = json!({
+                "number": 5,
+                "html_url": "https://codeberg.org/org/remote/pulls/5",
+                "state": "open",
+                "merged": false,
+                "merge_base": repo.merge_base(head, main).unwrap().to_string(),
+                "head": { "ref": "pr", "sha": head.to_string(), "repo": { "full_name": full_name } },
+                "base": { "ref": "main", "sha": main.to_string() },
+            });
+
+            match request.method.as_str() {
+                "GET" if path.ends_with("/remote/pulls") => (200, json!([pr])),
+                "GET" if path.ends_with("/remote/pulls/5") => (200, pr),
+                "POST" if path.ends_with("/remote/pulls/5/update") => {
+                    git_cmd(&server_dir)
+                        .args(["fetch", "origin"])
+                        .status()
+                        .unwrap();
+                    git_cmd(&server_dir)
+                        .args(["checkout", "-B", "pr", "origin/pr"])
+                        .status()
+                        .unwrap();
+                    let merged = git_cmd(&server_dir)
+                        .args(["merge", "--no-edit", "origin/main"])
+                        .status()
+                        .unwrap();
+                    if !merged.success() {
+                        git_cmd(&server_dir)
+                            .args(["merge", "--abort"])
+                            .status()
+                            .unwrap();
+                        return (
+                            409,
+                            json!({ "message": "merge failed because of conflict" }),
+                        );
+                    }
+                    git_cmd(&server_dir)
+                        .args(["push", "origin", "pr"])
+                        .status()
+                        .unwrap();
+
+                    (200, Value::Null)
+                }
+                "GET" if path.ends_with("/issues/5/comments") => (200, json!([])),
+                "POST" if path.ends_with("/issues/5/comments") => {
+                    (201, json!({ "id": 4, "body": request.body["body"] }))
+                }
+                _ => (404, json!({ "message": "The target couldn't be found." })),
+            }
+        })?;
+
+        Ok(api)
+    }
+
+    #[test]
+    fn test_gitea_server_side_update() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "pr.txt")?;
+        let api = fake_gitea(&fixture)?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .strategy(Strategy::ServerSide)
+            .forge(
+                Gitea::new("token")
+                    .api_url(&api.url)
+                    .poll(Duration::from_millis(10), Duration::from_secs(5)),
+            )
+            .update()?;
+
+        let remote_head = Repository::open(&fixture.remote_dir)?.refname_to_id("refs/heads/pr")?;
+        assert_eq!(outcome.merge, MergeOutcome::Merged(remote_head));
+        assert_eq!(outcome.head_after, remote_head);
+        assert!(api
+            .requests()
+            .iter()
+            .any(|r| r.method == "POST" && r.path.ends_with("/pulls/5/update?style=merge")));
+
+        // Nothing is asked of Gitea once the branch is up to date
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .strategy(Strategy::ServerSide)
+            .forge(Gitea::new("token").api_url(&api.url))
+            .update()?;
+        assert_eq!(outcome.merge, MergeOutcome::UpToDate);
+        let updates = api
+            .requests()
+            .iter()
+            .filter(|r| r.method == "POST" && r.path.contains("/pulls/5/update"))
+            .count();
+        assert_eq!(updates, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gitea_conflict_falls_back() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "file.txt")?;
+        let api = fake_gitea(&fixture)?;
+
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(SilentReporter)
+            .strategy(Strategy::ServerSide)
+            .forge(Gitea::new("token").api_url(&api.url))
+            .update()?;
+
+        assert_eq!(
+            outcome.merge,
+            MergeOutcome::Conflicts(vec!["file.txt".to_string()])
+        );
+        assert!(api
+            .requests()
+            .iter()
+            .any(|r| r.method == "POST" && r.path.ends_with("/issues/5/comments")));
+
+        Ok(())
+    }
+}
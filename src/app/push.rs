@@ -1,44 +1,122 @@
-use console::{style, Emoji};
-use git2::{IntoCString, PushOptions, Remote, RemoteCallbacks};
+use std::{cell::RefCell, ffi::OsStr};
 
-use crate::{progress::push::PushProgress, utils::git::ssh_creds};
+use git2::{ErrorClass, ErrorCode, IntoCString, PushOptions, Remote, RemoteCallbacks};
 
-use super::App;
+use crate::{report::Event, utils::git::redact_credentials};
 
-static TRUCK: Emoji<'_, '_> = Emoji("🚚  ", "");
+use super::{lfs::Lfs, Updater};
 
-pub trait Push {
+pub trait Push: Lfs {
     fn push<Str: AsRef<str> + IntoCString + Clone>(
         &self,
         refspecs: &[Str],
         remote: &mut Remote,
     ) -> Result<(), git2::Error> {
-        let pb = PushProgress::new();
+        let _span = tracing::info_span!(
+            "push",
+            remote = remote.name().unwrap_or_default(),
+            url = %redact_credentials(remote.url().unwrap_or_default()),
+        )
+        .entered();
+
+        self.pre_push(refspecs, remote)?;
+        self.lfs_pre_push(&self.pre_push_input(refspecs, remote), remote)?;
+
+        let reporter = self.reporter();
+        let remote_name = remote.name().unwrap().to_string();
+        let rejected = RefCell::new(None);
 
         let mut cb = RemoteCallbacks::new();
-        cb.credentials(ssh_creds)
-            .push_transfer_progress(|current, total, _bytes| {
-                let pb = pb.pb();
-                pb.set_length(total as u64);
-                pb.set_position(current as u64);
+        cb.credentials(|url, username, allowed| self.credentials(url, username, allowed))
+            .push_transfer_progress(|current, total, bytes| {
+                reporter.report(&Event::PushProgress {
+                    current,
+                    total,
+                    bytes,
+                });
+            })
+            // The remote refusing a reference is not an error for libgit2
+            .push_update_reference(|refname, status| {
+                if let Some(message) = status {
+                    rejected.replace(Some(format!("{} ({})", refname, message)));
+                }
+                Ok(())
             });
 
         let mut po = PushOptions::new();
         po.remote_callbacks(cb);
 
-        pb.pb().println(format!(
-            "{}Pushing {} for repo...",
-            TRUCK,
-            style(remote.name().unwrap()).italic().bold()
-        ));
+        reporter.report(&Event::PushStarted {
+            remote: remote_name.clone(),
+            refspecs: refspecs.iter().map(|r| r.as_ref().to_string()).collect(),
+        });
 
         remote.push(refspecs, Some(&mut po))?;
+        drop(po);
+        if let Some(rejected) = rejected.into_inner() {
+            return Err(git2::Error::new(
+                ErrorCode::NotFastForward,
+                ErrorClass::Reference,
+                format!("the remote rejected {}", rejected),
+            ));
+        }
+        tracing::info!("pushed");
+
+        reporter.report(&Event::PushDone {
+            remote: remote_name,
+        });
 
         Ok(())
     }
+
+    /// Run the `pre-push` hook with the arguments and input git would give it
+    fn pre_push<Str: AsRef<str>>(
+        &self,
+        refspecs: &[Str],
+        remote: &Remote,
+    ) -> Result<(), git2::Error> {
+        let remote_name = remote.name().unwrap_or_default();
+
+        self.run_hook(
+            "pre-push",
+            &[
+                OsStr::new(remote_name),
+                OsStr::new(remote.url().unwrap_or(remote_name)),
+            ],
+            Some(&self.pre_push_input(refspecs, remote)),
+        )
+    }
+
+    /// The input of the `pre-push` hook for pushing `refspecs` to `remote`
+    ///
+    /// Each refspec is written as `<local ref> <local oid> <remote ref> <remote oid>`,
+    /// where the remote oid is taken from the remote-tracking branch when there is one.
+    fn pre_push_input<Str: AsRef<str>>(&self, refspecs: &[Str], remote: &Remote) -> String {
+        let repo = self.repo();
+        let remote_name = remote.name().unwrap_or_default();
+        let zero = git2::Oid::zero();
+
+        let mut stdin = String::new();
+        for refspec in refspecs {
+            let refspec = refspec.as_ref().trim_start_matches('+');
+            let (src, dst) = refspec.split_once(':').unwrap_or((refspec, refspec));
+
+            let local_oid = repo.refname_to_id(src).unwrap_or(zero);
+            let tracking = format!(
+                "refs/remotes/{}/{}",
+                remote_name,
+                dst.trim_start_matches("refs/heads/")
+            );
+            let remote_oid = repo.refname_to_id(&tracking).unwrap_or(zero);
+
+            stdin.push_str(&format!("{} {} {} {}\n", src, local_oid, dst, remote_oid));
+        }
+
+        stdin
+    }
 }
 
-impl Push for App {}
+impl Push for Updater {}
 
 #[cfg(test)]
 mod tests {
@@ -54,6 +132,7 @@ mod tests {
     use testdir::testdir;
 
     use super::*;
+    use crate::app::{hooks::Hooks, repo::Repo};
 
     pub struct TestApp {
         pub repo: Repository,
@@ -69,6 +148,16 @@ mod tests {
         }
     }
 
+    impl Repo for TestApp {
+        fn repo(&self) -> &Repository {
+            &self.repo
+        }
+    }
+
+    impl Hooks for TestApp {}
+
+    impl Lfs for TestApp {}
+
     impl Push for TestApp {}
 
     fn git_cmd(dir: &Path) -> Command {
@@ -182,4 +271,93 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_push_rejected() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+        git_cmd(&usage_dir)
+            .args(["commit", "--allow-empty", "-m", "diverged"])
+            .status()?;
+
+        // Another clone moves main first
+        let other_dir = fixture.get_repo_dir("other")?;
+        git_cmd(&other_dir)
+            .args(["commit", "--allow-empty", "-m", "other"])
+            .status()?;
+        git_cmd(&other_dir)
+            .args(["push", "origin", "HEAD:main"])
+            .status()?;
+
+        let app = TestApp {
+            repo: Repository::open(&usage_dir)?,
+        };
+
+        let err = app
+            .push(&["refs/heads/main"], &mut app.repo.find_remote("origin")?)
+            .unwrap_err();
+
+        assert_eq!(err.code(), ErrorCode::NotFastForward);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pre_push_hook() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let fixture = TestFixture::new()?.setup()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+
+        // Create a commit
+        {
+            let file_path = usage_dir.join("file.txt");
+            let mut file = File::create(&file_path)?;
+            file.write_all(b"Hello World")?;
+
+            git_cmd(&usage_dir).arg("add").arg(&file_path).status()?;
+            git_cmd(&usage_dir)
+                .arg("commit")
+                .arg("-m")
+                .arg("add file.txt")
+                .status()?;
+        }
+
+        // Record what the hook receives and reject the push
+        let hook_path = usage_dir.join(".git/hooks/pre-push");
+        std::fs::write(
+            &hook_path,
+            "#!/bin/sh\necho \"$1\" > pre-push.out\ncat >> pre-push.out\nexit 1\n",
+        )?;
+        std::fs::set_permissions(&hook_path, std::fs::Permissions::from_mode(0o755))?;
+
+        let app = TestApp {
+            repo: Repository::open(&usage_dir)?,
+        };
+
+        let remote_commit_id = app.get_fetch_commit()?.id();
+        let local_commit_id = app.repo.head()?.peel_to_commit()?.id();
+
+        let err = app
+            .push(&["refs/heads/main"], &mut app.repo.find_remote("origin")?)
+            .unwrap_err();
+
+        assert!(crate::app::hooks::is_hook_error(&err));
+        assert_eq!(
+            std::fs::read_to_string(usage_dir.join("pre-push.out"))?,
+            format!(
+                "origin\nrefs/heads/main {} refs/heads/main {}\n",
+                local_commit_id, remote_commit_id
+            )
+        );
+        assert_eq!(
+            Repository::open(&fixture.remote_dir)?.refname_to_id("refs/heads/main")?,
+            remote_commit_id
+        );
+
+        Ok(())
+    }
 }
@@ -1,13 +1,49 @@
-use git2::Repository;
+use git2::{Cred, CredentialType, Repository};
 
-use super::App;
+use crate::{
+    report::{Reporter, SilentReporter},
+    utils::git::ssh_creds,
+};
+
+use super::Updater;
 
 pub trait Repo {
     fn repo(&self) -> &Repository;
+
+    /// Acquire credentials for the remote, from the SSH agent by default
+    fn credentials(
+        &self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed_types: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        ssh_creds(url, username_from_url, allowed_types)
+    }
+
+    /// Where events are reported, discarded by default
+    fn reporter(&self) -> &dyn Reporter {
+        &SilentReporter
+    }
 }
 
-impl Repo for App {
+impl Repo for Updater {
     fn repo(&self) -> &Repository {
         &self.repo
     }
+
+    fn credentials(
+        &self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed_types: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        match &self.credentials {
+            Some(credentials) => credentials(url, username_from_url, allowed_types),
+            None => ssh_creds(url, username_from_url, allowed_types),
+        }
+    }
+
+    fn reporter(&self) -> &dyn Reporter {
+        self.reporter.as_ref()
+    }
 }
@@ -0,0 +1,582 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use git2::{FileMode, Index, IndexConflict, IndexEntry, MergeFileOptions, Oid, Repository};
+use sha1_smol::Sha1;
+
+use crate::report::Event;
+
+use super::{repo::Repo, submodules::INDEX_ENTRY_STAGE_MASK, Updater};
+
+/// The length of the conflict markers, as written by git and libgit2
+const MARKER_SIZE: usize = 7;
+
+/// Lists the conflicts waiting for a resolution, like `MERGE_RR`, after the two commits they come from
+///
+/// git deletes `MERGE_RR` itself when committing or resetting, so the
+/// resolutions pulled from the remote could not be recorded with it.
+const PENDING_FILE: &str = "UPDATE_PR_RR";
+
+/// A conflicted file as `git rerere` records it
+///
+/// The markers are stripped of their labels and the two sides of each
+/// conflict are sorted, so the same conflict has the same preimage whichever
+/// branch is merged into the other.
+#[derive(Debug, PartialEq, Eq)]
+struct Preimage {
+    /// The SHA-1 of the conflicts, naming their directory in `rr-cache`
+    id: String,
+    content: Vec<u8>,
+}
+
+/// Records how conflicts are resolved, and resolves them the same way when they come back
+///
+/// Resolutions are kept in `rr-cache` with the layout of `git rerere`, so
+/// resolutions recorded by git are reused as well.
+pub trait Rerere: Repo {
+    /// Whether resolutions are recorded and reused
+    ///
+    /// Like git, this follows `rerere.enabled`, and when it is not set,
+    /// whether `rr-cache` exists.
+    fn rerere_enabled(&self) -> bool {
+        enabled_in_config(self.repo())
+    }
+
+    /// Resolve the conflicts of `idx` that were resolved before
+    ///
+    /// The resolutions are staged in `idx`, the other conflicts are left
+    /// untouched.
+    fn reuse_resolutions(&self, idx: &mut Index) -> Result<(), git2::Error> {
+        if !self.rerere_enabled() {
+            return Ok(());
+        }
+        let repo = self.repo();
+
+        let mut resolved: Vec<(IndexEntry, Vec<u8>)> = Vec::new();
+        for conflict in idx.conflicts()? {
+            let Some((ours, preimage)) = preimage(repo, conflict?)? else {
+                continue;
+            };
+            if let Some(content) = recorded_resolution(repo, &ours, &preimage)? {
+                resolved.push((ours, content));
+            }
+        }
+
+        for (mut entry, content) in resolved {
+            let path = String::from_utf8_lossy(&entry.path).to_string();
+
+            idx.conflict_remove(Path::new(&path))?;
+            entry.flags &= !INDEX_ENTRY_STAGE_MASK;
+            entry.id = repo.blob(&content)?;
+            entry.file_size = content.len() as u32;
+            idx.add(&entry)?;
+            tracing::info!(%path, "reused a recorded resolution");
+
+            self.reporter().report(&Event::ResolutionReused { path });
+        }
+
+        Ok(())
+    }
+
+    /// Record the preimages of the conflicts left in `idx` by merging `base` into `head`
+    ///
+    /// They are listed with both commits, so that their resolutions are
+    /// recorded once a merge of them follows.
+    fn record_conflicts(&self, idx: &Index, head: Oid, base: Oid) -> Result<(), git2::Error> {
+        if !self.rerere_enabled() {
+            return Ok(());
+        }
+        let repo = self.repo();
+
+        let mut pending = Vec::new();
+        for conflict in idx.conflicts()? {
+            let Some((ours, preimage)) = preimage(repo, conflict?)? else {
+                continue;
+            };
+
+            // A conflict already resolved in another context is recorded as a new variant
+            let dir = cache_dir(repo).join(&preimage.id);
+            let variant = (0..)
+                .find(|variant| !dir.join(image_name("postimage", *variant)).exists())
+                .expect("variants are not exhausted");
+            fs::create_dir_all(&dir).map_err(io_error)?;
+            fs::write(dir.join(image_name("preimage", variant)), &preimage.content)
+                .map_err(io_error)?;
+            tracing::debug!(id = %preimage.id, variant, "recorded a preimage");
+
+            match variant {
+                0 => pending.extend_from_slice(preimage.id.as_bytes()),
+                variant => pending.extend(format!("{}.{}", preimage.id, variant).bytes()),
+            }
+            pending.push(b'\t');
+            pending.extend_from_slice(&ours.path);
+            pending.push(0);
+        }
+
+        if !pending.is_empty() {
+            let mut content = format!("{}\0{}\0", head, base).into_bytes();
+            content.extend(pending);
+            fs::write(repo.path().join(PENDING_FILE), content).map_err(io_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record the resolutions of the conflicts left by the last update, as committed in `HEAD`
+    ///
+    /// Nothing is recorded until a commit follows the conflicts, whether it
+    /// was made here or pulled from the remote. Only a merge of the two
+    /// conflicting commits is taken as their resolution, otherwise the
+    /// conflicts are forgotten. Files still having conflict markers are not
+    /// taken as resolutions either.
+    fn record_resolutions(&self) -> Result<(), git2::Error> {
+        let repo = self.repo();
+        let pending_path = repo.path().join(PENDING_FILE);
+        if !self.rerere_enabled() || !pending_path.exists() {
+            return Ok(());
+        }
+
+        let pending = fs::read(&pending_path).map_err(io_error)?;
+        let mut records = pending.split(|&c| c == 0).filter(|r| !r.is_empty());
+        let mut next_oid = || {
+            records
+                .next()
+                .and_then(|oid| Oid::from_str(&String::from_utf8_lossy(oid)).ok())
+        };
+        let (conflicted, base) = (next_oid(), next_oid());
+        let head = repo.head()?.peel_to_commit()?;
+        if conflicted == Some(head.id()) {
+            tracing::debug!("the conflicts are not resolved yet");
+            return Ok(());
+        }
+
+        // e.g. the branch was reset, then commits of someone else were pulled
+        let parents: Vec<Oid> = head.parent_ids().collect();
+        let resolved = match (conflicted, base) {
+            (Some(conflicted), Some(base)) => {
+                parents.contains(&conflicted) && parents.contains(&base)
+            }
+            _ => false,
+        };
+        if !resolved {
+            tracing::debug!(head = %head.id(), "not a merge of the conflicts, forgetting them");
+            return fs::remove_file(&pending_path).map_err(io_error);
+        }
+        let tree = head.tree()?;
+
+        for record in records {
+            let record = String::from_utf8_lossy(record);
+            let Some((id, path)) = record.split_once('\t') else {
+                continue;
+            };
+            let (id, variant) = match id.split_once('.') {
+                Some((id, variant)) => (id, variant.parse().unwrap_or(0)),
+                None => (id, 0),
+            };
+
+            let dir = cache_dir(repo).join(id);
+            let postimage = dir.join(image_name("postimage", variant));
+            if postimage.exists() || !dir.join(image_name("preimage", variant)).exists() {
+                continue;
+            }
+            let Ok(blob) = tree
+                .get_path(Path::new(path))
+                .and_then(|entry| repo.find_blob(entry.id()))
+            else {
+                continue;
+            };
+            if normalize(blob.content()).is_some() {
+                tracing::debug!(%path, "still conflicted");
+                continue;
+            }
+
+            fs::write(&postimage, blob.content()).map_err(io_error)?;
+            tracing::info!(%path, %id, "recorded a resolution");
+
+            self.reporter().report(&Event::ResolutionRecorded {
+                path: path.to_string(),
+            });
+        }
+
+        fs::remove_file(&pending_path).map_err(io_error)
+    }
+}
+
+impl Rerere for Updater {
+    fn rerere_enabled(&self) -> bool {
+        self.rerere.unwrap_or_else(|| enabled_in_config(&self.repo))
+    }
+}
+
+/// The preimage of a conflict between two versions of a text file, with the entry of our version
+fn preimage(
+    repo: &Repository,
+    conflict: IndexConflict,
+) -> Result<Option<(IndexEntry, Preimage)>, git2::Error> {
+    let (Some(ours), Some(theirs)) = (conflict.our, conflict.their) else {
+        return Ok(None);
+    };
+    let is_file = |entry: &IndexEntry| {
+        entry.mode == u32::from(FileMode::Blob) || entry.mode == u32::from(FileMode::BlobExecutable)
+    };
+    if !is_file(&ours) || !is_file(&theirs) {
+        return Ok(None);
+    }
+
+    // Both sides added the file
+    let ancestor = match conflict.ancestor {
+        Some(ancestor) => ancestor,
+        None => with_blob(&ours, repo.blob(b"")?),
+    };
+
+    let mut opts = MergeFileOptions::new();
+    opts.our_label("ours").their_label("theirs");
+    let merged = repo.merge_file_from_index(&ancestor, &ours, &theirs, Some(&mut opts))?;
+    if merged.content().contains(&0) {
+        return Ok(None);
+    }
+
+    Ok(normalize(merged.content()).map(|preimage| (ours, preimage)))
+}
+
+/// The content resolving the conflict `preimage` of `entry`, if it was resolved before
+///
+/// Like `git rerere`, the recorded resolution is merged with the conflict,
+/// so it applies as long as the lines around the conflicts didn't change
+/// too.
+fn recorded_resolution(
+    repo: &Repository,
+    entry: &IndexEntry,
+    preimage: &Preimage,
+) -> Result<Option<Vec<u8>>, git2::Error> {
+    let dir = cache_dir(repo).join(&preimage.id);
+    let Ok(files) = fs::read_dir(&dir) else {
+        return Ok(None);
+    };
+
+    let mut postimages: Vec<PathBuf> = files
+        .filter_map(|file| file.ok())
+        .map(|file| file.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("postimage"))
+        })
+        .collect();
+    postimages.sort();
+
+    for postimage in postimages {
+        let name = postimage.file_name().unwrap().to_str().unwrap();
+        let recorded = dir.join(name.replacen("postimage", "preimage", 1));
+        let (Ok(recorded), Ok(resolution)) = (fs::read(recorded), fs::read(&postimage)) else {
+            continue;
+        };
+
+        let merged = repo.merge_file_from_index(
+            &with_blob(entry, repo.blob(&recorded)?),
+            &with_blob(entry, repo.blob(&preimage.content)?),
+            &with_blob(entry, repo.blob(&resolution)?),
+            None,
+        )?;
+        if merged.is_automergeable() {
+            tracing::debug!(id = %preimage.id, file = name, "found a recorded resolution");
+            return Ok(Some(merged.content().to_vec()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn enabled_in_config(repo: &Repository) -> bool {
+    match repo
+        .config()
+        .and_then(|config| config.get_bool("rerere.enabled"))
+    {
+        Ok(enabled) => enabled,
+        Err(_) => cache_dir(repo).is_dir(),
+    }
+}
+
+/// Where resolutions are recorded, shared by the worktrees of the repository
+fn cache_dir(repo: &Repository) -> PathBuf {
+    repo.commondir().join("rr-cache")
+}
+
+/// The name of the preimage or postimage of a variant of a conflict
+fn image_name(image: &str, variant: usize) -> String {
+    match variant {
+        0 => image.to_string(),
+        variant => format!("{}.{}", image, variant),
+    }
+}
+
+/// An entry like `entry` with the content of `blob`
+fn with_blob(entry: &IndexEntry, blob: Oid) -> IndexEntry {
+    IndexEntry {
+        ctime: entry.ctime,
+        mtime: entry.mtime,
+        dev: entry.dev,
+        ino: entry.ino,
+        mode: entry.mode,
+        uid: entry.uid,
+        gid: entry.gid,
+        file_size: 0,
+        id: blob,
+        flags: entry.flags & !INDEX_ENTRY_STAGE_MASK,
+        flags_extended: entry.flags_extended,
+        path: entry.path.clone(),
+    }
+}
+
+fn io_error(e: io::Error) -> git2::Error {
+    git2::Error::from_str(&e.to_string())
+}
+
+/// The preimage of a file with conflict markers, `None` when it has no conflicts or they are malformed
+fn normalize(content: &[u8]) -> Option<Preimage> {
+    let mut hasher = Sha1::new();
+    let mut normalized = Vec::with_capacity(content.len());
+    let mut conflicts = 0;
+
+    let mut lines = content.split_inclusive(|&c| c == b'\n');
+    while let Some(line) = lines.next() {
+        if is_marker(line, b'<') {
+            normalized.extend(normalize_conflict(&mut lines, Some(&mut hasher))?);
+            conflicts += 1;
+        } else {
+            normalized.extend_from_slice(line);
+        }
+    }
+
+    (conflicts > 0).then(|| Preimage {
+        id: hasher.digest().to_string(),
+        content: normalized,
+    })
+}
+
+/// Normalize the conflict following a `<<<<<<<` line, hashing its sides into `hasher`
+///
+/// The common ancestor of diff3-style conflicts is dropped, nested conflicts
+/// are normalized without being hashed, as `git rerere` does.
+fn normalize_conflict<'a>(
+    lines: &mut impl Iterator<Item = &'a [u8]>,
+    hasher: Option<&mut Sha1>,
+) -> Option<Vec<u8>> {
+    #[derive(PartialEq)]
+    enum Side {
+        One,
+        Ancestor,
+        Two,
+    }
+
+    let (mut one, mut two) = (Vec::new(), Vec::new());
+    let mut side = Side::One;
+    while let Some(line) = lines.next() {
+        if is_marker(line, b'<') {
+            let nested = normalize_conflict(lines, None)?;
+            match side {
+                Side::One => one.extend(nested),
+                Side::Ancestor | Side::Two => two.extend(nested),
+            }
+        } else if is_marker(line, b'|') {
+            if side != Side::One {
+                return None;
+            }
+            side = Side::Ancestor;
+        } else if is_marker(line, b'=') {
+            if side == Side::Two {
+                return None;
+            }
+            side = Side::Two;
+        } else if is_marker(line, b'>') {
+            if side != Side::Two {
+                return None;
+            }
+            if one > two {
+                std::mem::swap(&mut one, &mut two);
+            }
+            if let Some(hasher) = hasher {
+                hasher.update(&one);
+                hasher.update(&[0]);
+                hasher.update(&two);
+                hasher.update(&[0]);
+            }
+
+            let marker = |c: u8| {
+                let mut marker = vec![c; MARKER_SIZE];
+                marker.push(b'\n');
+                marker
+            };
+            let mut normalized = marker(b'<');
+            normalized.extend(one);
+            normalized.extend(marker(b'='));
+            normalized.extend(two);
+            normalized.extend(marker(b'>'));
+
+            return Some(normalized);
+        } else {
+            match side {
+                Side::One => one.extend_from_slice(line),
+                Side::Ancestor => {}
+                Side::Two => two.extend_from_slice(line),
+            }
+        }
+    }
+
+    None
+}
+
+/// Whether `line` is a conflict marker made of `c`, the ones opening and closing a conflict having a label
+fn is_marker(line: &[u8], c: u8) -> bool {
+    line.len() > MARKER_SIZE
+        && line[..MARKER_SIZE].iter().all(|&m| m == c)
+        && match line[MARKER_SIZE] {
+            b' ' => true,
+            _ if c == b'<' || c == b'>' => false,
+            next => next.is_ascii_whitespace(),
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::Result;
+
+    use super::*;
+    use crate::app::fixture::{commit_file, git_cmd, setup_branches, TestFixture};
+    use crate::{app::MergeOutcome, report::SilentReporter};
+
+    /// A `pr` branch in `usage` and a commit on `main`, both changing the middle line of `file.txt`
+    fn setup(fixture: &TestFixture) -> Result<PathBuf> {
+        setup_branches(
+            fixture,
+            &[("file.txt", "first\nbase\nlast\n")],
+            &[("file.txt", "first\npr\nlast\n")],
+            &[("file.txt", "first\nmain\nlast\n")],
+        )
+    }
+
+    #[test]
+    fn test_normalize() {
+        let ours = normalize(b"a\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\nb\n").unwrap();
+        let theirs = normalize(b"a\n<<<<<<< HEAD\ny\n=======\nx\n>>>>>>> main\nb\n").unwrap();
+        let diff3 =
+            normalize(b"a\n<<<<<<< ours\ny\n||||||| base\nz\n=======\nx\n>>>>>>> theirs\nb\n")
+                .unwrap();
+
+        assert_eq!(
+            ours.content,
+            b"a\n<<<<<<<\nx\n=======\ny\n>>>>>>>\nb\n".to_vec()
+        );
+        assert_eq!(ours, theirs);
+        assert_eq!(ours, diff3);
+
+        assert_eq!(normalize(b"a\n=======\nb\n"), None);
+        assert_eq!(normalize(b"<<<<<<< ours\nx\n=======\ny\n"), None);
+    }
+
+    #[test]
+    fn test_same_id_as_git() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup(&fixture)?;
+
+        git_cmd(&usage_dir).arg("fetch").status()?;
+        git_cmd(&usage_dir)
+            .args(["-c", "rerere.enabled=true", "merge", "origin/main"])
+            .status()?;
+
+        let recorded = fs::read_dir(usage_dir.join(".git/rr-cache"))?
+            .next()
+            .unwrap()?
+            .path();
+        let preimage = normalize(&fs::read(usage_dir.join("file.txt"))?).unwrap();
+        assert_eq!(recorded.file_name().unwrap().to_str().unwrap(), preimage.id);
+        assert_eq!(fs::read(recorded.join("preimage"))?, preimage.content);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reuse_resolution() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup(&fixture)?;
+        let repo = Repository::open(&usage_dir)?;
+        let pr = repo.refname_to_id("HEAD")?;
+        let update = || -> Result<MergeOutcome> {
+            let outcome = Updater::open(&usage_dir)?
+                .reporter(SilentReporter)
+                .rerere(true)
+                .update()?;
+            Ok(outcome.merge)
+        };
+
+        assert_eq!(
+            update()?,
+            MergeOutcome::Conflicts(vec!["file.txt".to_string()])
+        );
+        assert!(usage_dir.join(".git").join(PENDING_FILE).exists());
+
+        // Resolved by hand, and recorded by the next update
+        git_cmd(&usage_dir).args(["reset", "--hard"]).status()?;
+        git_cmd(&usage_dir)
+            .args(["-c", "rerere.enabled=false", "merge", "origin/main"])
+            .status()?;
+        commit_file(&usage_dir, "file.txt", "first\npr and main\nlast\n")?;
+        assert_eq!(update()?, MergeOutcome::UpToDate);
+        assert!(!usage_dir.join(".git").join(PENDING_FILE).exists());
+
+        // The same conflict comes back
+        git_cmd(&usage_dir)
+            .args(["reset", "--hard"])
+            .arg(pr.to_string())
+            .status()?;
+        let MergeOutcome::Merged(merge) = update()? else {
+            panic!("expected the recorded resolution to be reused");
+        };
+        let tree = repo.find_commit(merge)?.tree()?;
+        let blob = repo.find_blob(tree.get_path(Path::new("file.txt"))?.id())?;
+        assert_eq!(blob.content(), b"first\npr and main\nlast\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forget_unmerged_conflicts() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup(&fixture)?;
+        let update = || -> Result<MergeOutcome> {
+            let outcome = Updater::open(&usage_dir)?
+                .reporter(SilentReporter)
+                .rerere(true)
+                .update()?;
+            Ok(outcome.merge)
+        };
+
+        assert_eq!(
+            update()?,
+            MergeOutcome::Conflicts(vec!["file.txt".to_string()])
+        );
+
+        // Given up on, while someone else pushes to the branch
+        git_cmd(&usage_dir).args(["reset", "--hard"]).status()?;
+        let other_dir = fixture.get_repo_dir("other")?;
+        git_cmd(&other_dir).args(["checkout", "pr"]).status()?;
+        commit_file(&other_dir, "other.txt", "other\n")?;
+        git_cmd(&other_dir)
+            .args(["push", "origin", "pr"])
+            .status()?;
+
+        // The pulled commit is not a resolution of the conflicts
+        assert_eq!(
+            update()?,
+            MergeOutcome::Conflicts(vec!["file.txt".to_string()])
+        );
+        let postimages = fs::read_dir(usage_dir.join(".git/rr-cache"))?
+            .map(|dir| Ok(dir?.path().join("postimage").exists()))
+            .collect::<Result<Vec<bool>>>()?;
+        assert_eq!(postimages, vec![false]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use git2::{
+    FetchOptions, FileMode, Index, IndexEntry, Oid, RemoteCallbacks, Repository,
+    SubmoduleUpdateOptions, Tree,
+};
+
+use crate::report::Event;
+
+use super::{repo::Repo, Updater};
+
+/// The stage bits of [`IndexEntry::flags`], set on the entries of a conflict
+pub(super) const INDEX_ENTRY_STAGE_MASK: u16 = 0x3000;
+
+/// How conflicting submodule commits (gitlinks) are resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GitlinkPolicy {
+    /// Take the commit of the base branch
+    Base,
+    /// Keep the commit of the current branch
+    Ours,
+    /// Take the commit descending from the other one, the conflict stays when neither does
+    Newer,
+}
+
+pub trait Submodules: Repo {
+    /// Whether submodules changed by a merge are initialized and updated afterwards
+    fn update_submodules(&self) -> bool {
+        false
+    }
+
+    /// How conflicting gitlinks are resolved, `None` leaves them as conflicts
+    fn gitlink_policy(&self) -> Option<GitlinkPolicy> {
+        None
+    }
+
+    /// Options to fetch the repositories of submodules
+    fn submodule_fetch_options(&self) -> FetchOptions<'_> {
+        let mut cb = RemoteCallbacks::new();
+        cb.credentials(move |url, username, allowed| self.credentials(url, username, allowed));
+
+        let mut fo = FetchOptions::new();
+        fo.remote_callbacks(cb);
+
+        fo
+    }
+
+    /// Check out the submodules whose commit changed from `from` to `to`
+    ///
+    /// Like `git submodule update --init`, missing submodules are cloned and
+    /// commits missing from them are fetched. Does nothing unless
+    /// [`Submodules::update_submodules`] is set.
+    fn update_changed_submodules(&self, from: &Tree, to: &Tree) -> Result<(), git2::Error> {
+        if !self.update_submodules() {
+            return Ok(());
+        }
+
+        let repo = self.repo();
+        let diff = repo.diff_tree_to_tree(Some(from), Some(to), None)?;
+
+        let changed: Vec<(String, Oid)> = diff
+            .deltas()
+            .filter(|delta| delta.new_file().mode() == FileMode::Commit)
+            .filter_map(|delta| {
+                let path = delta.new_file().path()?.to_str()?.to_string();
+                Some((path, delta.new_file().id()))
+            })
+            .collect();
+
+        for (path, oid) in changed {
+            let _span = tracing::info_span!("submodule", %path, %oid).entered();
+
+            let mut submodule = repo.find_submodule(&path)?;
+            let mut opts = SubmoduleUpdateOptions::new();
+            opts.fetch(self.submodule_fetch_options());
+            submodule.update(true, Some(&mut opts))?;
+            tracing::info!("updated submodule");
+
+            self.reporter()
+                .report(&Event::SubmoduleUpdated { path, oid });
+        }
+
+        Ok(())
+    }
+
+    /// Resolve the conflicts of `idx` where both sides changed a gitlink
+    ///
+    /// The resolved gitlinks are staged in `idx`, the other conflicts are left
+    /// untouched. Does nothing without a [`Submodules::gitlink_policy`].
+    fn resolve_gitlink_conflicts(&self, idx: &mut Index) -> Result<(), git2::Error> {
+        let Some(policy) = self.gitlink_policy() else {
+            return Ok(());
+        };
+
+        let mut gitlinks: Vec<(IndexEntry, IndexEntry)> = Vec::new();
+        for conflict in idx.conflicts()? {
+            let conflict = conflict?;
+            if let (Some(ours), Some(theirs)) = (conflict.our, conflict.their) {
+                if is_gitlink(&ours) && is_gitlink(&theirs) {
+                    gitlinks.push((ours, theirs));
+                }
+            }
+        }
+
+        for (ours, theirs) in gitlinks {
+            let path = String::from_utf8_lossy(&ours.path).to_string();
+
+            let resolved = match policy {
+                GitlinkPolicy::Base => Some(theirs),
+                GitlinkPolicy::Ours => Some(ours),
+                GitlinkPolicy::Newer => self.newer_gitlink(&path, ours, theirs)?,
+            };
+            let Some(mut entry) = resolved else {
+                tracing::info!(%path, "neither submodule commit descends from the other");
+                continue;
+            };
+
+            idx.conflict_remove(Path::new(&path))?;
+            entry.flags &= !INDEX_ENTRY_STAGE_MASK;
+            let oid = entry.id;
+            idx.add(&entry)?;
+            tracing::info!(%path, %oid, ?policy, "resolved gitlink conflict");
+
+            self.reporter()
+                .report(&Event::GitlinkResolved { path, oid });
+        }
+
+        Ok(())
+    }
+
+    /// The side of a gitlink conflict whose commit descends from the other one
+    ///
+    /// The submodule has to be checked out to compare its commits, the other
+    /// side is fetched into it when missing.
+    fn newer_gitlink(
+        &self,
+        path: &str,
+        ours: IndexEntry,
+        theirs: IndexEntry,
+    ) -> Result<Option<IndexEntry>, git2::Error> {
+        let submodule = match self.repo().find_submodule(path).and_then(|s| s.open()) {
+            Ok(submodule) => submodule,
+            Err(e) => {
+                tracing::debug!(%path, "submodule not checked out: {}", e);
+                return Ok(None);
+            }
+        };
+
+        for oid in [ours.id, theirs.id] {
+            if submodule.find_commit(oid).is_err() {
+                self.fetch_submodule(&submodule)?;
+                break;
+            }
+        }
+        let descends = |a: Oid, b: Oid| submodule.graph_descendant_of(a, b).unwrap_or(false);
+        if descends(theirs.id, ours.id) {
+            Ok(Some(theirs))
+        } else if descends(ours.id, theirs.id) {
+            Ok(Some(ours))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Fetch the default refspecs of the `origin` remote of a submodule
+    fn fetch_submodule(&self, submodule: &Repository) -> Result<(), git2::Error> {
+        let mut remote = submodule.find_remote("origin")?;
+        let mut fo = self.submodule_fetch_options();
+
+        remote.fetch::<&str>(&[], Some(&mut fo), None)
+    }
+}
+
+impl Submodules for Updater {
+    fn update_submodules(&self) -> bool {
+        self.submodules
+    }
+
+    fn gitlink_policy(&self) -> Option<GitlinkPolicy> {
+        self.gitlink_policy
+    }
+}
+
+fn is_gitlink(entry: &IndexEntry) -> bool {
+    entry.mode == u32::from(FileMode::Commit)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::create_dir,
+        path::{Path, PathBuf},
+        process::Command,
+    };
+
+    use color_eyre::eyre::Result;
+    use git2::Repository;
+
+    use super::*;
+    use crate::app::{
+        fixture::{self, commit_file, TestApp, TestFixture},
+        merge::Merge,
+        MergeOutcome,
+    };
+
+    fn git_cmd(dir: &Path) -> Command {
+        let mut cmd = fixture::git_cmd(dir);
+
+        // Submodules cloned from a local path are refused by default since git 2.38.1
+        cmd.arg("-c").arg("protocol.file.allow=always");
+
+        cmd
+    }
+
+    /// The shared fixture, with a second remote for the submodule
+    struct SubFixture {
+        fixture: TestFixture,
+        sub_remote_dir: PathBuf,
+    }
+
+    impl SubFixture {
+        fn new() -> Result<Self> {
+            let fixture = TestFixture::new()?;
+
+            let sub_remote_dir = fixture.base_dir.join("sub_remote");
+            create_dir(&sub_remote_dir)?;
+            git_cmd(&sub_remote_dir)
+                .arg("init")
+                .arg("--bare")
+                .arg("--initial-branch=main")
+                .status()?;
+
+            Ok(Self {
+                fixture,
+                sub_remote_dir,
+            })
+        }
+
+        /// Push a first commit to the submodule and add it to the repository as `sub`
+        fn setup(mut self) -> Result<Self> {
+            let sub_dir = self.get_sub_dir("sub_setup")?;
+            commit_file(&sub_dir, "README.md", "# Sub\n")?;
+            push(&sub_dir)?;
+
+            self.fixture = self.fixture.setup()?;
+            let setup_dir = self.fixture.base_dir.join("setup");
+            git_cmd(&setup_dir)
+                .arg("submodule")
+                .arg("add")
+                .arg(&self.sub_remote_dir)
+                .arg("sub")
+                .status()?;
+            git_cmd(&setup_dir)
+                .arg("commit")
+                .arg("-m")
+                .arg("add sub")
+                .status()?;
+            push(&setup_dir)?;
+
+            Ok(self)
+        }
+
+        /// A clone of the repository, with its submodule
+        fn get_repo_dir(&self, name: &str) -> Result<PathBuf> {
+            let repo_dir = self.fixture.get_repo_dir(name)?;
+            git_cmd(&repo_dir)
+                .arg("submodule")
+                .arg("update")
+                .arg("--init")
+                .status()?;
+
+            Ok(repo_dir)
+        }
+
+        fn get_sub_dir(&self, name: &str) -> Result<PathBuf> {
+            let sub_dir = self.fixture.base_dir.join(name);
+            create_dir(&sub_dir)?;
+            git_cmd(&sub_dir)
+                .arg("clone")
+                .arg(&self.sub_remote_dir)
+                .arg(".")
+                .status()?;
+
+            Ok(sub_dir)
+        }
+    }
+
+    /// Commit `content` to `name` in `dir`, returning the new commit
+    fn commit(dir: &Path, name: &str, content: &str) -> Result<Oid> {
+        commit_file(dir, name, content)?;
+
+        Ok(Repository::open(dir)?.refname_to_id("HEAD")?)
+    }
+
+    fn push(dir: &Path) -> Result<()> {
+        git_cmd(dir)
+            .arg("push")
+            .arg("origin")
+            .arg("HEAD:main")
+            .status()?;
+
+        Ok(())
+    }
+
+    /// Check out `oid` in the submodule of `dir` and commit the new gitlink
+    fn bump_sub(dir: &Path, oid: Oid) -> Result<()> {
+        let sub_dir = dir.join("sub");
+
+        git_cmd(&sub_dir).arg("fetch").arg("origin").status()?;
+        git_cmd(&sub_dir)
+            .arg("checkout")
+            .arg(oid.to_string())
+            .status()?;
+        git_cmd(dir).arg("add").arg("sub").status()?;
+        git_cmd(dir)
+            .arg("commit")
+            .arg("-m")
+            .arg(format!("bump sub to {}", oid))
+            .status()?;
+
+        Ok(())
+    }
+
+    fn sub_head(dir: &Path) -> Result<Oid> {
+        Ok(Repository::open(dir.join("sub"))?.refname_to_id("HEAD")?)
+    }
+
+    #[test]
+    fn test_fast_forward_updates_submodule() -> Result<()> {
+        let fixture = SubFixture::new()?.setup()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+
+        // Bump the submodule on the base branch
+        let sub_dir = fixture.get_sub_dir("sub_alt")?;
+        let sub_commit = commit(&sub_dir, "file.txt", "Hello World")?;
+        push(&sub_dir)?;
+
+        let alt_dir = fixture.get_repo_dir("alt")?;
+        bump_sub(&alt_dir, sub_commit)?;
+        push(&alt_dir)?;
+
+        git_cmd(&usage_dir)
+            .arg("fetch")
+            .arg("origin")
+            .arg("main")
+            .status()?;
+
+        let app = TestApp {
+            update_submodules: true,
+            ..TestApp::open(&usage_dir)?
+        };
+
+        let outcome = app.merge("main", app.get_fetch_commit()?)?;
+
+        assert!(matches!(outcome, MergeOutcome::FastForward(_)));
+        assert_eq!(sub_head(&usage_dir)?, sub_commit);
+        assert!(usage_dir.join("sub/file.txt").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_submodule_update_rolls_back() -> Result<()> {
+        let fixture = SubFixture::new()?.setup()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+        let head_before = Repository::open(&usage_dir)?.refname_to_id("HEAD")?;
+
+        // The base branch points the submodule to a commit that was never pushed
+        let alt_dir = fixture.get_repo_dir("alt")?;
+        let unpushed = commit(&alt_dir.join("sub"), "file.txt", "Hello World")?;
+        bump_sub(&alt_dir, unpushed)?;
+        push(&alt_dir)?;
+
+        git_cmd(&usage_dir)
+            .arg("fetch")
+            .arg("origin")
+            .arg("main")
+            .status()?;
+
+        let app = TestApp {
+            update_submodules: true,
+            ..TestApp::open(&usage_dir)?
+        };
+
+        assert!(app.merge("main", app.get_fetch_commit()?).is_err());
+        assert_eq!(app.repo.refname_to_id("HEAD")?, head_before);
+        assert!(app.repo.statuses(None)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gitlink_conflicts() -> Result<()> {
+        let fixture = SubFixture::new()?.setup()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+
+        // The current branch moves the submodule to a commit the base then builds upon
+        let sub_dir = fixture.get_sub_dir("sub_alt")?;
+        let older = commit(&sub_dir, "file.txt", "Hello")?;
+        push(&sub_dir)?;
+        bump_sub(&usage_dir, older)?;
+
+        let newer = commit(&sub_dir, "file.txt", "Hello World")?;
+        push(&sub_dir)?;
+
+        let alt_dir = fixture.get_repo_dir("alt")?;
+        bump_sub(&alt_dir, newer)?;
+        push(&alt_dir)?;
+
+        git_cmd(&usage_dir)
+            .arg("fetch")
+            .arg("origin")
+            .arg("main")
+            .status()?;
+
+        // Without a policy the gitlink is an ordinary conflict
+        let app = TestApp {
+            update_submodules: true,
+            ..TestApp::open(&usage_dir)?
+        };
+
+        let outcome = app.merge("main", app.get_fetch_commit()?)?;
+
+        assert_eq!(outcome, MergeOutcome::Conflicts(vec!["sub".to_string()]));
+        git_cmd(&usage_dir)
+            .arg("reset")
+            .arg("--hard")
+            .arg("HEAD")
+            .status()?;
+
+        // The newer commit is fetched into the submodule and wins
+        let app = TestApp {
+            gitlink_policy: Some(GitlinkPolicy::Newer),
+            ..app
+        };
+
+        let outcome = app.merge("main", app.get_fetch_commit()?)?;
+
+        assert!(matches!(outcome, MergeOutcome::Merged(_)));
+        let tree = app.repo.head()?.peel_to_tree()?;
+        assert_eq!(tree.get_path(Path::new("sub"))?.id(), newer);
+        assert_eq!(sub_head(&usage_dir)?, newer);
+
+        Ok(())
+    }
+}
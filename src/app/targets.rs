@@ -0,0 +1,104 @@
+//! The repositories to update when several are given at once
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/// Read the repositories listed in the manifest at `path`
+///
+/// The manifest has one path per line, relative to the manifest's directory
+/// unless absolute. Blank lines and lines starting with `#` are ignored.
+pub fn read_manifest(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let content = fs::read_to_string(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| dir.join(line))
+        .collect())
+}
+
+/// Find the git repositories under `dir`, sorted by path
+///
+/// Repositories are not searched for nested ones, and hidden directories are
+/// skipped.
+pub fn scan(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("{} is not a directory", dir.display()),
+        ));
+    }
+
+    let mut repos = Vec::new();
+    scan_into(dir, &mut repos)?;
+    repos.sort();
+
+    Ok(repos)
+}
+
+fn scan_into(dir: &Path, repos: &mut Vec<PathBuf>) -> io::Result<()> {
+    // `.git` is a directory in repositories and a file in linked worktrees
+    if dir.join(".git").exists() {
+        repos.push(dir.to_path_buf());
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+
+        if !hidden && entry.file_type()?.is_dir() {
+            scan_into(&entry.path(), repos)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::create_dir_all;
+
+    use color_eyre::eyre::Result;
+    use testdir::testdir;
+
+    use super::*;
+
+    #[test]
+    fn test_read_manifest() -> Result<()> {
+        let dir = testdir!();
+        let manifest = dir.join("repos.txt");
+        fs::write(&manifest, "# services\napi\n\n  web  \n/srv/db\n")?;
+
+        assert_eq!(
+            read_manifest(&manifest)?,
+            vec![dir.join("api"), dir.join("web"), PathBuf::from("/srv/db")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan() -> Result<()> {
+        let dir = testdir!();
+        for repo in ["b/web", "a/api", "a/api/vendor/lib", ".cache/tool"] {
+            create_dir_all(dir.join(repo).join(".git"))?;
+        }
+        create_dir_all(dir.join("docs"))?;
+        create_dir_all(dir.join("worktree"))?;
+        fs::write(dir.join("worktree/.git"), "gitdir: elsewhere\n")?;
+
+        assert_eq!(
+            scan(&dir)?,
+            vec![dir.join("a/api"), dir.join("b/web"), dir.join("worktree")]
+        );
+        assert!(scan(&dir.join("missing")).is_err());
+
+        Ok(())
+    }
+}
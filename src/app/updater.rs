@@ -0,0 +1,1110 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use git2::{Cred, CredentialType, ErrorClass, ErrorCode, Oid, Repository, ResetType};
+
+use crate::{
+    forge::{CiStatus, Forge, MergeMethod},
+    progress::terminal::TerminalReporter,
+    report::{Event, Reporter},
+};
+
+use super::{
+    auto_merge::{AutoMerge, MergeAttempt, DEFAULT_CHECKS_INTERVAL, DEFAULT_CHECKS_TIMEOUT},
+    ci::{CiGate, CiStatuses, DEFAULT_WALK_LIMIT},
+    fetch::{Fetch, TagPolicy, DEFAULT_DEEPEN_LIMIT},
+    filters::{Filter, Filters},
+    hooks::is_hook_error,
+    lockfiles::Lockfile,
+    merge::Merge,
+    merge_options::MergeOption,
+    outcome::{MergeOutcome, UpdateOutcome},
+    pull_request::{ForgeMerge, PullRequests},
+    push::Push,
+    rerere::Rerere,
+    submodules::GitlinkPolicy,
+    worktrees::{WorktreePolicy, Worktrees},
+};
+
+/// Callback used to acquire credentials for the remote
+///
+/// It receives the remote url, the username found in the url, if any, and the
+/// allowed credential types, just like [`git2::RemoteCallbacks::credentials`].
+/// It must be `Send` and `Sync` so that updaters can run on other threads.
+pub type Credentials =
+    dyn Fn(&str, Option<&str>, CredentialType) -> Result<Cred, git2::Error> + Send + Sync;
+
+/// How the base branch is brought into the current branch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Fast-forward when possible, otherwise create a merge commit
+    #[default]
+    Merge,
+    /// Only fast-forward, leaving diverged branches untouched
+    FastForwardOnly,
+    /// Have the forge merge or rebase the pull request onto the base branch, then pull the result
+    ///
+    /// Nothing is pushed from the repository. The local pipeline, as with
+    /// [`Strategy::Merge`], is used instead when the forge reports conflicts,
+    /// when the branch has no pull request or when no forge is configured.
+    ServerSide,
+}
+
+/// Keeps the checked out branch of a repository up to date with a base branch
+///
+/// ```no_run
+/// use update_pr::Updater;
+///
+/// let outcome = Updater::open(".")?
+///     .remote("origin")
+///     .base("main")
+///     .update()?;
+///
+/// println!("{}", outcome);
+/// # Ok::<(), git2::Error>(())
+/// ```
+pub struct Updater {
+    pub(super) repo: Repository,
+    pub(super) remote: String,
+    pub(super) base: String,
+    strategy: Strategy,
+    pub(super) tags: Option<TagPolicy>,
+    pub(super) prune: Option<bool>,
+    pub(super) deepen_limit: i32,
+    pub(super) submodules: bool,
+    pub(super) gitlink_policy: Option<GitlinkPolicy>,
+    pub(super) rerere: Option<bool>,
+    pub(super) lockfiles: Vec<Lockfile>,
+    pub(super) merge_options: Vec<MergeOption>,
+    pub(super) no_verify: bool,
+    pub(super) worktree_policy: WorktreePolicy,
+    pub(super) forge: Option<Box<dyn Forge>>,
+    pub(super) conflict_label: Option<String>,
+    pub(super) ci_gate: Option<CiGate>,
+    pub(super) ci_walk_limit: usize,
+    pub(super) filters: Vec<Filter>,
+    pub(super) merge_method: MergeMethod,
+    pub(super) checks_interval: Duration,
+    pub(super) checks_timeout: Duration,
+    pub(super) credentials: Option<Box<Credentials>>,
+    pub(super) reporter: Box<dyn Reporter>,
+}
+
+impl Updater {
+    /// Create an updater for an already opened repository
+    pub fn new(repo: Repository) -> Self {
+        Self {
+            repo,
+            remote: "origin".to_string(),
+            base: "main".to_string(),
+            strategy: Strategy::default(),
+            tags: None,
+            prune: None,
+            deepen_limit: DEFAULT_DEEPEN_LIMIT,
+            submodules: false,
+            gitlink_policy: None,
+            rerere: None,
+            lockfiles: Vec::new(),
+            merge_options: Vec::new(),
+            no_verify: false,
+            worktree_policy: WorktreePolicy::default(),
+            forge: None,
+            conflict_label: None,
+            ci_gate: None,
+            ci_walk_limit: DEFAULT_WALK_LIMIT,
+            filters: Vec::new(),
+            merge_method: MergeMethod::default(),
+            checks_interval: DEFAULT_CHECKS_INTERVAL,
+            checks_timeout: DEFAULT_CHECKS_TIMEOUT,
+            credentials: None,
+            reporter: Box::new(TerminalReporter::new()),
+        }
+    }
+
+    /// Open the repository at `path` and create an updater for it
+    ///
+    /// Partial clones made by older versions of git, which mark them with the
+    /// `partialclone` extension, can only be opened after
+    /// [`allow_partial_clone`](crate::allow_partial_clone).
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, git2::Error> {
+        Ok(Self::new(Repository::open(path)?))
+    }
+
+    /// Open the linked worktree called `name` of the repository at `path`
+    ///
+    /// `path` can be the main worktree or any of the linked ones.
+    pub fn open_worktree<P: AsRef<Path>>(path: P, name: &str) -> Result<Self, git2::Error> {
+        let repo = Repository::open(path)?;
+        let worktree = Repository::open(repo.commondir())?.find_worktree(name)?;
+
+        Ok(Self::new(Repository::open_from_worktree(&worktree)?))
+    }
+
+    /// The remote to fetch from and push to, `origin` by default
+    pub fn remote<S: Into<String>>(mut self, remote: S) -> Self {
+        self.remote = remote.into();
+        self
+    }
+
+    /// The branch on the remote to merge into the current branch, `main` by default
+    pub fn base<S: Into<String>>(mut self, base: S) -> Self {
+        self.base = base.into();
+        self
+    }
+
+    /// How to bring the base branch in, [`Strategy::Merge`] by default
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Which tags to download, by default following `remote.<name>.tagOpt`
+    pub fn tags(mut self, tags: TagPolicy) -> Self {
+        self.tags = Some(tags);
+        self
+    }
+
+    /// Whether to prune deleted branches, by default following `fetch.prune`
+    pub fn prune(mut self, prune: bool) -> Self {
+        self.prune = Some(prune);
+        self
+    }
+
+    /// How many commits deep a shallow history is fetched at most to find a merge base
+    pub fn deepen_limit(mut self, deepen_limit: i32) -> Self {
+        self.deepen_limit = deepen_limit;
+        self
+    }
+
+    /// Initialize and update the submodules changed by merges, off by default
+    pub fn submodules(mut self, submodules: bool) -> Self {
+        self.submodules = submodules;
+        self
+    }
+
+    /// Resolve conflicting submodule commits with `policy` instead of reporting them
+    pub fn gitlink_conflicts(mut self, policy: GitlinkPolicy) -> Self {
+        self.gitlink_policy = Some(policy);
+        self
+    }
+
+    /// Record how conflicts are resolved and reuse the resolutions, following `rerere.enabled` by default
+    pub fn rerere(mut self, rerere: bool) -> Self {
+        self.rerere = Some(rerere);
+        self
+    }
+
+    /// Merge with `option`, like `git merge -X`, can be repeated
+    pub fn merge_option(mut self, option: MergeOption) -> Self {
+        self.merge_options.push(option);
+        self
+    }
+
+    /// Regenerate `lockfile` with its command when it is the only conflict, can be repeated
+    pub fn lockfile(mut self, lockfile: Lockfile) -> Self {
+        self.lockfiles.push(lockfile);
+        self
+    }
+
+    /// Skip the repository's hooks, the equivalent of `--no-verify`
+    pub fn no_verify(mut self, no_verify: bool) -> Self {
+        self.no_verify = no_verify;
+        self
+    }
+
+    /// What to do when the branch is also checked out in another worktree,
+    /// [`WorktreePolicy::Refuse`] by default
+    pub fn other_worktrees(mut self, policy: WorktreePolicy) -> Self {
+        self.worktree_policy = policy;
+        self
+    }
+
+    /// Comment the conflicts on the pull request of the branch on `forge`, e.g. [`GitHub`](crate::forge::GitHub)
+    pub fn forge<F: Forge + 'static>(mut self, forge: F) -> Self {
+        self.forge = Some(Box::new(forge));
+        self
+    }
+
+    /// Put `label` on the pull request while it has conflicts, e.g. `needs-rebase`
+    pub fn conflict_label<S: Into<String>>(mut self, label: S) -> Self {
+        self.conflict_label = Some(label.into());
+        self
+    }
+
+    /// Only merge commits of the base branch that passed their CI on the forge
+    pub fn ci_gate(mut self, gate: CiGate) -> Self {
+        self.ci_gate = Some(gate);
+        self
+    }
+
+    /// How many commits of the base branch are checked at most with [`CiGate::WalkBack`], 20 by default
+    pub fn ci_walk_limit(mut self, limit: usize) -> Self {
+        self.ci_walk_limit = limit;
+        self
+    }
+
+    /// Only update the branch if its pull request on the forge matches `filter`, can be called several times
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// How [`Updater::update_and_merge`] merges the pull request, [`MergeMethod::Merge`] by default
+    pub fn merge_method(mut self, method: MergeMethod) -> Self {
+        self.merge_method = method;
+        self
+    }
+
+    /// How often and how long [`Updater::update_and_merge`] reads the checks, 30 seconds and 30 minutes by default
+    pub fn checks_poll(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.checks_interval = interval;
+        self.checks_timeout = timeout;
+        self
+    }
+
+    /// Acquire credentials with `credentials` instead of the SSH agent
+    pub fn credentials<F>(mut self, credentials: F) -> Self
+    where
+        F: Fn(&str, Option<&str>, CredentialType) -> Result<Cred, git2::Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.credentials = Some(Box::new(credentials));
+        self
+    }
+
+    /// Report events to `reporter` instead of the terminal
+    pub fn reporter<R: Reporter + 'static>(mut self, reporter: R) -> Self {
+        self.reporter = Box::new(reporter);
+        self
+    }
+
+    /// The repository being updated
+    pub fn repository(&self) -> &Repository {
+        &self.repo
+    }
+
+    /// Pull the current branch, merge the base branch into it and push the result
+    ///
+    /// Nothing is pushed unless merging the base branch moved the branch.
+    pub fn update(&self) -> Result<UpdateOutcome, git2::Error> {
+        let _span = tracing::info_span!(
+            "update",
+            repo = %self.repo.path().display(),
+            remote = %self.remote,
+            base = %self.base,
+        )
+        .entered();
+
+        let branch = self.current_branch()?;
+        let head_before = self.get_current_oid()?;
+        tracing::debug!(%branch, %head_before, "starting update");
+
+        if let Some(reason) = self.skip_reason(&branch)? {
+            return Ok(UpdateOutcome {
+                branch,
+                base: self.base.clone(),
+                head_before,
+                head_after: head_before,
+                pull: MergeOutcome::UpToDate,
+                merge: MergeOutcome::Skipped(reason),
+                pushed: false,
+            });
+        }
+
+        let other_worktrees = self.check_other_worktrees(&branch)?;
+
+        let mut remote = self.repo.find_remote(&self.remote)?;
+        if self.should_prune(&remote) {
+            self.prune_remote(&mut remote)?;
+        }
+
+        if self.strategy == Strategy::ServerSide {
+            if let Some(outcome) = self.update_on_forge(&branch, head_before, &other_worktrees)? {
+                return Ok(outcome);
+            }
+        }
+
+        let pull = self.pull_current_branch(&branch)?;
+
+        self.record_resolutions()?;
+
+        let current_oid = self.get_current_oid()?;
+        let merge = self.merge_base(&branch)?;
+
+        let pushed = merge.is_changed();
+        if pushed {
+            if let Err(e) = self.push_current_branch(&branch) {
+                // A rejected pre-push aborts the whole update, including the merge commit
+                if is_hook_error(&e) {
+                    tracing::warn!(%current_oid, "push rejected by hook, rolling back");
+                    let commit = self.repo.find_commit(current_oid)?;
+                    self.repo.reset(commit.as_object(), ResetType::Hard, None)?;
+
+                    // The pull may still have moved the branch
+                    if current_oid != head_before {
+                        if let Err(sync_err) = self.update_worktrees(&other_worktrees) {
+                            tracing::warn!("could not update the other worktrees: {}", sync_err);
+                        }
+                    }
+                }
+
+                return Err(e);
+            }
+        }
+
+        let head_after = self.get_current_oid()?;
+        if head_after != head_before {
+            self.update_worktrees(&other_worktrees)?;
+        }
+
+        let outcome = UpdateOutcome {
+            branch,
+            base: self.base.clone(),
+            head_before,
+            head_after,
+            pull,
+            merge,
+            pushed,
+        };
+        self.sync_pull_request(&outcome);
+
+        Ok(outcome)
+    }
+
+    /// Update the branch, wait for the checks of its pull request, then merge it on the forge
+    ///
+    /// The branch is updated again whenever the base branch moves while the
+    /// checks run, until the checks timeout. The pull request is left open
+    /// when the branch has conflicts or its checks fail.
+    pub fn update_and_merge(&self) -> Result<UpdateOutcome, git2::Error> {
+        let deadline = Instant::now() + self.checks_timeout;
+
+        loop {
+            let outcome = self.update()?;
+
+            match self.merge_when_green(&outcome, deadline)? {
+                MergeAttempt::BaseMoved if Instant::now() >= deadline => {
+                    return Err(git2::Error::new(
+                        ErrorCode::GenericError,
+                        ErrorClass::Net,
+                        format!(
+                            "{} kept moving for {}, not merging the pull request",
+                            self.base,
+                            humantime::format_duration(self.checks_timeout)
+                        ),
+                    ))
+                }
+                MergeAttempt::BaseMoved => continue,
+                MergeAttempt::Merged(_) | MergeAttempt::Stopped(_) => return Ok(outcome),
+            }
+        }
+    }
+
+    /// Have the forge merge the base branch, `None` when it has to be merged locally
+    fn update_on_forge(
+        &self,
+        branch: &str,
+        head_before: Oid,
+        other_worktrees: &[Repository],
+    ) -> Result<Option<UpdateOutcome>, git2::Error> {
+        let Some(forge) = self.forge.as_deref() else {
+            return Ok(None);
+        };
+        if self.ci_gate.is_some() && !self.base_tip_is_green()? {
+            // The local pipeline merges the most recent green commit instead
+            return self.fallback(&format!("the tip of {} did not pass its CI", self.base));
+        }
+
+        let merge = match self.merge_on_forge(forge, branch, head_before) {
+            Ok(ForgeMerge::Merged(oid)) => MergeOutcome::Merged(oid),
+            Ok(ForgeMerge::Rebased(oid)) => MergeOutcome::Rebased(oid),
+            Ok(ForgeMerge::UpToDate) => MergeOutcome::UpToDate,
+            Ok(ForgeMerge::Conflict) => {
+                return self.fallback("the forge reports conflicts with the base branch")
+            }
+            Ok(ForgeMerge::NoPullRequest) => {
+                return self.fallback("the branch has no open pull request")
+            }
+            Ok(ForgeMerge::Diverged) => {
+                return self.fallback("the pull request is not at the commit of the local branch")
+            }
+            Ok(ForgeMerge::Unsupported) => {
+                return self.fallback(&format!("{} cannot update pull requests", forge.name()))
+            }
+            Err(e) => {
+                return Err(git2::Error::new(
+                    ErrorCode::GenericError,
+                    ErrorClass::Net,
+                    format!("could not update the pull request: {}", e),
+                ))
+            }
+        };
+
+        // The branch moved on the remote only
+        let pull = match merge {
+            MergeOutcome::Rebased(oid) => self.pull_rebased_branch(branch, oid)?,
+            _ => self.pull_current_branch(branch)?,
+        };
+
+        let head_after = self.get_current_oid()?;
+        if head_after != head_before {
+            self.update_worktrees(other_worktrees)?;
+        }
+
+        let outcome = UpdateOutcome {
+            branch: branch.to_string(),
+            base: self.base.clone(),
+            head_before,
+            head_after,
+            pull,
+            pushed: merge.is_changed(),
+            merge,
+        };
+        self.sync_pull_request(&outcome);
+
+        Ok(Some(outcome))
+    }
+
+    fn base_tip_is_green(&self) -> Result<bool, git2::Error> {
+        let mut remote = self.repo.find_remote(&self.remote)?;
+        let tip = self.fetch(&self.base, &mut remote)?.id();
+
+        Ok(self.ci_status(tip)? == CiStatus::Success)
+    }
+
+    fn fallback(&self, reason: &str) -> Result<Option<UpdateOutcome>, git2::Error> {
+        tracing::info!("merging locally: {}", reason);
+        self.reporter.report(&Event::ForgeFallback {
+            reason: reason.to_string(),
+        });
+
+        Ok(None)
+    }
+
+    fn merge_base(&self, branch: &str) -> Result<MergeOutcome, git2::Error> {
+        let mut remote = self.repo.find_remote(&self.remote)?;
+        let fetch_commit = self.fetch(&self.base, &mut remote)?;
+        self.deepen_until_merge_base(
+            &[branch, &self.base],
+            self.get_current_oid()?,
+            fetch_commit.id(),
+            &mut remote,
+        )?;
+
+        let fetch_commit = match self.green_base(&self.base, fetch_commit.id())? {
+            Some(oid) if oid == fetch_commit.id() => fetch_commit,
+            // Named after the commit itself in the merge message, not after the tip of the base branch
+            Some(oid) => self.repo.annotated_commit_from_fetchhead(
+                &oid.to_string(),
+                remote.url().unwrap_or_default(),
+                &oid,
+            )?,
+            None => {
+                return Ok(MergeOutcome::Skipped(format!(
+                    "no recent commit of {} passed its CI",
+                    self.base
+                )))
+            }
+        };
+
+        if self.strategy == Strategy::FastForwardOnly {
+            let (analysis, _) = self.repo.merge_analysis(&[&fetch_commit])?;
+
+            if analysis.is_normal() {
+                tracing::info!("branch diverged from base, not merging with fast-forward only");
+                return Ok(MergeOutcome::Diverged);
+            }
+        }
+
+        self.merge(branch, fetch_commit)
+    }
+
+    fn pull_current_branch(&self, branch: &str) -> Result<MergeOutcome, git2::Error> {
+        let mut remote = self.repo.find_remote(&self.remote)?;
+        let fetch_commit = match self.fetch(branch, &mut remote) {
+            Ok(fetch_commit) => fetch_commit,
+            // The branch has not been pushed yet, so there is nothing to pull
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(MergeOutcome::UpToDate),
+            Err(e) => return Err(e),
+        };
+        self.deepen_until_merge_base(
+            &[branch],
+            self.get_current_oid()?,
+            fetch_commit.id(),
+            &mut remote,
+        )?;
+
+        self.merge(branch, fetch_commit)
+    }
+
+    /// Move the local branch to `oid`, the branch rebased on the remote
+    ///
+    /// The local branch was at the head of the pull request before the
+    /// rebase, so no local commit is lost.
+    fn pull_rebased_branch(&self, branch: &str, oid: Oid) -> Result<MergeOutcome, git2::Error> {
+        let mut remote = self.repo.find_remote(&self.remote)?;
+        let fetch_commit = self.fetch(branch, &mut remote)?;
+        if fetch_commit.id() != oid {
+            return Err(git2::Error::new(
+                ErrorCode::NotFastForward,
+                ErrorClass::Reference,
+                format!("{} moved to {} after the rebase", branch, fetch_commit.id()),
+            ));
+        }
+
+        self.fast_forward(&mut self.repo.find_reference(branch)?, &fetch_commit)?;
+
+        Ok(MergeOutcome::Rebased(oid))
+    }
+
+    fn push_current_branch(&self, branch: &str) -> Result<(), git2::Error> {
+        let mut remote = self.repo.find_remote(&self.remote)?;
+
+        self.push(&[branch], &mut remote)
+    }
+
+    fn current_branch(&self) -> Result<String, git2::Error> {
+        let head = self.repo.head()?;
+
+        match head.name() {
+            Some(name) if head.is_branch() => Ok(name.to_string()),
+            _ => Err(git2::Error::from_str("HEAD is not on a branch")),
+        }
+    }
+
+    fn get_current_oid(&self) -> Result<Oid, git2::Error> {
+        self.repo.refname_to_id("HEAD")
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        fs::{create_dir, create_dir_all, File},
+        io::Write,
+        path::{Path, PathBuf},
+        process::Command,
+    };
+
+    use std::sync::{Arc, Mutex};
+
+    use color_eyre::eyre::Result;
+    use git2::AnnotatedCommit;
+    use testdir::testdir;
+
+    use super::*;
+    use crate::{
+        app::{
+            hooks::Hooks, lfs::Lfs, lockfiles::Lockfiles, merge_drivers::MergeDrivers,
+            merge_options::MergeTuning, partial::Partial, repo::Repo, submodules::Submodules,
+        },
+        report::Event,
+    };
+
+    /// Keeps every reported event
+    #[derive(Default)]
+    pub(crate) struct Recorder(pub(crate) Mutex<Vec<Event>>);
+
+    impl Reporter for Recorder {
+        fn report(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    pub(crate) fn git_cmd(dir: &Path) -> Command {
+        let mut cmd = Command::new("git");
+
+        cmd.current_dir(dir);
+
+        cmd
+    }
+
+    pub(crate) struct TestFixture {
+        pub(crate) base_dir: PathBuf,
+        pub(crate) remote_dir: PathBuf,
+    }
+
+    impl TestFixture {
+        pub(crate) fn new() -> Result<Self> {
+            // Named after the whole test path, as tests of other modules share this fixture
+            let test_name = std::thread::current().name().unwrap().replace("::", "/");
+            let base_dir = testdir!(test_name);
+
+            //
+            // setup remote
+            //
+            let remote_dir = base_dir.join("remote");
+            create_dir(&remote_dir)?;
+
+            git_cmd(&remote_dir)
+                .arg("init")
+                .arg("--bare")
+                .arg("--initial-branch=main")
+                .status()?;
+
+            Ok(Self {
+                base_dir,
+                remote_dir,
+            })
+        }
+
+        pub(crate) fn setup(self) -> Result<Self> {
+            let setup_dir = self.get_repo_dir("setup")?;
+
+            commit_file(&setup_dir, "README.md", "# Test\n")?;
+            git_cmd(&setup_dir)
+                .arg("push")
+                .arg("origin")
+                .arg("HEAD:main")
+                .status()?;
+
+            Ok(self)
+        }
+
+        pub(crate) fn get_repo_dir(&self, name: &str) -> Result<PathBuf> {
+            //
+            // setup repo
+            //
+            let repo_dir = self.base_dir.join(name);
+            create_dir(&repo_dir)?;
+
+            git_cmd(&repo_dir)
+                .arg("clone")
+                .arg(&self.remote_dir)
+                .arg(".")
+                .status()?;
+
+            Ok(repo_dir)
+        }
+    }
+
+    pub(crate) fn commit_file(dir: &Path, name: &str, content: &str) -> Result<()> {
+        let file_path = dir.join(name);
+        create_dir_all(file_path.parent().unwrap())?;
+        let mut file = File::create(&file_path)?;
+        file.write_all(content.as_bytes())?;
+
+        git_cmd(dir).arg("add").arg(&file_path).status()?;
+        git_cmd(dir)
+            .arg("commit")
+            .arg("-m")
+            .arg(format!("update {}", name))
+            .status()?;
+
+        Ok(())
+    }
+
+    /// Runs the merge of a repository on its own, without the rest of an [`Updater`]
+    pub(crate) struct TestApp {
+        pub(crate) repo: Repository,
+        pub(crate) update_submodules: bool,
+        pub(crate) gitlink_policy: Option<GitlinkPolicy>,
+    }
+
+    impl TestApp {
+        pub(crate) fn open(dir: &Path) -> Result<Self> {
+            Ok(Self {
+                repo: Repository::open(dir)?,
+                update_submodules: false,
+                gitlink_policy: None,
+            })
+        }
+
+        pub(crate) fn get_fetch_commit(&self) -> Result<AnnotatedCommit<'_>> {
+            let reference = self.repo.find_reference("refs/remotes/origin/main")?;
+
+            let ret = self.repo.reference_to_annotated_commit(&reference)?;
+
+            Ok(ret)
+        }
+    }
+
+    impl Repo for TestApp {
+        fn repo(&self) -> &Repository {
+            &self.repo
+        }
+    }
+
+    impl Hooks for TestApp {}
+
+    impl Lfs for TestApp {}
+
+    impl Lockfiles for TestApp {}
+
+    impl MergeDrivers for TestApp {}
+
+    impl MergeTuning for TestApp {}
+
+    impl Partial for TestApp {}
+
+    impl Rerere for TestApp {}
+
+    impl Submodules for TestApp {
+        fn update_submodules(&self) -> bool {
+            self.update_submodules
+        }
+
+        fn gitlink_policy(&self) -> Option<GitlinkPolicy> {
+            self.gitlink_policy
+        }
+    }
+
+    impl Merge for TestApp {}
+
+    /// Create a `pr` branch in `usage` adding `pr_file` and a commit on `main` adding `file.txt`
+    pub(crate) fn setup_pr(fixture: &TestFixture, pr_file: &str) -> Result<PathBuf> {
+        setup_branches(
+            fixture,
+            &[],
+            &[(pr_file, "pr\n")],
+            &[("file.txt", "main\n")],
+        )
+    }
+
+    /// Push `base` to `main`, a `pr` branch in `usage` with `pr`, then `main` to `main`
+    ///
+    /// Each `(name, content)` is a commit of its own, made in the `setup` clone of
+    /// [`TestFixture::setup`] for `base` and `main`.
+    pub(crate) fn setup_branches(
+        fixture: &TestFixture,
+        base: &[(&str, &str)],
+        pr: &[(&str, &str)],
+        main: &[(&str, &str)],
+    ) -> Result<PathBuf> {
+        let setup_dir = fixture.base_dir.join("setup");
+        for (name, content) in base {
+            commit_file(&setup_dir, name, content)?;
+        }
+        git_cmd(&setup_dir)
+            .arg("push")
+            .arg("origin")
+            .arg("HEAD:main")
+            .status()?;
+
+        let usage_dir = fixture.get_repo_dir("usage")?;
+
+        git_cmd(&usage_dir)
+            .arg("checkout")
+            .arg("-b")
+            .arg("pr")
+            .status()?;
+        for (name, content) in pr {
+            commit_file(&usage_dir, name, content)?;
+        }
+        git_cmd(&usage_dir)
+            .arg("push")
+            .arg("origin")
+            .arg("pr")
+            .status()?;
+
+        for (name, content) in main {
+            commit_file(&setup_dir, name, content)?;
+        }
+        git_cmd(&setup_dir)
+            .arg("push")
+            .arg("origin")
+            .arg("HEAD:main")
+            .status()?;
+
+        Ok(usage_dir)
+    }
+
+    #[test]
+    fn test_update() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "pr.txt")?;
+
+        let outcome = Updater::open(&usage_dir)?.update()?;
+
+        assert_eq!(outcome.branch, "refs/heads/pr");
+        assert!(matches!(outcome.merge, MergeOutcome::Merged(_)));
+        assert!(outcome.is_updated());
+        assert_eq!(
+            Repository::open(&fixture.remote_dir)?.refname_to_id("refs/heads/pr")?,
+            outcome.head_after
+        );
+
+        let outcome = Updater::open(&usage_dir)?.update()?;
+
+        assert_eq!(outcome.merge, MergeOutcome::UpToDate);
+        assert!(!outcome.is_updated());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_conflicts() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "file.txt")?;
+
+        let recorder = Arc::new(Recorder::default());
+        let outcome = Updater::open(&usage_dir)?
+            .reporter(recorder.clone())
+            .update()?;
+
+        assert_eq!(
+            outcome.merge,
+            MergeOutcome::Conflicts(vec!["file.txt".to_string()])
+        );
+
+        let events = recorder.0.lock().unwrap();
+        assert!(events.contains(&Event::Conflict {
+            paths: vec!["file.txt".to_string()]
+        }));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Event::PushStarted { .. })));
+        assert!(outcome.has_conflicts());
+        assert!(!outcome.is_updated());
+        assert_eq!(outcome.head_before, outcome.head_after);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_updater_is_send() {
+        // Updaters run on the worker threads of `-j`
+        fn assert_send<T: Send>() {}
+
+        assert_send::<Updater>();
+    }
+
+    #[test]
+    fn test_update_other_worktrees() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "pr.txt")?;
+
+        // Check out pr a second time, which git only allows with --force
+        let other_dir = fixture.base_dir.join("other");
+        git_cmd(&usage_dir)
+            .arg("worktree")
+            .arg("add")
+            .arg("--force")
+            .arg(&other_dir)
+            .arg("pr")
+            .status()?;
+
+        let err = Updater::open(&usage_dir)?.update().unwrap_err();
+
+        assert_eq!(err.code(), git2::ErrorCode::Locked);
+        assert!(err.message().contains("also checked out in the worktree"));
+
+        // The linked worktree is opened by name and the main one is updated with it
+        let updater =
+            Updater::open_worktree(&usage_dir, "other")?.other_worktrees(WorktreePolicy::Update);
+        assert!(updater.repository().is_worktree());
+
+        let outcome = updater.update()?;
+
+        assert!(outcome.is_updated());
+        assert!(other_dir.join("file.txt").exists());
+        assert!(usage_dir.join("file.txt").exists());
+        assert_eq!(
+            Repository::open(&usage_dir)?.refname_to_id("HEAD")?,
+            outcome.head_after
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_rejected_push_updates_other_worktrees() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "pr.txt")?;
+
+        let other_dir = fixture.base_dir.join("other");
+        git_cmd(&usage_dir)
+            .arg("worktree")
+            .arg("add")
+            .arg("--force")
+            .arg(&other_dir)
+            .arg("pr")
+            .status()?;
+
+        // A teammate pushes to pr, so the pull fast-forwards before the push is rejected
+        let mate_dir = fixture.get_repo_dir("mate")?;
+        git_cmd(&mate_dir).arg("checkout").arg("pr").status()?;
+        commit_file(&mate_dir, "mate.txt", "mate\n")?;
+        git_cmd(&mate_dir)
+            .arg("push")
+            .arg("origin")
+            .arg("pr")
+            .status()?;
+
+        let hook_path = usage_dir.join(".git").join("hooks").join("pre-push");
+        std::fs::write(&hook_path, "#!/bin/sh\nexit 1\n")?;
+        std::fs::set_permissions(&hook_path, std::fs::Permissions::from_mode(0o755))?;
+
+        let result = Updater::open(&usage_dir)?
+            .other_worktrees(WorktreePolicy::Update)
+            .update();
+
+        assert!(result.is_err());
+        assert!(usage_dir.join("mate.txt").exists());
+        assert!(!usage_dir.join("file.txt").exists());
+        assert!(other_dir.join("mate.txt").exists());
+
+        Ok(())
+    }
+
+    /// A `git daemon` serving the fixture, since libgit2 only supports shallow
+    /// fetches over the network
+    struct GitDaemon {
+        child: std::process::Child,
+        port: u16,
+    }
+
+    impl GitDaemon {
+        fn start(fixture: &TestFixture) -> Result<Self> {
+            let port = std::net::TcpListener::bind("127.0.0.1:0")?
+                .local_addr()?
+                .port();
+
+            // Run the daemon binary itself, `git daemon` would leave it orphaned when killed
+            let exec_path = Command::new("git").arg("--exec-path").output()?.stdout;
+            let exec_path = PathBuf::from(String::from_utf8_lossy(&exec_path).trim());
+
+            let child = Command::new(exec_path.join("git-daemon"))
+                .arg("--reuseaddr")
+                .arg("--export-all")
+                .arg("--enable=receive-pack")
+                .arg("--listen=127.0.0.1")
+                .arg(format!("--port={}", port))
+                .arg(format!("--base-path={}", fixture.base_dir.display()))
+                .spawn()?;
+
+            for _ in 0..50 {
+                if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+
+            Ok(Self { child, port })
+        }
+
+        fn url(&self) -> String {
+            format!("git://127.0.0.1:{}/remote", self.port)
+        }
+    }
+
+    impl Drop for GitDaemon {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+
+    #[test]
+    fn test_update_shallow() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        setup_pr(&fixture, "pr.txt")?;
+
+        let daemon = GitDaemon::start(&fixture)?;
+        let shallow_dir = fixture.base_dir.join("shallow");
+        Command::new("git")
+            .arg("clone")
+            .arg("--depth=1")
+            .arg("--branch=pr")
+            .arg(daemon.url())
+            .arg(&shallow_dir)
+            .status()?;
+
+        let recorder = Arc::new(Recorder::default());
+        let updater = Updater::open(&shallow_dir)?.reporter(recorder.clone());
+        assert!(updater.repository().is_shallow());
+
+        let outcome = updater.update()?;
+
+        assert!(matches!(outcome.merge, MergeOutcome::Merged(_)));
+        assert!(outcome.is_updated());
+        assert!(recorder
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|e| matches!(e, Event::Deepening { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_shallow_limit() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let usage_dir = setup_pr(&fixture, "pr.txt")?;
+
+        // Put the merge base more than 2 commits away from the tips
+        for i in 0..3 {
+            commit_file(&usage_dir, &format!("pr{}.txt", i), "pr\n")?;
+        }
+        git_cmd(&usage_dir)
+            .arg("push")
+            .arg("origin")
+            .arg("pr")
+            .status()?;
+
+        let daemon = GitDaemon::start(&fixture)?;
+        let shallow_dir = fixture.base_dir.join("shallow");
+        Command::new("git")
+            .arg("clone")
+            .arg("--depth=1")
+            .arg("--branch=pr")
+            .arg(daemon.url())
+            .arg(&shallow_dir)
+            .status()?;
+
+        let err = Updater::open(&shallow_dir)?
+            .deepen_limit(2)
+            .update()
+            .unwrap_err();
+
+        assert!(err
+            .message()
+            .contains("no merge base found within 2 commits"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_partial_clone() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        git_cmd(&fixture.remote_dir)
+            .args(["config", "uploadpack.allowFilter", "true"])
+            .status()?;
+        git_cmd(&fixture.remote_dir)
+            .args(["config", "uploadpack.allowAnySHA1InWant", "true"])
+            .status()?;
+
+        // main.txt is only on main, so its blob is missing from a blobless clone of pr
+        setup_pr(&fixture, "pr.txt")?;
+
+        let partial_dir = fixture.base_dir.join("partial");
+        Command::new("git")
+            .arg("clone")
+            .arg("--filter=blob:none")
+            .arg("--branch=pr")
+            .arg(format!("file://{}", fixture.remote_dir.display()))
+            .arg(&partial_dir)
+            .status()?;
+
+        let updater = Updater::open(&partial_dir)?;
+        assert_eq!(updater.promisor_remote(), Some("origin".to_string()));
+
+        let outcome = updater.update()?;
+
+        assert!(outcome.is_updated());
+        assert_eq!(
+            std::fs::read_to_string(partial_dir.join("file.txt"))?,
+            "main\n"
+        );
+
+        Ok(())
+    }
+}
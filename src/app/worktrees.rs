@@ -0,0 +1,116 @@
+use clap::ValueEnum;
+use git2::{build::CheckoutBuilder, ErrorClass, ErrorCode, Repository, StatusOptions};
+
+use crate::report::Event;
+
+use super::{repo::Repo, Updater};
+
+/// What to do when the updated branch is also checked out in another worktree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum WorktreePolicy {
+    /// Refuse to update the branch
+    #[default]
+    Refuse,
+    /// Update the files of the other worktrees as well, if they have no local changes
+    Update,
+}
+
+pub trait Worktrees: Repo {
+    /// What to do when the branch is checked out in another worktree
+    fn worktree_policy(&self) -> WorktreePolicy {
+        WorktreePolicy::default()
+    }
+
+    /// The other worktrees of the repository, main or linked, with `branch` checked out
+    fn worktrees_on_branch(&self, branch: &str) -> Result<Vec<Repository>, git2::Error> {
+        let repo = self.repo();
+        let main = Repository::open(repo.commondir())?;
+
+        let mut worktrees = Vec::new();
+        for name in main.worktrees()?.iter().flatten() {
+            let worktree = main.find_worktree(name)?;
+
+            // Worktrees whose directory was removed without `git worktree remove`
+            if worktree.validate().is_err() {
+                tracing::debug!(name, "skipping invalid worktree");
+                continue;
+            }
+            worktrees.push(Repository::open_from_worktree(&worktree)?);
+        }
+        if !main.is_bare() {
+            worktrees.push(main);
+        }
+
+        let own_path = repo.path().canonicalize().ok();
+        worktrees.retain(|worktree| {
+            worktree.path().canonicalize().ok() != own_path
+                && worktree
+                    .head()
+                    .is_ok_and(|head| head.is_branch() && head.name() == Some(branch))
+        });
+
+        Ok(worktrees)
+    }
+
+    /// Check whether `branch` can be updated given the other worktrees it is checked out in
+    ///
+    /// Returns the worktrees to update along with the branch, see [`Worktrees::update_worktrees`].
+    fn check_other_worktrees(&self, branch: &str) -> Result<Vec<Repository>, git2::Error> {
+        let worktrees = self.worktrees_on_branch(branch)?;
+
+        for worktree in &worktrees {
+            let path = worktree.workdir().unwrap_or_else(|| worktree.path());
+
+            match self.worktree_policy() {
+                WorktreePolicy::Refuse => {
+                    return Err(worktree_error(&format!(
+                        "{} is also checked out in the worktree at {}",
+                        branch,
+                        path.display()
+                    )));
+                }
+                WorktreePolicy::Update => {
+                    let mut opts = StatusOptions::new();
+                    opts.include_untracked(false).include_ignored(false);
+
+                    if !worktree.statuses(Some(&mut opts))?.is_empty() {
+                        return Err(worktree_error(&format!(
+                            "{} is also checked out in the worktree at {}, which has local changes",
+                            branch,
+                            path.display()
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(worktrees)
+    }
+
+    /// Check out the new commit of the branch in the other `worktrees` having it
+    fn update_worktrees(&self, worktrees: &[Repository]) -> Result<(), git2::Error> {
+        for worktree in worktrees {
+            let path = worktree.workdir().unwrap_or_else(|| worktree.path());
+            tracing::info!(path = %path.display(), "updating worktree");
+
+            // The worktrees were clean, only the moved branch differs from their files
+            worktree.checkout_head(Some(CheckoutBuilder::default().force()))?;
+
+            self.reporter().report(&Event::WorktreeUpdated {
+                path: path.to_path_buf(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Worktrees for Updater {
+    fn worktree_policy(&self) -> WorktreePolicy {
+        self.worktree_policy
+    }
+}
+
+fn worktree_error(message: &str) -> git2::Error {
+    git2::Error::new(ErrorCode::Locked, ErrorClass::Worktree, message)
+}
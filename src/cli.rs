@@ -1,15 +1,376 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{builder::RangedU64ValueParser, ArgAction, Args, Parser, Subcommand, ValueEnum};
 use humantime::Duration;
 
-#[derive(Parser)]
+use crate::{
+    app::{
+        ci::CiGate, fetch::TagPolicy, filters::Filter, lockfiles::Lockfile,
+        merge_options::MergeOption, submodules::GitlinkPolicy, worktrees::WorktreePolicy,
+    },
+    forge::{gitea::UpdateStyle, ForgeKind, MergeMethod},
+    logging::LogRotation,
+    progress::terminal::Verbosity,
+    webhooks::NotifyEvent,
+};
+
+#[derive(Parser, Clone)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
-    /// Optional working directory
-    pub working_dir: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Working directories of the repositories to update, the current one when none is given.
+    /// A directory named like a subcommand is given as ./daemon or after --
+    pub working_dirs: Vec<PathBuf>,
+
+    /// File listing repositories to update, one path per line relative to the file
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+
+    /// Update every git repository found under this directory
+    #[arg(long, value_name = "DIR")]
+    pub scan: Option<PathBuf>,
+
+    /// How many repositories to update at the same time
+    #[arg(short, long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jobs: usize,
 
     /// Delay between attempts.
     /// If this is not set, it will try only once.
     /// Examples: -d 10s -d 3m
     #[arg(short = 'd')]
     pub delay: Option<Duration>,
+
+    /// Update the linked worktree with this name instead of the working directory
+    #[arg(long)]
+    pub worktree: Option<String>,
+
+    /// What to do when the branch is also checked out in another worktree
+    #[arg(long, value_enum, default_value_t = WorktreePolicy::Refuse)]
+    pub other_worktrees: WorktreePolicy,
+
+    /// Remote to fetch from and push to
+    #[arg(long, default_value = "origin")]
+    pub remote: String,
+
+    /// Branch on the remote to merge into the current branch
+    #[arg(long, default_value = "main")]
+    pub base: String,
+
+    /// Tags to download, remote.<name>.tagOpt is followed when not set
+    #[arg(long, value_enum)]
+    pub tags: Option<TagPolicy>,
+
+    /// Remove remote-tracking branches deleted on the remote, like fetch.prune
+    #[arg(long, overrides_with = "no_prune")]
+    pub prune: bool,
+
+    /// Keep remote-tracking branches deleted on the remote, even when remote.<name>.prune or
+    /// fetch.prune is set
+    #[arg(long, overrides_with = "prune")]
+    pub no_prune: bool,
+
+    /// How many commits deep a shallow clone is fetched at most to find a merge base
+    #[arg(long, default_value_t = crate::app::fetch::DEFAULT_DEEPEN_LIMIT)]
+    pub deepen_limit: i32,
+
+    /// Initialize and update the submodules changed by the update, like `git submodule update --init`
+    #[arg(long)]
+    pub submodules: bool,
+
+    /// How to resolve submodule commits changed on both branches, they are left as conflicts when not set
+    #[arg(long, value_enum)]
+    pub gitlink_conflicts: Option<GitlinkPolicy>,
+
+    /// Record how conflicts are resolved and resolve them the same way when they come back, like
+    /// git rerere. rerere.enabled in the git config is followed when not set
+    #[arg(long)]
+    pub rerere: bool,
+
+    /// Merge with this option of git merge -X, for every file or the ones matching a pattern, e.g.
+    /// -X patience or -X '*.md:union'. Can be repeated and adds to update-pr.mergeOption in the git
+    /// config. Options: ours, theirs, union, patience, minimal, diff-algorithm=<algorithm>,
+    /// ignore-space-change, ignore-all-space, ignore-space-at-eol, find-renames[=<percent>], no-renames
+    #[arg(short = 'X', long, value_name = "[PATTERN:]OPTION")]
+    pub merge_option: Vec<MergeOption>,
+
+    /// Regenerate the files matching PATTERN with COMMAND when they are the only conflicts, e.g.
+    /// Cargo.lock='cargo update --workspace'. Can be repeated and adds to update-pr.lockfile in the
+    /// git config. The command runs in the directory of the lockfile
+    #[arg(long, value_name = "PATTERN=COMMAND")]
+    pub lockfile: Vec<Lockfile>,
+
+    /// Only fast-forward, never create a merge commit
+    #[arg(long)]
+    pub ff_only: bool,
+
+    /// Bypass the pre-commit, commit-msg and pre-push hooks
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Comment the conflicts on the pull request of the branch, and remove the comment once resolved.
+    /// The forge is taken from update-pr.forge in the git config or guessed from the url of the remote
+    /// when not given
+    #[arg(
+        long,
+        value_name = "FORGE",
+        num_args = 0..=1,
+        require_equals = true,
+        group = "forges"
+    )]
+    pub forge: Option<Option<ForgeKind>>,
+
+    /// Same as --forge=github
+    #[arg(long, hide = true, group = "forges")]
+    pub github: bool,
+
+    /// Token used to call the GitHub API
+    #[arg(long, env = "GITHUB_TOKEN", hide_env_values = true)]
+    pub github_token: Option<String>,
+
+    /// The GitHub API, https://<host of the remote>/api/v3 for GitHub Enterprise when not set
+    #[arg(long, value_name = "URL")]
+    pub github_api: Option<String>,
+
+    /// Token used to call the GitLab API
+    #[arg(long, env = "GITLAB_TOKEN", hide_env_values = true)]
+    pub gitlab_token: Option<String>,
+
+    /// The GitLab API, https://<host of the remote>/api/v4 when not set
+    #[arg(long, value_name = "URL")]
+    pub gitlab_api: Option<String>,
+
+    /// Token used to call the Gitea or Forgejo API
+    #[arg(long, env = "GITEA_TOKEN", hide_env_values = true)]
+    pub gitea_token: Option<String>,
+
+    /// The Gitea or Forgejo API, https://<host of the remote>/api/v1 when not set
+    #[arg(long, value_name = "URL")]
+    pub gitea_api: Option<String>,
+
+    /// How Gitea and Forgejo bring the base branch into the pull request with --server-side
+    #[arg(long, value_name = "STYLE", default_value = "merge")]
+    pub gitea_update_style: UpdateStyle,
+
+    /// Have the forge bring the base branch into the pull request instead of pushing a local merge,
+    /// merging locally when it reports conflicts. GitHub merges the base branch, GitLab rebases onto it.
+    /// This still runs in a local clone, which finds the pull request and pulls its new head
+    #[arg(long, requires = "forges", conflicts_with = "ff_only")]
+    pub server_side: bool,
+
+    /// Label put on the pull request while it has conflicts, e.g. needs-rebase
+    #[arg(long, value_name = "LABEL", requires = "forges")]
+    pub conflict_label: Option<String>,
+
+    /// Only merge commits of the base branch that passed their CI on the forge. When its tip did not,
+    /// either merge the most recent commit that did or skip the update
+    #[arg(long, value_name = "MODE", requires = "forges")]
+    pub ci_gate: Option<CiGate>,
+
+    /// How many commits of the base branch are checked at most with --ci-gate=walk-back
+    #[arg(long, value_name = "COMMITS", default_value_t = crate::app::ci::DEFAULT_WALK_LIMIT)]
+    pub ci_walk_limit: usize,
+
+    /// Only update the branch if its pull request matches this rule, can be repeated and adds to
+    /// update-pr.filter in the git config. Rules: draft, label:<names>, author:<logins>,
+    /// review:<approved|changes-requested|pending>, age>7d or age<30d, negated with a leading !
+    #[arg(long, value_name = "RULE", requires = "forges")]
+    pub filter: Vec<Filter>,
+
+    /// Once the branch is updated, wait for the checks of its pull request and merge it on the forge,
+    /// updating the branch again if the base branch moves meanwhile
+    #[arg(long, requires = "forges")]
+    pub auto_merge: bool,
+
+    /// How --auto-merge merges the pull request, GitLab only tells squash from the method of the project
+    #[arg(long, value_name = "METHOD", default_value = "merge")]
+    pub merge_method: MergeMethod,
+
+    /// How often --auto-merge reads the checks
+    #[arg(long, default_value = "30s")]
+    pub checks_interval: Duration,
+
+    /// How long --auto-merge waits for the checks to pass
+    #[arg(long, default_value = "30m")]
+    pub checks_timeout: Duration,
+
+    /// Post a JSON notification to this url after updates, can be repeated
+    #[arg(long, value_name = "URL")]
+    pub webhook: Vec<String>,
+
+    /// Post a notification to this Slack incoming webhook, can be repeated
+    #[arg(long, value_name = "URL")]
+    pub slack_webhook: Vec<String>,
+
+    /// Post a notification to this Microsoft Teams incoming webhook, can be repeated
+    #[arg(long, value_name = "URL")]
+    pub teams_webhook: Vec<String>,
+
+    /// Events notified to webhooks, all of them when not set
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub webhook_events: Vec<NotifyEvent>,
+
+    /// How long a webhook has to answer
+    #[arg(long, default_value = "10s")]
+    pub webhook_timeout: Duration,
+
+    /// How many times a failed webhook is tried again
+    #[arg(long, default_value_t = 3)]
+    pub webhook_retries: u32,
+
+    /// Only print conflicts and errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print more details, repeat for even more (-vv)
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// When to use colors, NO_COLOR is honored in auto mode
+    #[arg(long, value_enum, default_value_t = Color::Auto)]
+    pub color: Color,
+
+    /// Also write logs to this file, RUST_LOG sets the level
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// How often to rotate the log file, the last 7 rotated files are kept
+    #[arg(long, value_enum, default_value_t = LogRotation::Daily)]
+    pub log_rotation: LogRotation,
+}
+
+impl Cli {
+    /// Whether to prune, `None` following the git config
+    pub fn prune(&self) -> Option<bool> {
+        match (self.prune, self.no_prune) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        match (self.quiet, self.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::VeryVerbose,
+        }
+    }
+}
+
+#[derive(Subcommand, Clone)]
+pub enum Command {
+    /// Keep repositories up to date in the background, controlled with `update-pr ctl`
+    ///
+    /// The options given before the subcommand configure every update.
+    #[cfg(unix)]
+    Daemon(DaemonArgs),
+    /// Control a running daemon
+    #[cfg(unix)]
+    Ctl(CtlArgs),
+}
+
+#[derive(Args, Clone)]
+pub struct DaemonArgs {
+    /// Repositories to watch, in addition to the ones saved by a previous run
+    pub repos: Vec<PathBuf>,
+
+    /// Delay between two updates of the watched repositories
+    #[arg(long, default_value = "5m")]
+    pub interval: Duration,
+
+    /// Socket to listen on, $XDG_STATE_HOME/update-pr/daemon.sock by default
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+
+    /// File keeping the watched repositories, $XDG_STATE_HOME/update-pr/daemon.state by default
+    #[arg(long)]
+    pub state: Option<PathBuf>,
+}
+
+#[derive(Args, Clone)]
+pub struct CtlArgs {
+    /// Socket of the daemon, $XDG_STATE_HOME/update-pr/daemon.sock by default
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub request: CtlRequest,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum CtlRequest {
+    /// List the watched repositories and how their last update went
+    Status,
+    /// Watch a repository
+    Add { path: PathBuf },
+    /// Stop watching a repository
+    Remove { path: PathBuf },
+    /// Stop updating a repository, every repository when none is given
+    Pause { path: Option<PathBuf> },
+    /// Update a paused repository again, every repository when none is given
+    Resume { path: Option<PathBuf> },
+    /// Update a repository now, every repository when none is given
+    TriggerNow { path: Option<PathBuf> },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Color {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    /// Enable or disable the colors of the terminal output accordingly
+    pub fn apply(self) {
+        let enabled = match self {
+            Color::Auto => {
+                // An empty NO_COLOR does not disable colors, see https://no-color.org
+                if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+                    false
+                } else {
+                    return;
+                }
+            }
+            Color::Always => true,
+            Color::Never => false,
+        };
+
+        console::set_colors_enabled(enabled);
+        console::set_colors_enabled_stderr(enabled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directory_named_like_a_subcommand() {
+        let cli = Cli::try_parse_from(["update-pr", "./daemon"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.working_dirs, vec![PathBuf::from("./daemon")]);
+
+        let cli = Cli::try_parse_from(["update-pr", "--", "daemon"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.working_dirs, vec![PathBuf::from("daemon")]);
+
+        // The options given before the subcommand configure the daemon's updates
+        #[cfg(unix)]
+        {
+            let cli = Cli::try_parse_from(["update-pr", "--base", "develop", "daemon"]).unwrap();
+            assert!(matches!(cli.command, Some(Command::Daemon(_))));
+            assert_eq!(cli.base, "develop");
+        }
+    }
+
+    #[test]
+    fn test_filter_requires_a_forge() {
+        assert!(Cli::try_parse_from(["update-pr", "--filter", "draft"]).is_err());
+        assert!(Cli::try_parse_from(["update-pr", "--forge", "--filter", "draft"]).is_ok());
+    }
 }
@@ -0,0 +1,471 @@
+//! `update-pr daemon`, keeping watched repositories up to date in the background
+//!
+//! The daemon updates every watched repository at each interval. It is
+//! controlled through a Unix domain socket, see [`protocol`], which
+//! `update-pr ctl` talks to. The watched repositories and the HEAD they were
+//! left at are saved, so they survive restarts.
+
+pub mod protocol;
+mod state;
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use git2::Repository;
+
+use protocol::{read_response, write_response};
+pub use protocol::{Request, Response};
+use state::{State, Watch};
+
+use crate::{
+    app::{App, UpdateOutcome, Updater},
+    cli::{Cli, CtlArgs, CtlRequest, DaemonArgs},
+    logging,
+    progress::summary,
+    report::SilentReporter,
+    utils::git::redact_credentials,
+    webhooks::Webhooks,
+};
+
+/// Creates the updater of a watched repository before each update
+pub type UpdaterFactory = dyn Fn(&Path) -> Result<Updater, git2::Error> + Send + Sync;
+
+/// Updates the watched repositories at each interval and answers [`Request`]s
+pub struct Daemon {
+    socket: PathBuf,
+    interval: Duration,
+    shared: Arc<Shared>,
+    updater: Box<UpdaterFactory>,
+    webhooks: Webhooks,
+}
+
+/// What the scheduler shares with the control socket
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when an update may have become due
+    wake: Condvar,
+    state_path: PathBuf,
+}
+
+impl Daemon {
+    /// Create a daemon listening on `socket`, with the state saved at `state_path`
+    ///
+    /// * `interval` - How long to wait between two updates of a repository
+    /// * `updater` - Creates the updater of a repository
+    pub fn new<F>(
+        socket: PathBuf,
+        state_path: PathBuf,
+        interval: Duration,
+        updater: F,
+    ) -> io::Result<Self>
+    where
+        F: Fn(&Path) -> Result<Updater, git2::Error> + Send + Sync + 'static,
+    {
+        let state = State::load(&state_path)?;
+        tracing::info!(watches = state.watches.len(), "loaded state");
+
+        Ok(Self {
+            socket,
+            interval,
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                wake: Condvar::new(),
+                state_path,
+            }),
+            updater: Box::new(updater),
+            webhooks: Webhooks::new(),
+        })
+    }
+
+    /// Notify `webhooks` after each update
+    pub fn webhooks(mut self, webhooks: Webhooks) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Watch the repository at `path`, unless it already is
+    pub fn watch(&self, path: &Path) -> Result<(), String> {
+        let path = absolute(path);
+        if self.shared.state.lock().unwrap().find_mut(&path).is_some() {
+            return Ok(());
+        }
+
+        self.shared.apply(Request::Add(path)).map(|_| ())
+    }
+
+    /// Listen on the control socket and update the repositories, forever
+    pub fn run(self) -> io::Result<()> {
+        let listener = bind(&self.socket)?;
+        tracing::info!(socket = %self.socket.display(), "listening");
+
+        let shared = self.shared.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let handled = stream.and_then(|stream| shared.handle(stream));
+                if let Err(e) = handled {
+                    tracing::warn!("control connection failed: {}", e);
+                }
+            }
+        });
+
+        let mut next_cycle = Instant::now() + self.interval;
+        loop {
+            for path in self.wait_for_due(&mut next_cycle) {
+                let _span = tracing::info_span!("watch", path = %path.display()).entered();
+
+                let result = (self.updater)(&path).and_then(|updater| updater.update());
+                match &result {
+                    Ok(outcome) => tracing::info!(%outcome, "update finished"),
+                    Err(e) => tracing::error!(
+                        code = e.raw_code(),
+                        "update failed: {}",
+                        redact_credentials(e.message())
+                    ),
+                }
+
+                self.webhooks
+                    .notify_result(&path.display().to_string(), &result);
+
+                self.shared.record(&path, &result);
+            }
+        }
+    }
+
+    /// Wait until updates are due, either at the next cycle or when triggered
+    fn wait_for_due(&self, next_cycle: &mut Instant) -> Vec<PathBuf> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        loop {
+            let now = Instant::now();
+            if now >= *next_cycle {
+                for watch in &mut state.watches {
+                    watch.due = true;
+                }
+                *next_cycle = now + self.interval;
+            }
+
+            let due: Vec<PathBuf> = state
+                .watches
+                .iter_mut()
+                .filter(|watch| watch.due && !watch.paused)
+                .map(|watch| {
+                    watch.due = false;
+                    watch.path.clone()
+                })
+                .collect();
+            if !due.is_empty() {
+                return due;
+            }
+
+            state = self
+                .shared
+                .wake
+                .wait_timeout(state, *next_cycle - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl Shared {
+    /// Answer the single request of a control connection
+    fn handle(&self, stream: UnixStream) -> io::Result<()> {
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+
+        let response = Request::parse(&line).and_then(|request| {
+            tracing::info!(%request, "control request");
+            self.apply(request)
+        });
+
+        write_response(&stream, &response)
+    }
+
+    fn apply(&self, request: Request) -> Response {
+        let mut state = self.state.lock().unwrap();
+
+        let response = match request {
+            Request::Status => return Ok(state.watches.iter().map(describe).collect()),
+            Request::Add(path) => {
+                let path = path.canonicalize().map_err(|e| e.to_string())?;
+                Repository::open(&path).map_err(|e| e.message().to_string())?;
+
+                if state.find_mut(&path).is_some() {
+                    return Err(format!("{} is already watched", path.display()));
+                }
+                state.watches.push(Watch::new(path));
+
+                Ok(vec![])
+            }
+            Request::Remove(path) => {
+                let path = absolute(&path);
+                let count = state.watches.len();
+                state.watches.retain(|watch| watch.path != path);
+
+                if state.watches.len() == count {
+                    return Err(format!("{} is not watched", path.display()));
+                }
+
+                Ok(vec![])
+            }
+            Request::Pause(path) => for_each(&mut state, path, |watch| watch.paused = true),
+            Request::Resume(path) => for_each(&mut state, path, |watch| watch.paused = false),
+            Request::TriggerNow(path) => for_each(&mut state, path, |watch| watch.due = true),
+        };
+
+        state.save(&self.state_path).map_err(|e| e.to_string())?;
+        self.wake.notify_all();
+
+        response
+    }
+
+    /// Keep the result of updating the repository at `path`
+    fn record(&self, path: &Path, result: &Result<UpdateOutcome, git2::Error>) {
+        let mut state = self.state.lock().unwrap();
+
+        // The repository may have been removed during the update
+        let Some(watch) = state.find_mut(path) else {
+            return;
+        };
+
+        let (status, details) = summary::status(result);
+        watch.last_result = Some(format!("{} {}", status, details).trim_end().to_string());
+        watch.last_run = Some(SystemTime::now());
+        if let Ok(outcome) = result {
+            watch.last_head = Some(outcome.head_after);
+        }
+
+        if let Err(e) = state.save(&self.state_path) {
+            tracing::error!("could not save the state: {}", e);
+        }
+    }
+}
+
+/// Apply `f` to the watch of `path`, or to every watch
+fn for_each(state: &mut State, path: Option<PathBuf>, f: impl Fn(&mut Watch)) -> Response {
+    match path {
+        Some(path) => {
+            let path = absolute(&path);
+            let watch = state
+                .find_mut(&path)
+                .ok_or_else(|| format!("{} is not watched", path.display()))?;
+            f(watch);
+        }
+        None => state.watches.iter_mut().for_each(f),
+    }
+
+    Ok(vec![])
+}
+
+/// The status line of a watched repository
+fn describe(watch: &Watch) -> String {
+    let head = watch
+        .last_head
+        .map_or_else(|| "-".to_string(), |oid| oid.to_string()[..7].to_string());
+    let last_run = watch
+        .last_run
+        .and_then(|time| time.elapsed().ok())
+        .map_or_else(
+            || "never".to_string(),
+            |elapsed| {
+                let elapsed = Duration::from_secs(elapsed.as_secs());
+                format!("{} ago", humantime::format_duration(elapsed))
+            },
+        );
+
+    format!(
+        "{}  {}  {}  {}  {}",
+        watch.path.display(),
+        if watch.paused { "paused" } else { "active" },
+        head,
+        last_run,
+        watch.last_result.as_deref().unwrap_or("")
+    )
+    .trim_end()
+    .to_string()
+}
+
+/// Listen on `socket`, replacing the one left behind by a daemon that did not stop cleanly
+fn bind(socket: &Path) -> io::Result<UnixListener> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("a daemon is already listening on {}", socket.display()),
+            ));
+        }
+        fs::remove_file(socket)?;
+    }
+    if let Some(dir) = socket.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    UnixListener::bind(socket)
+}
+
+/// Send `request` to the daemon listening on `socket` and read its answer
+pub fn send(socket: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(socket)?;
+
+    writeln!(stream, "{}", request)?;
+    stream.shutdown(Shutdown::Write)?;
+
+    read_response(BufReader::new(stream))
+}
+
+/// Where the daemon keeps its socket and state by default, following the XDG base directories
+pub fn state_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir).join("update-pr"));
+    }
+
+    let home = std::env::var_os("HOME").ok_or_else(|| eyre!("HOME is not set"))?;
+
+    Ok(PathBuf::from(home).join(".local/state/update-pr"))
+}
+
+/// Run `update-pr daemon`, updating repositories as configured by `cli`
+pub fn run(cli: Cli, args: DaemonArgs) -> Result<i32> {
+    let _log_guard = logging::init(cli.verbosity(), cli.log_file.as_deref(), cli.log_rotation)?;
+
+    let dir = state_dir()?;
+    let socket = args.socket.unwrap_or_else(|| dir.join("daemon.sock"));
+    let state_path = args.state.unwrap_or_else(|| dir.join("daemon.state"));
+
+    let webhooks = App::webhooks(&cli);
+    let daemon = Daemon::new(
+        socket.clone(),
+        state_path.clone(),
+        args.interval.into(),
+        move |path| App::updater(&cli, path, SilentReporter),
+    )
+    .wrap_err_with(|| format!("could not load {}", state_path.display()))?
+    .webhooks(webhooks);
+
+    for repo in &args.repos {
+        daemon
+            .watch(repo)
+            .map_err(|e| eyre!("could not watch {}: {}", repo.display(), e))?;
+    }
+
+    println!("Listening on {}", socket.display());
+    daemon
+        .run()
+        .wrap_err_with(|| format!("could not listen on {}", socket.display()))?;
+
+    Ok(0)
+}
+
+/// Run `update-pr ctl`, sending a request to the daemon and printing its answer
+pub fn ctl(args: CtlArgs) -> Result<i32> {
+    let socket = match args.socket {
+        Some(socket) => socket,
+        None => state_dir()?.join("daemon.sock"),
+    };
+
+    // Paths are sent absolute, the daemon runs from another directory
+    let request = match args.request {
+        CtlRequest::Status => Request::Status,
+        CtlRequest::Add { path } => Request::Add(absolute(&path)),
+        CtlRequest::Remove { path } => Request::Remove(absolute(&path)),
+        CtlRequest::Pause { path } => Request::Pause(path.as_deref().map(absolute)),
+        CtlRequest::Resume { path } => Request::Resume(path.as_deref().map(absolute)),
+        CtlRequest::TriggerNow { path } => Request::TriggerNow(path.as_deref().map(absolute)),
+    };
+
+    let response = send(&socket, &request)
+        .wrap_err_with(|| format!("could not reach the daemon at {}", socket.display()))?;
+
+    match response {
+        Ok(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+
+            Ok(0)
+        }
+        Err(message) => {
+            eprintln!("Error: {}", message);
+
+            Ok(1)
+        }
+    }
+}
+
+/// `path` made absolute, resolving symbolic links when it exists
+fn absolute(path: &Path) -> PathBuf {
+    path.canonicalize()
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use color_eyre::eyre::Result;
+
+    use super::*;
+    use crate::app::fixture::{setup_pr, TestFixture};
+
+    /// Ask for the status until `done` accepts it
+    fn wait_status(socket: &Path, done: impl Fn(&[String]) -> bool) -> Result<Vec<String>> {
+        for _ in 0..100 {
+            if let Ok(Ok(lines)) = send(socket, &Request::Status) {
+                if done(&lines) {
+                    return Ok(lines);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        Err(eyre!("the daemon did not answer as expected"))
+    }
+
+    #[test]
+    fn test_daemon() -> Result<()> {
+        let fixture = TestFixture::new()?.setup()?;
+        let base_dir = &fixture.base_dir;
+        let usage_dir = setup_pr(&fixture, "pr.txt")?;
+        let socket = base_dir.join("run/daemon.sock");
+        let state_path = base_dir.join("daemon.state");
+
+        let daemon = Daemon::new(
+            socket.clone(),
+            state_path.clone(),
+            Duration::from_secs(3600),
+            |path| Updater::open(path),
+        )?;
+        std::thread::spawn(move || daemon.run());
+
+        wait_status(&socket, |_| true)?;
+        assert_eq!(send(&socket, &Request::Add(usage_dir.clone()))?, Ok(vec![]));
+        assert!(send(&socket, &Request::Add(usage_dir.clone()))?.is_err());
+
+        let lines = wait_status(&socket, |lines| lines[0].contains("updated"))?;
+        assert!(lines[0].starts_with(&format!("{}  active", usage_dir.canonicalize()?.display())));
+
+        // The HEAD after the update is kept for the next run
+        let head = Repository::open(&usage_dir)?.head()?.peel_to_commit()?.id();
+        let state = State::load(&state_path)?;
+        assert_eq!(state.watches[0].last_head, Some(head));
+
+        assert_eq!(send(&socket, &Request::Pause(None))?, Ok(vec![]));
+        assert_eq!(send(&socket, &Request::TriggerNow(None))?, Ok(vec![]));
+        wait_status(&socket, |lines| lines[0].contains("paused"))?;
+        assert!(State::load(&state_path)?.watches[0].paused);
+
+        assert_eq!(send(&socket, &Request::Remove(usage_dir))?, Ok(vec![]));
+        assert_eq!(send(&socket, &Request::Status)?, Ok(vec![]));
+
+        Ok(())
+    }
+}
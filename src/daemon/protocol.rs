@@ -0,0 +1,141 @@
+//! The line based protocol spoken over the control socket
+//!
+//! A client sends one request per connection, as a single line such as
+//! `add /path/to/repo`. The daemon answers with `ok` followed by the lines of
+//! the answer, or with `error <message>`, then closes the connection.
+
+use std::{
+    fmt::Display,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+/// A command sent to the daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// List the watched repositories and how their last update went
+    Status,
+    /// Watch a repository
+    Add(PathBuf),
+    /// Stop watching a repository
+    Remove(PathBuf),
+    /// Stop updating a repository, or every repository
+    Pause(Option<PathBuf>),
+    /// Update a paused repository again, or every repository
+    Resume(Option<PathBuf>),
+    /// Update a repository, or every repository, without waiting for the next interval
+    TriggerNow(Option<PathBuf>),
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (command, arg) = match line.split_once(' ') {
+            Some((command, arg)) => (command, Some(PathBuf::from(arg))),
+            None => (line, None),
+        };
+
+        let required = |arg: Option<PathBuf>| arg.ok_or(format!("{} needs a path", command));
+
+        match command {
+            "status" => Ok(Self::Status),
+            "add" => Ok(Self::Add(required(arg)?)),
+            "remove" => Ok(Self::Remove(required(arg)?)),
+            "pause" => Ok(Self::Pause(arg)),
+            "resume" => Ok(Self::Resume(arg)),
+            "trigger-now" => Ok(Self::TriggerNow(arg)),
+            _ => Err(format!("unknown command {:?}", command)),
+        }
+    }
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (command, path) = match self {
+            Self::Status => ("status", None),
+            Self::Add(path) => ("add", Some(path)),
+            Self::Remove(path) => ("remove", Some(path)),
+            Self::Pause(path) => ("pause", path.as_ref()),
+            Self::Resume(path) => ("resume", path.as_ref()),
+            Self::TriggerNow(path) => ("trigger-now", path.as_ref()),
+        };
+
+        match path {
+            Some(path) => write!(f, "{} {}", command, path.display()),
+            None => write!(f, "{}", command),
+        }
+    }
+}
+
+/// The answer to a [`Request`], its lines or an error message
+pub type Response = Result<Vec<String>, String>;
+
+pub fn write_response<W: Write>(mut writer: W, response: &Response) -> io::Result<()> {
+    match response {
+        Ok(lines) => {
+            writeln!(writer, "ok")?;
+            for line in lines {
+                writeln!(writer, "{}", line)?;
+            }
+        }
+        Err(message) => writeln!(writer, "error {}", message)?,
+    }
+
+    writer.flush()
+}
+
+pub fn read_response<R: BufRead>(reader: R) -> io::Result<Response> {
+    let mut lines = reader.lines();
+
+    let status = lines.next().transpose()?.unwrap_or_default();
+    if status == "ok" {
+        Ok(Ok(lines.collect::<io::Result<_>>()?))
+    } else if let Some(message) = status.strip_prefix("error ") {
+        Ok(Err(message.to_string()))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected answer from the daemon: {:?}", status),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_round_trip() {
+        let requests = [
+            Request::Status,
+            Request::Add(PathBuf::from("/srv/my repo")),
+            Request::Remove(PathBuf::from("/srv/api")),
+            Request::Pause(None),
+            Request::Resume(Some(PathBuf::from("/srv/api"))),
+            Request::TriggerNow(None),
+        ];
+
+        for request in requests {
+            assert_eq!(Request::parse(&format!("{}\n", request)), Ok(request));
+        }
+
+        assert!(Request::parse("add").is_err());
+        assert!(Request::parse("restart").is_err());
+    }
+
+    #[test]
+    fn test_response_round_trip() -> io::Result<()> {
+        for response in [
+            Ok(vec!["a".to_string(), "b".to_string()]),
+            Ok(vec![]),
+            Err("not watched".to_string()),
+        ] {
+            let mut buffer = Vec::new();
+            write_response(&mut buffer, &response)?;
+
+            assert_eq!(read_response(&buffer[..])?, response);
+        }
+
+        Ok(())
+    }
+}
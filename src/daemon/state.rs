@@ -0,0 +1,156 @@
+//! The repositories watched by the daemon, saved across restarts
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use git2::Oid;
+
+/// A repository watched by the daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    pub path: PathBuf,
+    pub paused: bool,
+    /// HEAD after the last update, if any
+    pub last_head: Option<Oid>,
+    /// How the last update since the daemon started went
+    pub last_result: Option<String>,
+    pub last_run: Option<SystemTime>,
+    /// Whether an update is waiting to run
+    pub due: bool,
+}
+
+impl Watch {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            paused: false,
+            last_head: None,
+            last_result: None,
+            last_run: None,
+            due: true,
+        }
+    }
+}
+
+/// The watched repositories
+///
+/// They are saved one per line as `<active|paused> <last head|-> <path>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct State {
+    pub watches: Vec<Watch>,
+}
+
+impl State {
+    /// Load the state saved at `path`, empty if there is none yet
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+
+        let watches = content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let invalid = || {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid line in {}: {:?}", path.display(), line),
+                    )
+                };
+
+                let mut fields = line.splitn(3, ' ');
+                let (Some(state), Some(head), Some(repo)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(invalid());
+                };
+
+                let mut watch = Watch::new(PathBuf::from(repo));
+                watch.paused = match state {
+                    "active" => false,
+                    "paused" => true,
+                    _ => return Err(invalid()),
+                };
+                watch.last_head = match head {
+                    "-" => None,
+                    oid => Some(Oid::from_str(oid).map_err(|_| invalid())?),
+                };
+
+                Ok(watch)
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self { watches })
+    }
+
+    /// Save the state to `path`, replacing the previous one at once
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let content: String = self
+            .watches
+            .iter()
+            .map(|watch| {
+                format!(
+                    "{} {} {}\n",
+                    if watch.paused { "paused" } else { "active" },
+                    watch
+                        .last_head
+                        .map_or_else(|| "-".to_string(), |oid| oid.to_string()),
+                    watch.path.display()
+                )
+            })
+            .collect();
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn find_mut(&mut self, path: &Path) -> Option<&mut Watch> {
+        self.watches.iter_mut().find(|watch| watch.path == path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::Result;
+    use testdir::testdir;
+
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let path = testdir!().join("state/daemon.state");
+
+        assert_eq!(State::load(&path)?, State::default());
+
+        let mut paused = Watch::new(PathBuf::from("/srv/my repo"));
+        paused.paused = true;
+        paused.last_head = Some(Oid::from_str("3f2a1b2c3d4e5f60718293a4b5c6d7e8f9012345")?);
+        paused.last_result = Some("updated".to_string());
+        let state = State {
+            watches: vec![Watch::new(PathBuf::from("/srv/api")), paused],
+        };
+
+        state.save(&path)?;
+        let loaded = State::load(&path)?;
+
+        assert_eq!(loaded.watches.len(), 2);
+        assert_eq!(loaded.watches[0], state.watches[0]);
+        assert_eq!(loaded.watches[1].path, PathBuf::from("/srv/my repo"));
+        assert!(loaded.watches[1].paused);
+        assert_eq!(loaded.watches[1].last_head, state.watches[1].last_head);
+        // Results are only kept while the daemon runs
+        assert_eq!(loaded.watches[1].last_result, None);
+
+        Ok(())
+    }
+}
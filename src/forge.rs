@@ -0,0 +1,487 @@
+//! Pull requests on the forge hosting the remote, e.g. GitHub, GitLab or Gitea
+//!
+//! Each forge implements [`Forge`] by speaking its own API. What is done with
+//! it during an update lives in [`crate::app::pull_request`].
+
+pub mod gitea;
+pub mod github;
+pub mod gitlab;
+
+#[cfg(test)]
+pub(crate) mod fake;
+
+use std::{fmt::Display, time::SystemTime};
+
+use clap::ValueEnum;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+pub use gitea::Gitea;
+pub use github::GitHub;
+pub use gitlab::GitLab;
+
+/// The kinds of forges known to update-pr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ForgeKind {
+    #[value(name = "github")]
+    GitHub,
+    #[value(name = "gitlab")]
+    GitLab,
+    /// Gitea or Forgejo
+    #[value(name = "gitea", alias = "forgejo")]
+    Gitea,
+}
+
+impl ForgeKind {
+    /// The forge a remote url points to, guessed from its host
+    pub fn detect(url: &str) -> Option<Self> {
+        let host = host(url)?.to_lowercase();
+
+        if host.contains("github") {
+            Some(Self::GitHub)
+        } else if host.contains("gitlab") {
+            Some(Self::GitLab)
+        } else if ["gitea", "forgejo", "codeberg"]
+            .iter()
+            .any(|name| host.contains(name))
+        {
+            Some(Self::Gitea)
+        } else {
+            None
+        }
+    }
+}
+
+/// The host of a remote url, with `https://`, `ssh://` or scp-like syntax
+pub fn host(url: &str) -> Option<&str> {
+    let authority = match url.split_once("://") {
+        Some((_, rest)) => rest.split('/').next()?,
+        None => url.split_once(':')?.0,
+    };
+    let host = authority.rsplit('@').next()?;
+    // Without the port, nor the brackets of an IPv6 address
+    let host = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+
+    (!host.is_empty()).then_some(host)
+}
+
+/// A repository on a forge, e.g. `octo-org/api`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoId {
+    /// The user or organization owning the repository, with its subgroups on GitLab
+    pub owner: String,
+    pub name: String,
+}
+
+impl RepoId {
+    /// The repository a remote url points to, from the components of its path
+    ///
+    /// Works with `https://host/owner/name.git`, `ssh://git@host/owner/name`
+    /// and `git@host:owner/name.git` urls. The owner takes every component but
+    /// the last, for GitLab subgroups.
+    pub fn from_url(url: &str) -> Option<Self> {
+        let path = match url.split_once("://") {
+            Some((_, rest)) => rest.split_once('/')?.1,
+            // scp-like syntax
+            None => url.split_once(':').map_or(url, |(_, path)| path),
+        };
+
+        let path = path.trim_matches('/').trim_end_matches(".git");
+        let (owner, name) = path.rsplit_once('/')?;
+        if owner.is_empty() || name.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            owner: owner.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+impl Display for RepoId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.owner, self.name)
+    }
+}
+
+/// Whether a pull request can still be merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PullRequestState {
+    Open,
+    Closed,
+    Merged,
+}
+
+/// A pull request, or merge request on GitLab
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PullRequest {
+    /// The number of the pull request in its repository, the `iid` on GitLab
+    pub number: u64,
+    /// The page of the pull request
+    pub url: String,
+    /// The branch it is merged into
+    pub base: String,
+    /// The last commit of its branch
+    pub head_sha: String,
+    pub state: PullRequestState,
+    pub draft: bool,
+    /// The login of the user who opened it
+    pub author: String,
+    pub labels: Vec<String>,
+    pub created_at: Option<SystemTime>,
+}
+
+/// Where the reviews of a pull request stand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReviewDecision {
+    /// Approved, and no reviewer requests changes
+    Approved,
+    /// A reviewer requests changes
+    ChangesRequested,
+    /// Neither approved nor refused yet
+    Pending,
+}
+
+impl ReviewDecision {
+    /// The decision from reviews given as `(reviewer, state)` in chronological order, as named by GitHub and Gitea
+    ///
+    /// The last approval, refusal or dismissal of each reviewer counts, comments don't.
+    pub(crate) fn from_reviews<'a>(reviews: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut last = std::collections::HashMap::new();
+        for (reviewer, state) in reviews {
+            match state {
+                "APPROVED" | "CHANGES_REQUESTED" | "REQUEST_CHANGES" | "DISMISSED" => {
+                    last.insert(reviewer, state);
+                }
+                _ => {}
+            }
+        }
+
+        if last
+            .values()
+            .any(|state| matches!(*state, "CHANGES_REQUESTED" | "REQUEST_CHANGES"))
+        {
+            Self::ChangesRequested
+        } else if last.values().any(|state| *state == "APPROVED") {
+            Self::Approved
+        } else {
+            Self::Pending
+        }
+    }
+}
+
+/// How a pull request is merged into its base branch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MergeMethod {
+    /// A merge commit
+    #[default]
+    Merge,
+    /// A single commit with every change of the pull request
+    Squash,
+    /// The commits of the pull request rebased onto the base branch
+    Rebase,
+}
+
+/// How the forge brought the base branch into a pull request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BranchUpdate {
+    /// The branch already contains the base branch
+    UpToDate,
+    /// The base branch cannot be brought in without conflicts
+    Conflict,
+    /// The base branch was merged, the branch is now at this commit
+    Merged(String),
+    /// The branch was rebased onto the base branch, it is now at this commit
+    Rebased(String),
+    /// The forge cannot update pull requests, e.g. an older version of it
+    Unsupported,
+}
+
+/// The outcome of the CI of a commit, from its statuses and checks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CiStatus {
+    /// Every check passed
+    Success,
+    /// Some checks are still running, none failed so far
+    Pending,
+    /// A check failed or was cancelled
+    Failure,
+    /// No check reported on the commit
+    Missing,
+}
+
+impl CiStatus {
+    /// The status of a commit with several sources of checks, e.g. statuses and check runs on GitHub
+    pub fn combine(self, other: Self) -> Self {
+        use CiStatus::*;
+
+        match (self, other) {
+            (Failure, _) | (_, Failure) => Failure,
+            (Pending, _) | (_, Pending) => Pending,
+            (Success, _) | (_, Success) => Success,
+            (Missing, Missing) => Missing,
+        }
+    }
+}
+
+impl Display for CiStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Success => write!(f, "passed"),
+            Self::Pending => write!(f, "pending"),
+            Self::Failure => write!(f, "failed"),
+            Self::Missing => write!(f, "no checks"),
+        }
+    }
+}
+
+/// The API of a forge hosting pull requests
+///
+/// Pull requests are identified by their number in `repo`. Failing calls
+/// return a [`ForgeError`] with the HTTP status when there was one.
+pub trait Forge: Send + Sync {
+    /// The name of the forge, as shown to users
+    fn name(&self) -> &'static str;
+
+    /// The open pull request of `branch`, pushed to `repo`
+    fn find_pull_request(
+        &self,
+        repo: &RepoId,
+        branch: &str,
+    ) -> Result<Option<PullRequest>, ForgeError>;
+
+    fn pull_request(&self, repo: &RepoId, number: u64) -> Result<PullRequest, ForgeError>;
+
+    /// Have the forge bring the base branch into `pr`, waiting until it is done
+    ///
+    /// Nothing is done if the branch moved since `pr` was read.
+    fn update_branch(&self, repo: &RepoId, pr: &PullRequest) -> Result<BranchUpdate, ForgeError>;
+
+    /// The CI status of the commit `sha`
+    fn ci_status(&self, repo: &RepoId, sha: &str) -> Result<CiStatus, ForgeError>;
+
+    fn review_decision(&self, repo: &RepoId, number: u64) -> Result<ReviewDecision, ForgeError>;
+
+    /// Merge `pr` into its base branch, only if its head is still `pr.head_sha`
+    fn merge_pull_request(
+        &self,
+        repo: &RepoId,
+        pr: &PullRequest,
+        method: MergeMethod,
+    ) -> Result<(), ForgeError>;
+
+    /// The login of the owner of the token
+    fn current_user(&self) -> Result<String, ForgeError>;
+
+    /// Every comment of the pull request `number`, from all the pages of them
+    fn comments(&self, repo: &RepoId, number: u64) -> Result<Vec<Comment>, ForgeError>;
+
+    fn create_comment(&self, repo: &RepoId, number: u64, body: &str)
+        -> Result<Comment, ForgeError>;
+
+    fn update_comment(
+        &self,
+        repo: &RepoId,
+        number: u64,
+        id: u64,
+        body: &str,
+    ) -> Result<(), ForgeError>;
+
+    fn delete_comment(&self, repo: &RepoId, number: u64, id: u64) -> Result<(), ForgeError>;
+
+    fn add_label(&self, repo: &RepoId, number: u64, label: &str) -> Result<(), ForgeError>;
+
+    /// Remove `label` from the pull request `number`, if it has it
+    fn remove_label(&self, repo: &RepoId, number: u64, label: &str) -> Result<(), ForgeError>;
+}
+
+/// A comment on a pull request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    pub id: u64,
+    pub body: String,
+}
+
+/// A failed call to the API of a forge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForgeError {
+    /// The HTTP status of the answer, if there was one
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl ForgeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            status: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status == Some(404)
+    }
+}
+
+impl Display for ForgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{} ({})", self.message, status),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ForgeError {}
+
+impl From<ureq::Error> for ForgeError {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(status, response) => {
+                // The APIs explain errors in a `message` field
+                let message = response
+                    .into_json::<serde_json::Value>()
+                    .ok()
+                    .and_then(|body| body["message"].as_str().map(str::to_string))
+                    .unwrap_or_else(|| "the request failed".to_string());
+
+                Self {
+                    status: Some(status),
+                    message,
+                }
+            }
+            ureq::Error::Transport(e) => Self::new(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for ForgeError {
+    fn from(e: std::io::Error) -> Self {
+        Self::new(format!("invalid answer: {}", e))
+    }
+}
+
+/// An RFC 3339 timestamp, as found in the answers of the APIs
+pub(crate) fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    OffsetDateTime::parse(timestamp, &Rfc3339)
+        .ok()
+        .map(SystemTime::from)
+}
+
+/// `segment` escaped to be used as one component of an url path
+pub(crate) fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repo_from_url() {
+        let api = Some(RepoId {
+            owner: "octo-org".to_string(),
+            name: "api".to_string(),
+        });
+
+        assert_eq!(RepoId::from_url("https://github.com/octo-org/api.git"), api);
+        assert_eq!(
+            RepoId::from_url("https://x-access-token:t@github.com/octo-org/api"),
+            api
+        );
+        assert_eq!(RepoId::from_url("ssh://git@github.com/octo-org/api/"), api);
+        assert_eq!(RepoId::from_url("git@github.com:octo-org/api.git"), api);
+        assert_eq!(RepoId::from_url("https://github.com/api"), None);
+    }
+
+    #[test]
+    fn test_encode_path_segment() {
+        assert_eq!(
+            encode_path_segment("needs rebase/ü"),
+            "needs%20rebase%2F%C3%BC"
+        );
+        assert_eq!(encode_path_segment("v1.2_rc~1"), "v1.2_rc~1");
+    }
+
+    #[test]
+    fn test_review_decision() {
+        assert_eq!(
+            ReviewDecision::from_reviews([("ann", "APPROVED"), ("ann", "COMMENTED")]),
+            ReviewDecision::Approved
+        );
+        assert_eq!(
+            ReviewDecision::from_reviews([("ann", "APPROVED"), ("bob", "REQUEST_CHANGES")]),
+            ReviewDecision::ChangesRequested
+        );
+        assert_eq!(
+            ReviewDecision::from_reviews([("bob", "CHANGES_REQUESTED"), ("bob", "APPROVED")]),
+            ReviewDecision::Approved
+        );
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(
+            parse_timestamp("2017-03-21T13:24:51+08:00"),
+            parse_timestamp("2017-03-21T05:24:51.000Z")
+        );
+        assert!(parse_timestamp("yesterday").is_none());
+    }
+
+    #[test]
+    fn test_ci_status_combine() {
+        assert_eq!(
+            CiStatus::Success.combine(CiStatus::Missing),
+            CiStatus::Success
+        );
+        assert_eq!(
+            CiStatus::Success.combine(CiStatus::Pending),
+            CiStatus::Pending
+        );
+        assert_eq!(
+            CiStatus::Pending.combine(CiStatus::Failure),
+            CiStatus::Failure
+        );
+        assert_eq!(
+            CiStatus::Missing.combine(CiStatus::Missing),
+            CiStatus::Missing
+        );
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            ForgeKind::detect("git@github.com:octo-org/api.git"),
+            Some(ForgeKind::GitHub)
+        );
+        assert_eq!(
+            ForgeKind::detect("ssh://git@gitlab.example.com:2222/group/api"),
+            Some(ForgeKind::GitLab)
+        );
+        assert_eq!(ForgeKind::detect("/srv/git/api.git"), None);
+    }
+
+    #[test]
+    fn test_host() {
+        assert_eq!(
+            host("https://token@gitlab.example.com:8443/group/api"),
+            Some("gitlab.example.com")
+        );
+        assert_eq!(host("https://[::1]:3000/org/api"), Some("::1"));
+        assert_eq!(host("/srv/git/api.git"), None);
+    }
+}
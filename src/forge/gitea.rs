@@ -0,0 +1,561 @@
+//! A client of the Gitea REST API, also spoken by Forgejo, see <https://gitea.com/api/swagger>
+
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{
+    parse_timestamp, BranchUpdate, CiStatus, Comment, Forge, ForgeError, MergeMethod, PullRequest,
+    PullRequestState, RepoId, ReviewDecision,
+};
+
+/// The API of codeberg.org, the largest Forgejo instance
+pub const DEFAULT_API_URL: &str = "https://codeberg.org/api/v1";
+
+/// How Gitea brings the base branch into a pull request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum UpdateStyle {
+    /// Merge the base branch into the pull request
+    #[default]
+    Merge,
+    /// Rebase the pull request onto the base branch
+    Rebase,
+}
+
+/// Talks to the API of a Gitea or Forgejo instance with a token
+pub struct Gitea {
+    api_url: String,
+    token: String,
+    agent: ureq::Agent,
+    style: UpdateStyle,
+    poll_interval: Duration,
+    poll_timeout: Duration,
+}
+
+#[derive(Deserialize)]
+struct ApiPullRequest {
+    number: u64,
+    html_url: String,
+    state: String,
+    #[serde(default)]
+    merged: bool,
+    #[serde(default)]
+    draft: bool,
+    /// The merge base of the head and base branches, missing before Gitea 1.13
+    merge_base: Option<String>,
+    user: Option<ApiUser>,
+    #[serde(default)]
+    labels: Vec<ApiLabel>,
+    created_at: Option<String>,
+    head: ApiBranch,
+    base: ApiBranch,
+}
+
+#[derive(Deserialize)]
+struct ApiUser {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct ApiReview {
+    user: Option<ApiUser>,
+    state: String,
+}
+
+#[derive(Deserialize)]
+struct ApiBranch {
+    #[serde(rename = "ref")]
+    name: String,
+    sha: String,
+    /// The repository of the branch, missing once it is deleted
+    repo: Option<ApiRepo>,
+}
+
+#[derive(Deserialize)]
+struct ApiRepo {
+    full_name: String,
+}
+
+impl From<ApiPullRequest> for PullRequest {
+    fn from(pr: ApiPullRequest) -> Self {
+        let state = match (pr.state.as_str(), pr.merged) {
+            (_, true) => PullRequestState::Merged,
+            ("open", false) => PullRequestState::Open,
+            _ => PullRequestState::Closed,
+        };
+
+        Self {
+            number: pr.number,
+            url: pr.html_url,
+            base: pr.base.name,
+            head_sha: pr.head.sha,
+            state,
+            draft: pr.draft,
+            author: pr.user.map(|user| user.login).unwrap_or_default(),
+            labels: pr.labels.into_iter().map(|label| label.name).collect(),
+            created_at: pr.created_at.as_deref().and_then(parse_timestamp),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiComment {
+    id: u64,
+    #[serde(default)]
+    body: String,
+}
+
+impl From<ApiComment> for Comment {
+    fn from(comment: ApiComment) -> Self {
+        Self {
+            id: comment.id,
+            body: comment.body,
+        }
+    }
+}
+
+/// The combined commit statuses, Gitea Actions reporting as statuses too
+#[derive(Deserialize)]
+struct ApiStatus {
+    #[serde(default)]
+    state: String,
+    #[serde(default)]
+    total_count: u64,
+}
+
+#[derive(Deserialize)]
+struct ApiLabel {
+    id: u64,
+    name: String,
+}
+
+impl Gitea {
+    /// A client of codeberg.org authenticated with `token`
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            api_url: DEFAULT_API_URL.to_string(),
+            token: token.into(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(30))
+                .user_agent(concat!("update-pr/", env!("CARGO_PKG_VERSION")))
+                .build(),
+            style: UpdateStyle::default(),
+            poll_interval: Duration::from_secs(2),
+            poll_timeout: Duration::from_secs(120),
+        }
+    }
+
+    /// Talk to another instance, e.g. `https://git.example.com/api/v1`
+    pub fn api_url(mut self, url: impl Into<String>) -> Self {
+        self.api_url = url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Whether pull requests are updated with a merge or a rebase, [`UpdateStyle::Merge`] by default
+    pub fn style(mut self, style: UpdateStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// How often and how long to wait for a pull request updated by Gitea, 2 seconds and 2 minutes by default
+    pub fn poll(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.poll_interval = interval;
+        self.poll_timeout = timeout;
+        self
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        self.agent
+            .request(method, &format!("{}{}", self.api_url, path))
+            .set("Authorization", &format!("token {}", self.token))
+            .set("Accept", "application/json")
+    }
+
+    fn api_pull_request(&self, repo: &RepoId, number: u64) -> Result<ApiPullRequest, ForgeError> {
+        Ok(self
+            .request("GET", &format!("/repos/{}/pulls/{}", repo, number))
+            .call()?
+            .into_json()?)
+    }
+
+    /// Wait until the head of the pull request `number` moves from `old_head_sha`, returning the new head
+    ///
+    /// Gitea updates the branch before answering, but may take a moment to
+    /// record the new head of the pull request.
+    fn wait_for_new_head(
+        &self,
+        repo: &RepoId,
+        number: u64,
+        old_head_sha: &str,
+    ) -> Result<String, ForgeError> {
+        let deadline = Instant::now() + self.poll_timeout;
+
+        loop {
+            let pr = self.api_pull_request(repo, number)?;
+            if pr.head.sha != old_head_sha {
+                return Ok(pr.head.sha);
+            }
+
+            if Instant::now() >= deadline {
+                return Err(ForgeError::new(format!(
+                    "pull request #{} was not updated within {}",
+                    number,
+                    humantime::format_duration(self.poll_timeout)
+                )));
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+impl Forge for Gitea {
+    fn name(&self) -> &'static str {
+        "Gitea"
+    }
+
+    /// The first open pull request from `branch` of `repo`, going through every page of them
+    ///
+    /// Gitea cannot filter pull requests by their head branch.
+    fn find_pull_request(
+        &self,
+        repo: &RepoId,
+        branch: &str,
+    ) -> Result<Option<PullRequest>, ForgeError> {
+        const LIMIT: usize = 50;
+
+        let full_name = repo.to_string();
+        let mut page = 1;
+        loop {
+            let pulls: Vec<ApiPullRequest> = self
+                .request("GET", &format!("/repos/{}/pulls", repo))
+                .query("state", "open")
+                .query("limit", &LIMIT.to_string())
+                .query("page", &page.to_string())
+                .call()?
+                .into_json()?;
+            let last_page = pulls.len() < LIMIT;
+
+            // Pull requests from forks can have a branch of the same name
+            let found = pulls.into_iter().find(|pr| {
+                pr.head.name == branch
+                    && pr
+                        .head
+                        .repo
+                        .as_ref()
+                        .is_some_and(|head| head.full_name.eq_ignore_ascii_case(&full_name))
+            });
+            if found.is_some() || last_page {
+                return Ok(found.map(PullRequest::from));
+            }
+            page += 1;
+        }
+    }
+
+    fn pull_request(&self, repo: &RepoId, number: u64) -> Result<PullRequest, ForgeError> {
+        Ok(self.api_pull_request(repo, number)?.into())
+    }
+
+    /// Merge or rebase with the update endpoint, available since Gitea 1.12
+    ///
+    /// Instances without it answer 404, which is reported as
+    /// [`BranchUpdate::Unsupported`].
+    fn update_branch(&self, repo: &RepoId, pr: &PullRequest) -> Result<BranchUpdate, ForgeError> {
+        let current = self.api_pull_request(repo, pr.number)?;
+        if current.head.sha != pr.head_sha {
+            return Err(ForgeError::new(format!(
+                "pull request #{} moved to {} meanwhile",
+                pr.number, current.head.sha
+            )));
+        }
+        if current.merge_base.as_deref() == Some(current.base.sha.as_str()) {
+            return Ok(BranchUpdate::UpToDate);
+        }
+
+        let style = match self.style {
+            UpdateStyle::Merge => "merge",
+            UpdateStyle::Rebase => "rebase",
+        };
+        let response = self
+            .request(
+                "POST",
+                &format!("/repos/{}/pulls/{}/update", repo, pr.number),
+            )
+            .query("style", style)
+            .call();
+
+        match response.map_err(ForgeError::from) {
+            Ok(_) => {}
+            Err(e) if e.status == Some(409) => return Ok(BranchUpdate::Conflict),
+            Err(e) if e.is_not_found() => return Ok(BranchUpdate::Unsupported),
+            Err(e) => return Err(e),
+        }
+
+        let head = self.wait_for_new_head(repo, pr.number, &pr.head_sha)?;
+
+        Ok(match self.style {
+            UpdateStyle::Merge => BranchUpdate::Merged(head),
+            UpdateStyle::Rebase => BranchUpdate::Rebased(head),
+        })
+    }
+
+    fn ci_status(&self, repo: &RepoId, sha: &str) -> Result<CiStatus, ForgeError> {
+        let status: ApiStatus = self
+            .request("GET", &format!("/repos/{}/commits/{}/status", repo, sha))
+            .call()?
+            .into_json()?;
+
+        Ok(match status.state.as_str() {
+            _ if status.total_count == 0 => CiStatus::Missing,
+            "success" | "warning" => CiStatus::Success,
+            "pending" => CiStatus::Pending,
+            _ => CiStatus::Failure,
+        })
+    }
+
+    fn review_decision(&self, repo: &RepoId, number: u64) -> Result<ReviewDecision, ForgeError> {
+        let reviews: Vec<ApiReview> = self
+            .request("GET", &format!("/repos/{}/pulls/{}/reviews", repo, number))
+            .call()?
+            .into_json()?;
+
+        Ok(ReviewDecision::from_reviews(reviews.iter().map(|review| {
+            let reviewer = review.user.as_ref().map_or("", |user| user.login.as_str());
+            (reviewer, review.state.as_str())
+        })))
+    }
+
+    fn merge_pull_request(
+        &self,
+        repo: &RepoId,
+        pr: &PullRequest,
+        method: MergeMethod,
+    ) -> Result<(), ForgeError> {
+        let method = match method {
+            MergeMethod::Merge => "merge",
+            MergeMethod::Squash => "squash",
+            MergeMethod::Rebase => "rebase",
+        };
+
+        self.request(
+            "POST",
+            &format!("/repos/{}/pulls/{}/merge", repo, pr.number),
+        )
+        .send_json(json!({ "Do": method, "head_commit_id": pr.head_sha }))?;
+
+        Ok(())
+    }
+
+    fn current_user(&self) -> Result<String, ForgeError> {
+        let user: ApiUser = self.request("GET", "/user").call()?.into_json()?;
+
+        Ok(user.login)
+    }
+
+    /// Every comment of the pull request, going through the pages of them
+    ///
+    /// Versions without pagination of comments answer all of them on each
+    /// page, reading stops at the first page without new comments.
+    fn comments(&self, repo: &RepoId, number: u64) -> Result<Vec<Comment>, ForgeError> {
+        const LIMIT: usize = 50;
+
+        let mut comments: Vec<Comment> = Vec::new();
+        let mut page = 1;
+        loop {
+            let page_comments: Vec<ApiComment> = self
+                .request(
+                    "GET",
+                    &format!("/repos/{}/issues/{}/comments", repo, number),
+                )
+                .query("limit", &LIMIT.to_string())
+                .query("page", &page.to_string())
+                .call()?
+                .into_json()?;
+            let last_page = page_comments.len() < LIMIT;
+
+            let before = comments.len();
+            for comment in page_comments {
+                if !comments.iter().any(|seen| seen.id == comment.id) {
+                    comments.push(comment.into());
+                }
+            }
+            if last_page || comments.len() == before {
+                return Ok(comments);
+            }
+            page += 1;
+        }
+    }
+
+    fn create_comment(
+        &self,
+        repo: &RepoId,
+        number: u64,
+        body: &str,
+    ) -> Result<Comment, ForgeError> {
+        let comment: ApiComment = self
+            .request(
+                "POST",
+                &format!("/repos/{}/issues/{}/comments", repo, number),
+            )
+            .send_json(json!({ "body": body }))?
+            .into_json()?;
+
+        Ok(comment.into())
+    }
+
+    fn update_comment(
+        &self,
+        repo: &RepoId,
+        _number: u64,
+        id: u64,
+        body: &str,
+    ) -> Result<(), ForgeError> {
+        self.request("PATCH", &format!("/repos/{}/issues/comments/{}", repo, id))
+            .send_json(json!({ "body": body }))?;
+
+        Ok(())
+    }
+
+    fn delete_comment(&self, repo: &RepoId, _number: u64, id: u64) -> Result<(), ForgeError> {
+        self.request("DELETE", &format!("/repos/{}/issues/comments/{}", repo, id))
+            .call()?;
+
+        Ok(())
+    }
+
+    /// Labels are given by name, which needs Gitea 1.19 or Forgejo
+    fn add_label(&self, repo: &RepoId, number: u64, label: &str) -> Result<(), ForgeError> {
+        self.request("POST", &format!("/repos/{}/issues/{}/labels", repo, number))
+            .send_json(json!({ "labels": [label] }))?;
+
+        Ok(())
+    }
+
+    /// Labels are removed by id, so the labels of the pull request are read first
+    fn remove_label(&self, repo: &RepoId, number: u64, label: &str) -> Result<(), ForgeError> {
+        let labels: Vec<ApiLabel> = self
+            .request("GET", &format!("/repos/{}/issues/{}/labels", repo, number))
+            .call()?
+            .into_json()?;
+
+        let Some(label) = labels.into_iter().find(|l| l.name == label) else {
+            return Ok(());
+        };
+        self.request(
+            "DELETE",
+            &format!("/repos/{}/issues/{}/labels/{}", repo, number, label.id),
+        )
+        .call()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::Result;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::forge::{fake::FakeApi, ForgeKind};
+
+    fn pull_request(number: u64, branch: &str, full_name: &str) -> Value {
+        json!({
+            "number": number,
+            "html_url": format!("https://codeberg.org/org/api/pulls/{}", number),
+            "state": "open",
+            "head": { "ref": branch, "sha": "abc", "repo": { "full_name": full_name } },
+            "base": { "ref": "main", "sha": "def" },
+        })
+    }
+
+    #[test]
+    fn test_detect() {
+        for url in [
+            "https://codeberg.org/forgejo/forgejo.git",
+            "git@gitea.example.com:org/api.git",
+            "ssh://git@forgejo.example.com:2222/org/api",
+        ] {
+            assert_eq!(ForgeKind::detect(url), Some(ForgeKind::Gitea), "{}", url);
+        }
+    }
+
+    #[test]
+    fn test_find_pull_request() -> Result<()> {
+        // A fork's `pr` branch on the first page, the one of the repository on the second
+        let api = FakeApi::start(|request| {
+            let pulls = match request.path.rsplit_once("page=").map(|(_, page)| page) {
+                Some("1") => {
+                    let mut pulls = vec![pull_request(9, "pr", "fork/api")];
+                    pulls.resize_with(50, || pull_request(8, "other", "org/api"));
+                    pulls
+                }
+                Some("2") => vec![pull_request(5, "pr", "org/api")],
+                _ => vec![],
+            };
+            (200, json!(pulls))
+        })?;
+        let repo = RepoId {
+            owner: "org".to_string(),
+            name: "api".to_string(),
+        };
+        let gitea = Gitea::new("token").api_url(&api.url);
+
+        assert_eq!(
+            gitea.find_pull_request(&repo, "pr")?.map(|pr| pr.number),
+            Some(5)
+        );
+        assert_eq!(
+            gitea.find_pull_request(&repo, "gone")?.map(|pr| pr.number),
+            None
+        );
+        assert_eq!(api.requests().len(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_comments() -> Result<()> {
+        let repo = RepoId {
+            owner: "org".to_string(),
+            name: "api".to_string(),
+        };
+        let comments = |ids: std::ops::Range<u64>| {
+            let comments: Vec<_> = ids.map(|id| json!({ "id": id, "body": "" })).collect();
+            json!(comments)
+        };
+
+        // A full first page, and one more comment on the second
+        let api = FakeApi::start(move |request| {
+            match request.path.rsplit_once("page=").map(|(_, page)| page) {
+                Some("1") => (200, comments(1..51)),
+                Some("2") => (200, comments(51..52)),
+                _ => (200, comments(0..0)),
+            }
+        })?;
+
+        assert_eq!(
+            Gitea::new("token")
+                .api_url(&api.url)
+                .comments(&repo, 1)?
+                .len(),
+            51
+        );
+        assert_eq!(api.requests().len(), 2);
+
+        // Every comment on each page, without pagination
+        let api = FakeApi::start(move |_| (200, comments(1..61)))?;
+
+        assert_eq!(
+            Gitea::new("token")
+                .api_url(&api.url)
+                .comments(&repo, 1)?
+                .len(),
+            60
+        );
+        assert_eq!(api.requests().len(), 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+//! A client of the GitHub REST API, see <https://docs.github.com/en/rest>
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{
+    encode_path_segment, parse_timestamp, BranchUpdate, CiStatus, Comment, Forge, ForgeError,
+    MergeMethod, PullRequest, PullRequestState, RepoId, ReviewDecision,
+};
+
+/// The API of github.com
+pub const DEFAULT_API_URL: &str = "https://api.github.com";
+
+/// Talks to the GitHub API with a token
+pub struct GitHub {
+    api_url: String,
+    token: String,
+    agent: ureq::Agent,
+    poll_interval: Duration,
+    poll_timeout: Duration,
+}
+
+#[derive(Deserialize)]
+struct ApiPullRequest {
+    number: u64,
+    html_url: String,
+    state: String,
+    merged_at: Option<String>,
+    #[serde(default)]
+    draft: bool,
+    user: Option<ApiUser>,
+    #[serde(default)]
+    labels: Vec<ApiLabel>,
+    created_at: Option<String>,
+    head: ApiBranch,
+    base: ApiBranch,
+}
+
+#[derive(Deserialize)]
+struct ApiUser {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct ApiLabel {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ApiReview {
+    user: Option<ApiUser>,
+    state: String,
+}
+
+#[derive(Deserialize)]
+struct ApiBranch {
+    #[serde(rename = "ref")]
+    name: String,
+    sha: String,
+}
+
+impl From<ApiPullRequest> for PullRequest {
+    fn from(pr: ApiPullRequest) -> Self {
+        let state = match (pr.state.as_str(), pr.merged_at) {
+            (_, Some(_)) => PullRequestState::Merged,
+            ("open", None) => PullRequestState::Open,
+            _ => PullRequestState::Closed,
+        };
+
+        Self {
+            number: pr.number,
+            url: pr.html_url,
+            base: pr.base.name,
+            head_sha: pr.head.sha,
+            state,
+            draft: pr.draft,
+            author: pr.user.map(|user| user.login).unwrap_or_default(),
+            labels: pr.labels.into_iter().map(|label| label.name).collect(),
+            created_at: pr.created_at.as_deref().and_then(parse_timestamp),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiComment {
+    id: u64,
+    #[serde(default)]
+    body: String,
+}
+
+impl From<ApiComment> for Comment {
+    fn from(comment: ApiComment) -> Self {
+        Self {
+            id: comment.id,
+            body: comment.body,
+        }
+    }
+}
+
+/// The combined commit statuses
+#[derive(Deserialize)]
+struct ApiStatus {
+    state: String,
+    total_count: u64,
+}
+
+#[derive(Deserialize)]
+struct ApiCheckRuns {
+    check_runs: Vec<ApiCheckRun>,
+}
+
+#[derive(Deserialize)]
+struct ApiCheckRun {
+    status: String,
+    conclusion: Option<String>,
+}
+
+impl ApiCheckRun {
+    fn ci_status(&self) -> CiStatus {
+        if self.status != "completed" {
+            return CiStatus::Pending;
+        }
+
+        match self.conclusion.as_deref() {
+            Some("success" | "neutral" | "skipped") => CiStatus::Success,
+            _ => CiStatus::Failure,
+        }
+    }
+}
+
+impl GitHub {
+    /// A client of github.com authenticated with `token`
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            api_url: DEFAULT_API_URL.to_string(),
+            token: token.into(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(30))
+                .user_agent(concat!("update-pr/", env!("CARGO_PKG_VERSION")))
+                .build(),
+            poll_interval: Duration::from_secs(2),
+            poll_timeout: Duration::from_secs(120),
+        }
+    }
+
+    /// Talk to another API, e.g. `https://github.example.com/api/v3` for GitHub Enterprise
+    pub fn api_url(mut self, url: impl Into<String>) -> Self {
+        self.api_url = url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// How often and how long to wait for a pull request updated by GitHub, 2 seconds and 2 minutes by default
+    pub fn poll(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.poll_interval = interval;
+        self.poll_timeout = timeout;
+        self
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        self.agent
+            .request(method, &format!("{}{}", self.api_url, path))
+            .set("Authorization", &format!("Bearer {}", self.token))
+            .set("Accept", "application/vnd.github+json")
+            .set("X-GitHub-Api-Version", "2022-11-28")
+    }
+
+    /// Wait until the head of the pull request `number` moves from `old_head_sha`, returning the new head
+    fn wait_for_new_head(
+        &self,
+        repo: &RepoId,
+        number: u64,
+        old_head_sha: &str,
+    ) -> Result<String, ForgeError> {
+        let deadline = Instant::now() + self.poll_timeout;
+
+        loop {
+            let pr = self.pull_request(repo, number)?;
+            if pr.head_sha != old_head_sha {
+                return Ok(pr.head_sha);
+            }
+
+            if Instant::now() >= deadline {
+                return Err(ForgeError::new(format!(
+                    "pull request #{} was not updated within {}",
+                    number,
+                    humantime::format_duration(self.poll_timeout)
+                )));
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+impl Forge for GitHub {
+    fn name(&self) -> &'static str {
+        "GitHub"
+    }
+
+    fn find_pull_request(
+        &self,
+        repo: &RepoId,
+        branch: &str,
+    ) -> Result<Option<PullRequest>, ForgeError> {
+        let pulls: Vec<ApiPullRequest> = self
+            .request("GET", &format!("/repos/{}/pulls", repo))
+            .query("head", &format!("{}:{}", repo.owner, branch))
+            .query("state", "open")
+            .call()?
+            .into_json()?;
+
+        Ok(pulls.into_iter().next().map(PullRequest::from))
+    }
+
+    fn pull_request(&self, repo: &RepoId, number: u64) -> Result<PullRequest, ForgeError> {
+        let pr: ApiPullRequest = self
+            .request("GET", &format!("/repos/{}/pulls/{}", repo, number))
+            .call()?
+            .into_json()?;
+
+        Ok(pr.into())
+    }
+
+    /// Merge the base branch with the update branch API, then wait for the new head
+    ///
+    /// The head of `pr` is sent as `expected_head_sha`, so that commits pushed
+    /// meanwhile are not raced.
+    fn update_branch(&self, repo: &RepoId, pr: &PullRequest) -> Result<BranchUpdate, ForgeError> {
+        let response = self
+            .request(
+                "PUT",
+                &format!("/repos/{}/pulls/{}/update-branch", repo, pr.number),
+            )
+            .send_json(json!({ "expected_head_sha": pr.head_sha }));
+
+        match response.map_err(ForgeError::from) {
+            Ok(_) => {}
+            Err(e) if e.status == Some(422) => {
+                let message = e.message.to_lowercase();
+                return if message.contains("conflict") {
+                    Ok(BranchUpdate::Conflict)
+                } else if message.contains("no new commits") {
+                    Ok(BranchUpdate::UpToDate)
+                } else {
+                    Err(e)
+                };
+            }
+            Err(e) => return Err(e),
+        }
+
+        let head = self.wait_for_new_head(repo, pr.number, &pr.head_sha)?;
+
+        Ok(BranchUpdate::Merged(head))
+    }
+
+    /// The combined commit statuses together with the check runs, as shown on GitHub
+    fn ci_status(&self, repo: &RepoId, sha: &str) -> Result<CiStatus, ForgeError> {
+        let status: ApiStatus = self
+            .request("GET", &format!("/repos/{}/commits/{}/status", repo, sha))
+            .call()?
+            .into_json()?;
+        let statuses = match status.state.as_str() {
+            _ if status.total_count == 0 => CiStatus::Missing,
+            "success" => CiStatus::Success,
+            "pending" => CiStatus::Pending,
+            _ => CiStatus::Failure,
+        };
+
+        let checks: ApiCheckRuns = self
+            .request(
+                "GET",
+                &format!("/repos/{}/commits/{}/check-runs", repo, sha),
+            )
+            .query("per_page", "100")
+            .call()?
+            .into_json()?;
+
+        Ok(checks
+            .check_runs
+            .iter()
+            .map(ApiCheckRun::ci_status)
+            .fold(statuses, CiStatus::combine))
+    }
+
+    fn review_decision(&self, repo: &RepoId, number: u64) -> Result<ReviewDecision, ForgeError> {
+        let reviews: Vec<ApiReview> = self
+            .request("GET", &format!("/repos/{}/pulls/{}/reviews", repo, number))
+            .query("per_page", "100")
+            .call()?
+            .into_json()?;
+
+        Ok(ReviewDecision::from_reviews(reviews.iter().map(|review| {
+            let reviewer = review.user.as_ref().map_or("", |user| user.login.as_str());
+            (reviewer, review.state.as_str())
+        })))
+    }
+
+    fn merge_pull_request(
+        &self,
+        repo: &RepoId,
+        pr: &PullRequest,
+        method: MergeMethod,
+    ) -> Result<(), ForgeError> {
+        let method = match method {
+            MergeMethod::Merge => "merge",
+            MergeMethod::Squash => "squash",
+            MergeMethod::Rebase => "rebase",
+        };
+
+        self.request("PUT", &format!("/repos/{}/pulls/{}/merge", repo, pr.number))
+            .send_json(json!({ "merge_method": method, "sha": pr.head_sha }))?;
+
+        Ok(())
+    }
+
+    fn current_user(&self) -> Result<String, ForgeError> {
+        let user: ApiUser = self.request("GET", "/user").call()?.into_json()?;
+
+        Ok(user.login)
+    }
+
+    fn comments(&self, repo: &RepoId, number: u64) -> Result<Vec<Comment>, ForgeError> {
+        const LIMIT: usize = 100;
+
+        let mut comments = Vec::new();
+        let mut page = 1;
+        loop {
+            let page_comments: Vec<ApiComment> = self
+                .request(
+                    "GET",
+                    &format!("/repos/{}/issues/{}/comments", repo, number),
+                )
+                .query("per_page", &LIMIT.to_string())
+                .query("page", &page.to_string())
+                .call()?
+                .into_json()?;
+            let last_page = page_comments.len() < LIMIT;
+
+            comments.extend(page_comments.into_iter().map(Comment::from));
+            if last_page {
+                return Ok(comments);
+            }
+            page += 1;
+        }
+    }
+
+    fn create_comment(
+        &self,
+        repo: &RepoId,
+        number: u64,
+        body: &str,
+    ) -> Result<Comment, ForgeError> {
+        let comment: ApiComment = self
+            .request(
+                "POST",
+                &format!("/repos/{}/issues/{}/comments", repo, number),
+            )
+            .send_json(json!({ "body": body }))?
+            .into_json()?;
+
+        Ok(comment.into())
+    }
+
+    fn update_comment(
+        &self,
+        repo: &RepoId,
+        _number: u64,
+        id: u64,
+        body: &str,
+    ) -> Result<(), ForgeError> {
+        self.request("PATCH", &format!("/repos/{}/issues/comments/{}", repo, id))
+            .send_json(json!({ "body": body }))?;
+
+        Ok(())
+    }
+
+    fn delete_comment(&self, repo: &RepoId, _number: u64, id: u64) -> Result<(), ForgeError> {
+        self.request("DELETE", &format!("/repos/{}/issues/comments/{}", repo, id))
+            .call()?;
+
+        Ok(())
+    }
+
+    fn add_label(&self, repo: &RepoId, number: u64, label: &str) -> Result<(), ForgeError> {
+        self.request("POST", &format!("/repos/{}/issues/{}/labels", repo, number))
+            .send_json(json!({ "labels": [label] }))?;
+
+        Ok(())
+    }
+
+    fn remove_label(&self, repo: &RepoId, number: u64, label: &str) -> Result<(), ForgeError> {
+        let path = format!(
+            "/repos/{}/issues/{}/labels/{}",
+            repo,
+            number,
+            encode_path_segment(label)
+        );
+
+        match self.request("DELETE", &path).call() {
+            Ok(_) => Ok(()),
+            Err(e) => match ForgeError::from(e) {
+                e if e.is_not_found() => Ok(()),
+                e => Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::Result;
+
+    use super::*;
+    use crate::forge::fake::FakeApi;
+
+    #[test]
+    fn test_comments() -> Result<()> {
+        // A full first page, and one more comment on the second
+        let api = FakeApi::start(|request| {
+            let ids = match request.path.rsplit_once("page=").map(|(_, page)| page) {
+                Some("1") => 1..101,
+                Some("2") => 101..102,
+                _ => 0..0,
+            };
+            let comments: Vec<_> = ids.map(|id| json!({ "id": id, "body": "" })).collect();
+            (200, json!(comments))
+        })?;
+        let repo = RepoId {
+            owner: "org".to_string(),
+            name: "api".to_string(),
+        };
+
+        let comments = GitHub::new("token").api_url(&api.url).comments(&repo, 1)?;
+
+        assert_eq!(comments.len(), 101);
+        assert_eq!(comments.last().map(|comment| comment.id), Some(101));
+        assert_eq!(api.requests().len(), 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,489 @@
+//! A client of the GitLab REST API, see <https://docs.gitlab.com/ee/api/merge_requests.html>
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{
+    encode_path_segment, parse_timestamp, BranchUpdate, CiStatus, Comment, Forge, ForgeError,
+    MergeMethod, PullRequest, PullRequestState, RepoId, ReviewDecision,
+};
+
+/// The API of gitlab.com
+pub const DEFAULT_API_URL: &str = "https://gitlab.com/api/v4";
+
+/// Talks to the GitLab API with a personal, project or group access token
+pub struct GitLab {
+    api_url: String,
+    token: String,
+    agent: ureq::Agent,
+    poll_interval: Duration,
+    poll_timeout: Duration,
+}
+
+#[derive(Deserialize)]
+struct ApiMergeRequest {
+    iid: u64,
+    project_id: u64,
+    source_project_id: u64,
+    web_url: String,
+    target_branch: String,
+    sha: String,
+    state: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    work_in_progress: bool,
+    author: Option<ApiUser>,
+    #[serde(default)]
+    labels: Vec<String>,
+    created_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiUser {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct ApiApprovals {
+    #[serde(default)]
+    approved: bool,
+}
+
+impl From<ApiMergeRequest> for PullRequest {
+    fn from(mr: ApiMergeRequest) -> Self {
+        let state = match mr.state.as_str() {
+            "opened" => PullRequestState::Open,
+            "merged" => PullRequestState::Merged,
+            _ => PullRequestState::Closed,
+        };
+
+        Self {
+            number: mr.iid,
+            url: mr.web_url,
+            base: mr.target_branch,
+            head_sha: mr.sha,
+            state,
+            // `work_in_progress` is the name used before GitLab 14
+            draft: mr.draft || mr.work_in_progress,
+            author: mr.author.map(|user| user.username).unwrap_or_default(),
+            labels: mr.labels,
+            created_at: mr.created_at.as_deref().and_then(parse_timestamp),
+        }
+    }
+}
+
+/// What GitLab knows about bringing the target branch into a merge request
+#[derive(Deserialize)]
+struct ApiMergeStatus {
+    sha: String,
+    #[serde(default)]
+    has_conflicts: bool,
+    diverged_commits_count: Option<u64>,
+    #[serde(default)]
+    rebase_in_progress: bool,
+    merge_error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiCommit {
+    last_pipeline: Option<ApiPipeline>,
+}
+
+#[derive(Deserialize)]
+struct ApiPipeline {
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct ApiNote {
+    id: u64,
+    #[serde(default)]
+    body: String,
+}
+
+impl From<ApiNote> for Comment {
+    fn from(note: ApiNote) -> Self {
+        Self {
+            id: note.id,
+            body: note.body,
+        }
+    }
+}
+
+impl GitLab {
+    /// A client of gitlab.com authenticated with `token`
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            api_url: DEFAULT_API_URL.to_string(),
+            token: token.into(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(30))
+                .user_agent(concat!("update-pr/", env!("CARGO_PKG_VERSION")))
+                .build(),
+            poll_interval: Duration::from_secs(2),
+            poll_timeout: Duration::from_secs(120),
+        }
+    }
+
+    /// Talk to another API, e.g. `https://gitlab.example.com/api/v4` for a self-managed instance
+    pub fn api_url(mut self, url: impl Into<String>) -> Self {
+        self.api_url = url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// How often and how long to wait for a merge request rebased by GitLab, 2 seconds and 2 minutes by default
+    pub fn poll(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.poll_interval = interval;
+        self.poll_timeout = timeout;
+        self
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        self.agent
+            .request(method, &format!("{}{}", self.api_url, path))
+            .set("PRIVATE-TOKEN", &self.token)
+    }
+
+    /// The path of the merge request `iid`, projects being identified by their escaped full path
+    fn merge_request_path(repo: &RepoId, iid: u64) -> String {
+        format!(
+            "/projects/{}/merge_requests/{}",
+            encode_path_segment(&repo.to_string()),
+            iid
+        )
+    }
+
+    fn merge_status(&self, repo: &RepoId, iid: u64) -> Result<ApiMergeStatus, ForgeError> {
+        Ok(self
+            .request("GET", &Self::merge_request_path(repo, iid))
+            .query("include_diverged_commits_count", "true")
+            .query("include_rebase_in_progress", "true")
+            .call()?
+            .into_json()?)
+    }
+
+    /// Wait until the rebase of the merge request `iid` is over
+    fn wait_for_rebase(&self, repo: &RepoId, iid: u64) -> Result<ApiMergeStatus, ForgeError> {
+        let deadline = Instant::now() + self.poll_timeout;
+
+        loop {
+            let status = self.merge_status(repo, iid)?;
+            if !status.rebase_in_progress {
+                return Ok(status);
+            }
+
+            if Instant::now() >= deadline {
+                return Err(ForgeError::new(format!(
+                    "merge request !{} was not rebased within {}",
+                    iid,
+                    humantime::format_duration(self.poll_timeout)
+                )));
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+impl Forge for GitLab {
+    fn name(&self) -> &'static str {
+        "GitLab"
+    }
+
+    fn find_pull_request(
+        &self,
+        repo: &RepoId,
+        branch: &str,
+    ) -> Result<Option<PullRequest>, ForgeError> {
+        let merge_requests: Vec<ApiMergeRequest> = self
+            .request(
+                "GET",
+                &format!(
+                    "/projects/{}/merge_requests",
+                    encode_path_segment(&repo.to_string())
+                ),
+            )
+            .query("source_branch", branch)
+            .query("state", "opened")
+            .query("per_page", "100")
+            .call()?
+            .into_json()?;
+
+        // Merge requests from forks can have a branch of the same name
+        Ok(merge_requests
+            .into_iter()
+            .find(|mr| mr.source_project_id == mr.project_id)
+            .map(PullRequest::from))
+    }
+
+    fn pull_request(&self, repo: &RepoId, number: u64) -> Result<PullRequest, ForgeError> {
+        let mr: ApiMergeRequest = self
+            .request("GET", &Self::merge_request_path(repo, number))
+            .call()?
+            .into_json()?;
+
+        Ok(mr.into())
+    }
+
+    /// Rebase the merge request onto its target branch, then wait for the rebase to finish
+    ///
+    /// GitLab has no merge counterpart to its rebase API. Nothing is done when
+    /// the head of the merge request is no longer the head of `pr`.
+    fn update_branch(&self, repo: &RepoId, pr: &PullRequest) -> Result<BranchUpdate, ForgeError> {
+        let status = self.merge_status(repo, pr.number)?;
+        if status.sha != pr.head_sha {
+            return Err(ForgeError::new(format!(
+                "merge request !{} moved to {} meanwhile",
+                pr.number, status.sha
+            )));
+        }
+        if status.has_conflicts {
+            return Ok(BranchUpdate::Conflict);
+        }
+        if status.diverged_commits_count == Some(0) {
+            return Ok(BranchUpdate::UpToDate);
+        }
+
+        self.request(
+            "PUT",
+            &format!("{}/rebase", Self::merge_request_path(repo, pr.number)),
+        )
+        .call()?;
+
+        let status = self.wait_for_rebase(repo, pr.number)?;
+        match status.merge_error {
+            Some(e) if e.to_lowercase().contains("conflict") => Ok(BranchUpdate::Conflict),
+            Some(e) => Err(ForgeError::new(e)),
+            None if status.sha == pr.head_sha => Ok(BranchUpdate::UpToDate),
+            None => Ok(BranchUpdate::Rebased(status.sha)),
+        }
+    }
+
+    /// The status of the last pipeline of the commit
+    fn ci_status(&self, repo: &RepoId, sha: &str) -> Result<CiStatus, ForgeError> {
+        let commit: ApiCommit = self
+            .request(
+                "GET",
+                &format!(
+                    "/projects/{}/repository/commits/{}",
+                    encode_path_segment(&repo.to_string()),
+                    sha
+                ),
+            )
+            .call()?
+            .into_json()?;
+
+        Ok(
+            match commit.last_pipeline.as_ref().map(|p| p.status.as_str()) {
+                None => CiStatus::Missing,
+                Some("success") => CiStatus::Success,
+                Some("failed" | "canceled") => CiStatus::Failure,
+                Some(_) => CiStatus::Pending,
+            },
+        )
+    }
+
+    /// Approved once the approval rules of the merge request are met, GitLab has no refusals
+    fn review_decision(&self, repo: &RepoId, number: u64) -> Result<ReviewDecision, ForgeError> {
+        let approvals: ApiApprovals = self
+            .request(
+                "GET",
+                &format!("{}/approvals", Self::merge_request_path(repo, number)),
+            )
+            .call()?
+            .into_json()?;
+
+        Ok(if approvals.approved {
+            ReviewDecision::Approved
+        } else {
+            ReviewDecision::Pending
+        })
+    }
+
+    /// The merge method of the project is used, only squashing can be asked for
+    fn merge_pull_request(
+        &self,
+        repo: &RepoId,
+        pr: &PullRequest,
+        method: MergeMethod,
+    ) -> Result<(), ForgeError> {
+        self.request(
+            "PUT",
+            &format!("{}/merge", Self::merge_request_path(repo, pr.number)),
+        )
+        .send_json(json!({
+            "sha": pr.head_sha,
+            "squash": method == MergeMethod::Squash,
+        }))?;
+
+        Ok(())
+    }
+
+    fn current_user(&self) -> Result<String, ForgeError> {
+        let user: ApiUser = self.request("GET", "/user").call()?.into_json()?;
+
+        Ok(user.username)
+    }
+
+    fn comments(&self, repo: &RepoId, number: u64) -> Result<Vec<Comment>, ForgeError> {
+        const LIMIT: usize = 100;
+
+        let mut comments = Vec::new();
+        let mut page = 1;
+        loop {
+            let notes: Vec<ApiNote> = self
+                .request(
+                    "GET",
+                    &format!("{}/notes", Self::merge_request_path(repo, number)),
+                )
+                .query("per_page", &LIMIT.to_string())
+                .query("page", &page.to_string())
+                .call()?
+                .into_json()?;
+            let last_page = notes.len() < LIMIT;
+
+            comments.extend(notes.into_iter().map(Comment::from));
+            if last_page {
+                return Ok(comments);
+            }
+            page += 1;
+        }
+    }
+
+    fn create_comment(
+        &self,
+        repo: &RepoId,
+        number: u64,
+        body: &str,
+    ) -> Result<Comment, ForgeError> {
+        let note: ApiNote = self
+            .request(
+                "POST",
+                &format!("{}/notes", Self::merge_request_path(repo, number)),
+            )
+            .send_json(json!({ "body": body }))?
+            .into_json()?;
+
+        Ok(note.into())
+    }
+
+    fn update_comment(
+        &self,
+        repo: &RepoId,
+        number: u64,
+        id: u64,
+        body: &str,
+    ) -> Result<(), ForgeError> {
+        self.request(
+            "PUT",
+            &format!("{}/notes/{}", Self::merge_request_path(repo, number), id),
+        )
+        .send_json(json!({ "body": body }))?;
+
+        Ok(())
+    }
+
+    fn delete_comment(&self, repo: &RepoId, number: u64, id: u64) -> Result<(), ForgeError> {
+        self.request(
+            "DELETE",
+            &format!("{}/notes/{}", Self::merge_request_path(repo, number), id),
+        )
+        .call()?;
+
+        Ok(())
+    }
+
+    fn add_label(&self, repo: &RepoId, number: u64, label: &str) -> Result<(), ForgeError> {
+        self.request("PUT", &Self::merge_request_path(repo, number))
+            .send_json(json!({ "add_labels": label }))?;
+
+        Ok(())
+    }
+
+    /// Removing a label the merge request doesn't have is not an error on GitLab
+    fn remove_label(&self, repo: &RepoId, number: u64, label: &str) -> Result<(), ForgeError> {
+        self.request("PUT", &Self::merge_request_path(repo, number))
+            .send_json(json!({ "remove_labels": label }))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::Result;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::forge::fake::FakeApi;
+
+    fn merge_request(iid: u64, source_project_id: u64) -> Value {
+        json!({
+            "iid": iid,
+            "project_id": 1,
+            "source_project_id": source_project_id,
+            "web_url": format!("https://gitlab.com/org/api/-/merge_requests/{}", iid),
+            "target_branch": "main",
+            "sha": "abc",
+            "state": "opened",
+        })
+    }
+
+    #[test]
+    fn test_repo_from_subgroup_url() {
+        assert_eq!(
+            RepoId::from_url("https://gitlab.example.com/group/sub/api.git"),
+            Some(RepoId {
+                owner: "group/sub".to_string(),
+                name: "api".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_find_pull_request_skips_forks() -> Result<()> {
+        let api = FakeApi::start(|_| (200, json!([merge_request(4, 2), merge_request(3, 1)])))?;
+        let repo = RepoId {
+            owner: "org".to_string(),
+            name: "api".to_string(),
+        };
+
+        let pr = GitLab::new("token")
+            .api_url(&api.url)
+            .find_pull_request(&repo, "pr")?;
+
+        assert_eq!(pr.map(|pr| pr.number), Some(3));
+        assert!(api.requests()[0]
+            .path
+            .starts_with("/projects/org%2Fapi/merge_requests?"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_comments() -> Result<()> {
+        // A full first page, and one more note on the second
+        let api = FakeApi::start(|request| {
+            let ids = match request.path.rsplit_once("page=").map(|(_, page)| page) {
+                Some("1") => 1..101,
+                Some("2") => 101..102,
+                _ => 0..0,
+            };
+            let notes: Vec<_> = ids.map(|id| json!({ "id": id, "body": "" })).collect();
+            (200, json!(notes))
+        })?;
+        let repo = RepoId {
+            owner: "org".to_string(),
+            name: "api".to_string(),
+        };
+
+        let comments = GitLab::new("token").api_url(&api.url).comments(&repo, 1)?;
+
+        assert_eq!(comments.len(), 101);
+        assert_eq!(api.requests().len(), 2);
+
+        Ok(())
+    }
+}
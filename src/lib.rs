@@ -1,4 +1,23 @@
+//! Keep a pull request branch up to date with its base branch.
+//!
+//! [`Updater`] pulls the checked out branch, merges the base branch into it
+//! and pushes the result, reporting what it did as an [`UpdateOutcome`]. The
+//! `update-pr` binary is a thin command line layer over it.
+
 pub mod app;
 pub mod cli;
+#[cfg(unix)]
+pub mod daemon;
+pub mod forge;
+mod logging;
 mod progress;
+pub mod report;
 mod utils;
+pub mod webhooks;
+
+pub use app::{
+    fetch::TagPolicy, partial::allow_partial_clone, Credentials, MergeOutcome, Strategy,
+    UpdateOutcome, Updater,
+};
+pub use progress::terminal::{TerminalReporter, Verbosity};
+pub use report::{Event, Reporter};
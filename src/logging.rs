@@ -0,0 +1,113 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::{progress::terminal::Verbosity, utils::git::redact_credentials};
+
+/// Number of rotated log files kept next to the current one
+const LOG_FILES_KEPT: usize = 7;
+
+/// How often the log file is rotated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Never => Rotation::NEVER,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+        }
+    }
+}
+
+/// Install the global tracing subscriber
+///
+/// Logs go to stderr when `RUST_LOG` is set or at [`Verbosity::VeryVerbose`],
+/// and to `log_file` when given. At [`Verbosity::VeryVerbose`] the internal
+/// trace of libgit2 is forwarded under the `libgit2` target.
+///
+/// The returned guard flushes the log file when dropped.
+pub fn init(
+    verbosity: Verbosity,
+    log_file: Option<&Path>,
+    rotation: LogRotation,
+) -> Result<Option<WorkerGuard>> {
+    let very_verbose = verbosity >= Verbosity::VeryVerbose;
+    let default_directives = if very_verbose {
+        "debug,libgit2=trace"
+    } else {
+        "debug"
+    };
+    let filter =
+        || EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_directives));
+
+    let stderr_layer = (very_verbose || std::env::var_os("RUST_LOG").is_some()).then(|| {
+        fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_filter(filter())
+    });
+
+    let (file_layer, guard) = match log_file {
+        Some(path) => {
+            let directory = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            std::fs::create_dir_all(directory)?;
+            let appender = RollingFileAppender::builder()
+                .rotation(rotation.into())
+                .filename_prefix(path.file_name().unwrap_or_default().to_string_lossy())
+                .max_log_files(LOG_FILES_KEPT + 1)
+                .build(directory)?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+
+            let layer = fmt::layer()
+                .with_ansi(false)
+                .with_writer(writer)
+                .with_filter(filter());
+
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    // A subscriber may already be installed, e.g. by an application embedding us
+    let _ = tracing_subscriber::registry()
+        .with(stderr_layer)
+        .with(file_layer)
+        .try_init();
+
+    if very_verbose {
+        if let Err(e) = git2::trace_set(git2::TraceLevel::Trace, trace_libgit2) {
+            tracing::debug!("libgit2 tracing is not available: {}", e);
+        }
+    }
+
+    Ok(guard)
+}
+
+fn trace_libgit2(level: git2::TraceLevel, msg: &[u8]) {
+    let msg = redact_credentials(&String::from_utf8_lossy(msg));
+
+    match level {
+        git2::TraceLevel::Fatal | git2::TraceLevel::Error => {
+            tracing::error!(target: "libgit2", "{}", msg)
+        }
+        git2::TraceLevel::Warn => tracing::warn!(target: "libgit2", "{}", msg),
+        git2::TraceLevel::Info => tracing::info!(target: "libgit2", "{}", msg),
+        git2::TraceLevel::Debug => tracing::debug!(target: "libgit2", "{}", msg),
+        _ => tracing::trace!(target: "libgit2", "{}", msg),
+    }
+}
@@ -1,20 +1,29 @@
-mod app;
-mod cli;
-mod progress;
-mod utils;
-
 use std::process::ExitCode;
 
-use app::App;
 use clap::Parser;
-use cli::Cli;
 use color_eyre::eyre::Result;
+use update_pr::{app::App, cli::Cli};
+#[cfg(unix)]
+use update_pr::{cli::Command, daemon};
 
 fn main() -> Result<ExitCode> {
     color_eyre::install()?;
 
-    let cli = Cli::parse();
-    let app = App::new(cli)?;
+    // Only partial clones of older versions of git need it, so it may fail
+    // SAFETY: nothing has used libgit2 yet, and no other thread is running
+    let _ = unsafe { update_pr::allow_partial_clone() };
+
+    let mut cli = Cli::parse();
+    let code = match cli.command.take() {
+        #[cfg(unix)]
+        Some(Command::Daemon(args)) => {
+            cli.color.apply();
+            daemon::run(cli, args)?
+        }
+        #[cfg(unix)]
+        Some(Command::Ctl(args)) => daemon::ctl(args)?,
+        None => App::new(cli)?.run()?,
+    };
 
-    std::process::exit(app.run()?)
+    std::process::exit(code)
 }
@@ -1,2 +1,5 @@
 pub mod fetch;
+pub mod multi;
 pub mod push;
+pub mod summary;
+pub mod terminal;
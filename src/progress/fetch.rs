@@ -1,8 +1,9 @@
 use std::{cell::OnceCell, ops::Deref};
 
-use git2::Progress;
 use indicatif::{MultiProgress, ProgressBar};
 
+use crate::report::TransferProgress;
+
 pub struct FetchProgress {
     m: MultiProgress,
 
@@ -40,15 +41,15 @@ impl FetchProgress {
         Self::default()
     }
 
-    pub fn process(&self, stats: Progress) {
-        let object_dl_pb = self.object_dl_pb(stats.total_objects());
-        object_dl_pb.set_position(stats.received_objects() as u64);
+    pub fn process(&self, stats: &TransferProgress) {
+        let object_dl_pb = self.object_dl_pb(stats.total_objects);
+        object_dl_pb.set_position(stats.received_objects as u64);
 
-        let object_pb = self.object_pb(stats.total_objects());
-        object_pb.set_position(stats.indexed_objects() as u64);
+        let object_pb = self.object_pb(stats.total_objects);
+        object_pb.set_position(stats.indexed_objects as u64);
 
-        let delta_pb = self.delta_pb(stats.total_deltas());
-        delta_pb.set_position(stats.indexed_deltas() as u64);
+        let delta_pb = self.delta_pb(stats.total_deltas);
+        delta_pb.set_position(stats.indexed_deltas as u64);
     }
 
     pub fn object_dl_pb(&self, total: usize) -> &ProgressBar {
@@ -0,0 +1,176 @@
+use console::{style, Term};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::report::{Event, Reporter};
+
+use super::terminal::{is_error, lines, Verbosity};
+
+/// One display shared by updates running in parallel, with a line per target
+///
+/// Each target reports to its own [`TargetReporter`], whose line shows what it
+/// is doing. The lines printed for events are prefixed with the target's name
+/// so they stay readable when interleaved. When not interactive, only these
+/// prefixed lines are printed.
+pub struct MultiDisplay {
+    m: MultiProgress,
+    verbosity: Verbosity,
+    interactive: bool,
+    name_width: usize,
+}
+
+impl MultiDisplay {
+    pub fn new(verbosity: Verbosity) -> Self {
+        let interactive = Term::stdout().is_term();
+        let m = MultiProgress::new();
+
+        if !interactive || verbosity == Verbosity::Quiet || cfg!(test) {
+            m.set_draw_target(ProgressDrawTarget::hidden());
+        }
+
+        Self {
+            m,
+            verbosity,
+            interactive,
+            name_width: 0,
+        }
+    }
+
+    /// Align the lines of the targets on names up to `width` characters
+    pub fn name_width(mut self, width: usize) -> Self {
+        self.name_width = width;
+        self
+    }
+
+    /// A reporter for the target called `name`, drawn on its own line
+    pub fn reporter(&self, name: &str) -> TargetReporter {
+        let line = self.m.add(ProgressBar::new_spinner());
+        line.set_style(
+            ProgressStyle::default_spinner()
+                .template("{prefix} {spinner} {wide_msg}")
+                .unwrap(),
+        );
+        line.set_prefix(format!("{:width$}", name, width = self.name_width));
+        line.set_message("waiting");
+
+        TargetReporter {
+            name: name.to_string(),
+            line,
+            m: self.m.clone(),
+            verbosity: self.verbosity,
+            interactive: self.interactive,
+        }
+    }
+}
+
+/// Reports the events of one target of a [`MultiDisplay`]
+pub struct TargetReporter {
+    name: String,
+    line: ProgressBar,
+    m: MultiProgress,
+    verbosity: Verbosity,
+    interactive: bool,
+}
+
+impl TargetReporter {
+    /// Print `line` for this target above the lines of every target
+    pub fn println(&self, line: &str) {
+        let line = format!("{} {}", style(format!("{}:", self.name)).bold(), line);
+
+        if self.interactive && !self.m.is_hidden() {
+            self.m.println(line).unwrap();
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    /// Print the error `line` for this target on stderr
+    pub fn eprintln(&self, line: &str) {
+        let line = format!("{} {}", style(format!("{}:", self.name)).bold(), line);
+
+        if self.interactive && !self.m.is_hidden() {
+            self.m.suspend(|| eprintln!("{}", line));
+        } else {
+            eprintln!("{}", line);
+        }
+    }
+
+    /// Mark the target as started, ticking its spinner
+    pub fn start(&self) {
+        // Lines are finished at the end of each attempt
+        self.line.reset();
+        self.line
+            .enable_steady_tick(std::time::Duration::from_millis(100));
+    }
+
+    /// Stop the spinner of the target and leave `status` on its line
+    pub fn finish(&self, status: &str) {
+        self.line.finish_with_message(status.to_string());
+    }
+}
+
+impl Reporter for TargetReporter {
+    fn report(&self, event: &Event) {
+        let status = match event {
+            Event::FetchStarted { refspec, .. } => Some(format!("fetching {}", refspec)),
+            Event::FetchProgress(stats) if stats.total_objects > 0 => Some(format!(
+                "fetching {}/{} objects",
+                stats.received_objects, stats.total_objects
+            )),
+            Event::Deepening { depth, .. } => Some(format!("deepening to {} commits", depth)),
+            Event::MergeAnalysis { branch, .. } => Some(format!("merging into {}", branch)),
+            Event::Conflict { .. } => Some("conflicts".to_string()),
+            Event::PushStarted { remote, .. } => Some(format!("pushing to {}", remote)),
+            Event::PushProgress { current, total, .. } => {
+                Some(format!("pushing {}/{} objects", current, total))
+            }
+            _ => None,
+        };
+        if let Some(status) = status {
+            self.line.set_message(status);
+        }
+
+        for (verbosity, line) in lines(event) {
+            if self.verbosity < verbosity {
+                continue;
+            }
+            if is_error(event) {
+                self.eprintln(&line);
+            } else {
+                self.println(&line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_line() {
+        let display = MultiDisplay::new(Verbosity::Quiet).name_width(8);
+        let api = display.reporter("api");
+        let web = display.reporter("services");
+
+        api.start();
+        api.report(&Event::FetchStarted {
+            remote: "origin".to_string(),
+            refspec: "main".to_string(),
+        });
+        web.report(&Event::PushProgress {
+            current: 1,
+            total: 3,
+            bytes: 10,
+        });
+
+        assert_eq!(api.line.prefix(), "api     ");
+        assert_eq!(api.line.message(), "fetching main");
+        assert_eq!(web.line.message(), "pushing 1/3 objects");
+
+        api.finish("up to date");
+
+        assert!(api.line.is_finished());
+        assert_eq!(api.line.message(), "up to date");
+        assert!(!web.line.is_finished());
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt::Display;
+
+use console::{measure_text_width, pad_str, style, Alignment};
+
+use crate::{
+    app::{MergeOutcome, UpdateOutcome},
+    utils::git::redact_credentials,
+};
+
+use super::terminal::Verbosity;
+
+/// How the update of a repository ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Updated,
+    UpToDate,
+    /// The base branch could not be fast-forwarded into the branch, so it was not merged
+    Diverged,
+    Conflict,
+    Error,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Updated => write!(f, "updated"),
+            Self::UpToDate => write!(f, "up to date"),
+            Self::Diverged => write!(f, "diverged"),
+            Self::Conflict => write!(f, "conflict"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// The status of an update and details about it, e.g. the conflicting paths
+pub fn status(result: &Result<UpdateOutcome, git2::Error>) -> (Status, String) {
+    match result {
+        Err(e) => (Status::Error, redact_credentials(e.message())),
+        Ok(outcome) => match &outcome.merge {
+            MergeOutcome::Conflicts(paths) => (Status::Conflict, paths.join(", ")),
+            merge if outcome.is_updated() => (Status::Updated, merge.to_string()),
+            MergeOutcome::Diverged => (
+                Status::Diverged,
+                format!("{} not merged, fast-forward only", outcome.base),
+            ),
+            MergeOutcome::Skipped(reason) => (Status::UpToDate, reason.clone()),
+            _ => (Status::UpToDate, String::new()),
+        },
+    }
+}
+
+struct Row {
+    name: String,
+    status: Status,
+    details: String,
+}
+
+/// The results of updating several repositories, printed as a table
+#[derive(Default)]
+pub struct Summary {
+    rows: Vec<Row>,
+}
+
+impl Summary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the result of updating the repository called `name`
+    pub fn push(&mut self, name: &str, result: &Result<UpdateOutcome, git2::Error>) {
+        let (status, details) = status(result);
+
+        self.rows.push(Row {
+            name: name.to_string(),
+            status,
+            details,
+        });
+    }
+
+    /// How many repositories ended with `status`
+    pub fn count(&self, status: Status) -> usize {
+        self.rows.iter().filter(|row| row.status == status).count()
+    }
+
+    /// The table of results, only conflicts and errors when quiet
+    pub fn render(&self, verbosity: Verbosity) -> String {
+        let rows: Vec<&Row> = self
+            .rows
+            .iter()
+            .filter(|row| {
+                verbosity > Verbosity::Quiet
+                    || matches!(row.status, Status::Conflict | Status::Error)
+            })
+            .collect();
+
+        let name_width = rows
+            .iter()
+            .map(|row| measure_text_width(&row.name))
+            .max()
+            .unwrap_or(0);
+        let status_width = "up to date".len();
+
+        let mut table = String::new();
+        for row in rows {
+            let status =
+                pad_str(&row.status.to_string(), status_width, Alignment::Left, None).into_owned();
+            let status = match row.status {
+                Status::Updated => style(status).green(),
+                Status::UpToDate => style(status).dim(),
+                Status::Diverged => style(status).yellow(),
+                Status::Conflict => style(status).yellow(),
+                Status::Error => style(status).red(),
+            };
+
+            let line = format!(
+                "{}  {}  {}",
+                pad_str(&row.name, name_width, Alignment::Left, None),
+                status,
+                row.details
+            );
+            table.push_str(line.trim_end());
+            table.push('\n');
+        }
+
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::Oid;
+
+    use super::*;
+
+    fn outcome(merge: MergeOutcome, pushed: bool) -> UpdateOutcome {
+        UpdateOutcome {
+            branch: "refs/heads/pr".to_string(),
+            base: "main".to_string(),
+            head_before: Oid::zero(),
+            head_after: Oid::zero(),
+            pull: MergeOutcome::UpToDate,
+            merge,
+            pushed,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        console::set_colors_enabled(false);
+
+        let mut summary = Summary::new();
+        summary.push("api", &Ok(outcome(MergeOutcome::Merged(Oid::zero()), true)));
+        summary.push("web", &Ok(outcome(MergeOutcome::UpToDate, false)));
+        summary.push(
+            "services/db",
+            &Ok(outcome(
+                MergeOutcome::Conflicts(vec!["a.txt".to_string(), "b.txt".to_string()]),
+                false,
+            )),
+        );
+        summary.push("docs", &Ok(outcome(MergeOutcome::Diverged, false)));
+        summary.push("cache", &Err(git2::Error::from_str("no remote")));
+
+        assert_eq!(summary.count(Status::UpToDate), 1);
+        assert_eq!(summary.count(Status::Diverged), 1);
+        assert_eq!(
+            summary.render(Verbosity::Normal),
+            format!(
+                "api          updated     merged in {}\n\
+                 web          up to date\n\
+                 services/db  conflict    a.txt, b.txt\n\
+                 docs         diverged    main not merged, fast-forward only\n\
+                 cache        error       no remote\n",
+                Oid::zero()
+            )
+        );
+        assert_eq!(
+            summary.render(Verbosity::Quiet),
+            "services/db  conflict    a.txt, b.txt\n\
+             cache        error       no remote\n"
+        );
+    }
+}
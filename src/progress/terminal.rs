@@ -0,0 +1,478 @@
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use console::{style, Emoji, Term};
+use indicatif::ProgressBar;
+
+use crate::report::{Analysis, Event, Reporter, TransferProgress};
+
+use super::{fetch::FetchProgress, push::PushProgress};
+
+static LOOKING_GLASS: Emoji<'_, '_> = Emoji("🔍  ", "");
+static TRUCK: Emoji<'_, '_> = Emoji("🚚  ", "");
+
+/// How much the [`TerminalReporter`] prints
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only conflicts and errors
+    Quiet,
+    /// What is being fetched, merged and pushed
+    #[default]
+    Normal,
+    /// Also the commits fetched and created
+    Verbose,
+    /// Also transfer statistics
+    VeryVerbose,
+}
+
+/// Reports events on the terminal
+///
+/// When interactive, transfers are shown with progress bars and waits with a
+/// spinner. Otherwise, e.g. when stdout is piped to a log, only plain lines are
+/// printed.
+pub struct TerminalReporter {
+    verbosity: Verbosity,
+    interactive: bool,
+
+    fetch: Mutex<Option<FetchProgress>>,
+    fetch_stats: Mutex<TransferProgress>,
+    push: Mutex<Option<PushProgress>>,
+    wait: Mutex<Option<ProgressBar>>,
+}
+
+impl Default for TerminalReporter {
+    fn default() -> Self {
+        Self {
+            verbosity: Verbosity::default(),
+            interactive: Term::stdout().is_term(),
+
+            fetch: Mutex::new(None),
+            fetch_stats: Mutex::new(TransferProgress::default()),
+            push: Mutex::new(None),
+            wait: Mutex::new(None),
+        }
+    }
+}
+
+impl TerminalReporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How much to print, [`Verbosity::Normal`] by default
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Whether to draw progress bars, by default only when stdout is a terminal
+    pub fn interactive(mut self, interactive: bool) -> Self {
+        self.interactive = interactive;
+        self
+    }
+
+    fn show_bars(&self) -> bool {
+        self.interactive && self.verbosity > Verbosity::Quiet
+    }
+
+    /// Print a line without breaking the progress bars being drawn
+    fn println(&self, verbosity: Verbosity, line: String) {
+        if self.verbosity >= verbosity {
+            self.write_line(line, false);
+        }
+    }
+
+    /// Print `line` on stdout, or on stderr for errors, around the progress bars being drawn
+    fn write_line(&self, line: String, error: bool) {
+        match (self.fetch.lock().unwrap().as_ref(), error) {
+            (Some(pb), false) => pb.println(line).unwrap(),
+            (Some(pb), true) => pb.suspend(|| eprintln!("{}", line)),
+            (None, false) => println!("{}", line),
+            (None, true) => eprintln!("{}", line),
+        }
+    }
+
+    /// The lines printed for `event` at this verbosity
+    fn printed(&self, event: &Event) -> Vec<String> {
+        lines(event)
+            .into_iter()
+            .filter(|(verbosity, _)| self.verbosity >= *verbosity)
+            .map(|(_, line)| line)
+            .collect()
+    }
+}
+
+impl Reporter for TerminalReporter {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::FetchStarted { .. } => {
+                *self.fetch_stats.lock().unwrap() = TransferProgress::default();
+
+                if self.show_bars() {
+                    *self.fetch.lock().unwrap() = Some(FetchProgress::new());
+                }
+            }
+            Event::FetchProgress(stats) => {
+                *self.fetch_stats.lock().unwrap() = *stats;
+
+                if let Some(pb) = self.fetch.lock().unwrap().as_ref() {
+                    pb.process(stats);
+                }
+            }
+            Event::FetchDone { .. } => {
+                self.fetch.lock().unwrap().take();
+
+                let stats = *self.fetch_stats.lock().unwrap();
+                if stats.total_objects > 0 {
+                    self.println(
+                        Verbosity::VeryVerbose,
+                        format!(
+                            "Received {} objects ({} bytes), {} deltas",
+                            stats.received_objects, stats.received_bytes, stats.indexed_deltas
+                        ),
+                    );
+                }
+            }
+            Event::PushStarted { .. } if self.show_bars() => {
+                let pb = PushProgress::new();
+                for (_, line) in lines(event) {
+                    pb.pb().println(line);
+                }
+                *self.push.lock().unwrap() = Some(pb);
+
+                return;
+            }
+            Event::PushProgress { current, total, .. } => {
+                if let Some(pb) = self.push.lock().unwrap().as_ref() {
+                    let pb = pb.pb();
+                    pb.set_length(*total as u64);
+                    pb.set_position(*current as u64);
+                }
+            }
+            Event::PushDone { .. } => {
+                self.push.lock().unwrap().take();
+            }
+            Event::Waiting { duration } if self.show_bars() => {
+                let pb = ProgressBar::new(duration.as_secs()).with_message("Waiting...");
+
+                pb.set_style(
+                    indicatif::ProgressStyle::default_bar()
+                        .template("{spinner} {elapsed} {msg}")
+                        .unwrap(),
+                );
+                pb.enable_steady_tick(std::time::Duration::from_millis(100));
+
+                *self.wait.lock().unwrap() = Some(pb);
+
+                return;
+            }
+            Event::WaitDone => {
+                if let Some(pb) = self.wait.lock().unwrap().take() {
+                    pb.finish();
+                }
+            }
+            _ => {}
+        }
+
+        for line in self.printed(event) {
+            self.write_line(line, is_error(event));
+        }
+    }
+}
+
+/// Whether `event` is an error, printed on stderr even when quiet
+pub(super) fn is_error(event: &Event) -> bool {
+    matches!(event, Event::FetchFailed { .. })
+}
+
+/// The lines printed for `event`, with the verbosity from which each is printed
+///
+/// Progress is drawn by the reporters themselves, so events only carrying
+/// progress have no lines.
+pub(super) fn lines(event: &Event) -> Vec<(Verbosity, String)> {
+    match event {
+        Event::FetchStarted { remote, refspec } => vec![(
+            Verbosity::Normal,
+            format!(
+                "{}Fetching {} for repo from {}...",
+                LOOKING_GLASS,
+                style(refspec).italic().dim().blue(),
+                style(remote).italic().bold().green()
+            ),
+        )],
+        Event::FetchProgress(_) => vec![],
+        Event::FetchFailed { message, .. } => {
+            vec![(Verbosity::Quiet, format!("Error: {}", message))]
+        }
+        Event::FetchDone { refspec, oid, .. } => vec![(
+            Verbosity::Verbose,
+            format!("Fetched {} at {}", refspec, style(oid).dim()),
+        )],
+        Event::Deepening { remote, depth } => vec![(
+            Verbosity::Normal,
+            format!(
+                "{}Deepening shallow history from {} to {} commits...",
+                LOOKING_GLASS,
+                style(remote).italic().bold().green(),
+                depth
+            ),
+        )],
+        Event::Pruned { refs, .. } => refs
+            .iter()
+            .map(|name| (Verbosity::Verbose, format!("Pruned {}", name)))
+            .collect(),
+        Event::MergeAnalysis { branch, analysis } => vec![match analysis {
+            Analysis::FastForward => (Verbosity::Normal, "Doing a fast forward".to_string()),
+            Analysis::UpToDate => (Verbosity::Normal, "Nothing to do...".to_string()),
+            Analysis::Normal => (Verbosity::Verbose, format!("Merging into {}", branch)),
+        }],
+        Event::FastForward { branch, oid } => vec![(
+            Verbosity::Normal,
+            format!("Fast-Forward: Setting {} to id: {}", branch, oid),
+        )],
+        Event::Conflict { paths } => {
+            let mut lines = vec![(Verbosity::Quiet, "Merge conflicts detected...".to_string())];
+            lines.extend(
+                paths
+                    .iter()
+                    .map(|path| (Verbosity::Verbose, format!("  {}", style(path).red()))),
+            );
+            lines
+        }
+        Event::GitlinkResolved { path, oid } => vec![(
+            Verbosity::Normal,
+            format!(
+                "Resolved submodule {} to {}",
+                style(path).bold(),
+                style(oid).dim()
+            ),
+        )],
+        Event::ResolutionReused { path } => vec![(
+            Verbosity::Normal,
+            format!("Resolved {} as it was resolved before", style(path).bold()),
+        )],
+        Event::ResolutionRecorded { path } => vec![(
+            Verbosity::Verbose,
+            format!("Recorded the resolution of {}", style(path).bold()),
+        )],
+        Event::MergedWithOptions { path, options } => vec![(
+            Verbosity::Normal,
+            format!(
+                "Merged {} with {}",
+                style(path).bold(),
+                style(options).dim()
+            ),
+        )],
+        Event::MergedWithDriver { path, driver } => vec![(
+            Verbosity::Normal,
+            format!(
+                "Merged {} with the {} merge driver",
+                style(path).bold(),
+                style(driver).dim()
+            ),
+        )],
+        Event::LockfileRegenerated { path, command } => vec![(
+            Verbosity::Normal,
+            format!(
+                "Regenerated {} with {}",
+                style(path).bold(),
+                style(command).dim()
+            ),
+        )],
+        Event::SubmoduleUpdated { path, oid } => vec![(
+            Verbosity::Verbose,
+            format!(
+                "Updated submodule {} to {}",
+                style(path).bold(),
+                style(oid).dim()
+            ),
+        )],
+        Event::LfsCheckout { paths } => {
+            let mut lines = vec![(
+                Verbosity::Verbose,
+                format!("Checked out {} LFS files", paths.len()),
+            )];
+            lines.extend(
+                paths
+                    .iter()
+                    .map(|path| (Verbosity::VeryVerbose, format!("  {}", path))),
+            );
+            lines
+        }
+        Event::CommitCreated { oid, message } => vec![(
+            Verbosity::Verbose,
+            format!("Created {} {}", style(oid).dim(), message.trim()),
+        )],
+        Event::WorktreeUpdated { path } => vec![(
+            Verbosity::Normal,
+            format!("Updated worktree {}", style(path.display()).bold()),
+        )],
+        Event::PushStarted { remote, .. } => vec![(
+            Verbosity::Normal,
+            format!(
+                "{}Pushing {} for repo...",
+                TRUCK,
+                style(remote).italic().bold()
+            ),
+        )],
+        Event::PushProgress { .. } => vec![],
+        Event::PushDone { remote } => vec![(Verbosity::Verbose, format!("Pushed to {}", remote))],
+        Event::PullRequestCommented { number, url } => vec![(
+            Verbosity::Normal,
+            format!(
+                "Commented the conflicts on pull request #{} {}",
+                number,
+                style(url).dim()
+            ),
+        )],
+        Event::PullRequestCommentRemoved { number } => vec![(
+            Verbosity::Normal,
+            format!("Removed the conflict comment from pull request #{}", number),
+        )],
+        Event::ForgeMerged { number, oid } => vec![(
+            Verbosity::Normal,
+            format!(
+                "Pull request #{} was updated by the forge to {}",
+                number,
+                style(oid).dim()
+            ),
+        )],
+        Event::ForgeRebased { number, oid } => vec![(
+            Verbosity::Normal,
+            format!(
+                "Pull request #{} was rebased by the forge to {}",
+                number,
+                style(oid).dim()
+            ),
+        )],
+        Event::BaseCommitChosen {
+            base,
+            tip,
+            tip_status,
+            oid,
+        } => vec![(
+            Verbosity::Normal,
+            format!(
+                "The CI of {} at {} {}, merging {} instead",
+                base,
+                style(tip).dim(),
+                tip_status,
+                style(oid).dim()
+            ),
+        )],
+        Event::BaseSkipped {
+            base,
+            tip,
+            tip_status,
+        } => vec![(
+            Verbosity::Normal,
+            format!(
+                "Not merging {}: the CI of {} {} and no earlier commit passed",
+                base,
+                style(tip).dim(),
+                tip_status
+            ),
+        )],
+        Event::PullRequestSkipped { reason, .. } => {
+            vec![(Verbosity::Normal, format!("Skipping: {}", reason))]
+        }
+        Event::WaitingForChecks { number, oid } => vec![(
+            Verbosity::Normal,
+            format!(
+                "Waiting for the checks of pull request #{} at {}",
+                number,
+                style(oid).dim()
+            ),
+        )],
+        Event::BaseMoved { base, oid } => vec![(
+            Verbosity::Normal,
+            format!("{} moved to {}, updating again", base, style(oid).dim()),
+        )],
+        Event::PullRequestMerged { number, method } => vec![(
+            Verbosity::Normal,
+            format!(
+                "Merged pull request #{} ({})",
+                number,
+                method.to_possible_value().unwrap().get_name()
+            ),
+        )],
+        Event::AutoMergeStopped { reason } => {
+            vec![(Verbosity::Normal, format!("Not merging: {}", reason))]
+        }
+        Event::ForgeFallback { reason } => {
+            vec![(Verbosity::Normal, format!("Merging locally: {}", reason))]
+        }
+        Event::ForgeFailed { message } => {
+            vec![(Verbosity::Normal, format!("Warning: {}", message))]
+        }
+        Event::Waiting { duration } => vec![(
+            Verbosity::Verbose,
+            format!("Waiting {}...", humantime::format_duration(*duration)),
+        )],
+        Event::WaitDone => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::Oid;
+
+    use super::*;
+
+    fn reporter(verbosity: Verbosity) -> TerminalReporter {
+        TerminalReporter::new()
+            .verbosity(verbosity)
+            .interactive(false)
+    }
+
+    #[test]
+    fn test_verbosity() {
+        let fetch_failed = Event::FetchFailed {
+            remote: "origin".to_string(),
+            refspec: "main".to_string(),
+            message: "could not resolve host".to_string(),
+        };
+        let conflict = Event::Conflict {
+            paths: vec!["file.txt".to_string()],
+        };
+        let fast_forward = Event::FastForward {
+            branch: "pr".to_string(),
+            oid: Oid::zero(),
+        };
+        let commit = Event::CommitCreated {
+            oid: Oid::zero(),
+            message: "Merge".to_string(),
+        };
+
+        let quiet = reporter(Verbosity::Quiet);
+        assert_eq!(
+            quiet.printed(&fetch_failed),
+            vec!["Error: could not resolve host"]
+        );
+        assert_eq!(
+            quiet.printed(&conflict),
+            vec!["Merge conflicts detected..."]
+        );
+        assert!(quiet.printed(&fast_forward).is_empty());
+
+        let normal = reporter(Verbosity::Normal);
+        assert_eq!(normal.printed(&fetch_failed).len(), 1);
+        assert_eq!(normal.printed(&conflict).len(), 1);
+        assert_eq!(normal.printed(&fast_forward).len(), 1);
+        assert!(normal.printed(&commit).is_empty());
+
+        let verbose = reporter(Verbosity::Verbose);
+        assert_eq!(verbose.printed(&conflict).len(), 2);
+        assert_eq!(verbose.printed(&commit).len(), 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(is_error(&Event::FetchFailed {
+            remote: "origin".to_string(),
+            refspec: "main".to_string(),
+            message: "timed out".to_string(),
+        }));
+        assert!(!is_error(&Event::Conflict { paths: vec![] }));
+    }
+}
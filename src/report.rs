@@ -0,0 +1,170 @@
+//! Events emitted while updating a branch and the [`Reporter`] trait receiving them.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use git2::Oid;
+
+use crate::forge::{CiStatus, MergeMethod};
+
+/// Counters of a fetch transfer, see [`git2::Progress`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub total_objects: usize,
+    pub received_objects: usize,
+    pub indexed_objects: usize,
+    pub total_deltas: usize,
+    pub indexed_deltas: usize,
+    pub received_bytes: usize,
+}
+
+impl From<git2::Progress<'_>> for TransferProgress {
+    fn from(stats: git2::Progress<'_>) -> Self {
+        Self {
+            total_objects: stats.total_objects(),
+            received_objects: stats.received_objects(),
+            indexed_objects: stats.indexed_objects(),
+            total_deltas: stats.total_deltas(),
+            indexed_deltas: stats.indexed_deltas(),
+            received_bytes: stats.received_bytes(),
+        }
+    }
+}
+
+/// The kind of merge needed to bring a commit into a branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Analysis {
+    UpToDate,
+    FastForward,
+    Normal,
+}
+
+/// Something that happened while updating a branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Fetching `refspec` from `remote` started
+    FetchStarted { remote: String, refspec: String },
+    /// Objects were received during a fetch
+    FetchProgress(TransferProgress),
+    /// The fetch failed with a network error and the local state is used instead
+    FetchFailed {
+        remote: String,
+        refspec: String,
+        message: String,
+    },
+    /// Fetching `refspec` finished, resolving to `oid`
+    FetchDone {
+        remote: String,
+        refspec: String,
+        oid: Oid,
+    },
+    /// A shallow history is fetched again `depth` commits deep to find a merge base
+    Deepening { remote: String, depth: i32 },
+    /// Remote-tracking branches of `remote` whose branch was deleted were removed
+    Pruned { remote: String, refs: Vec<String> },
+    /// A merge analysis was made for `branch`
+    MergeAnalysis { branch: String, analysis: Analysis },
+    /// `branch` was fast-forwarded to `oid`
+    FastForward { branch: String, oid: Oid },
+    /// The merge stopped on conflicts in `paths`
+    Conflict { paths: Vec<String> },
+    /// A conflict on the commit of the submodule at `path` was resolved to `oid`
+    GitlinkResolved { path: String, oid: Oid },
+    /// The conflict at `path` was resolved as it was the last time
+    ResolutionReused { path: String },
+    /// How the conflict at `path` was resolved was recorded, to be reused
+    ResolutionRecorded { path: String },
+    /// The conflict at `path` was merged away with the merge `options` of the file
+    MergedWithOptions { path: String, options: String },
+    /// The conflict at `path` was merged away by the merge `driver` of its attributes
+    MergedWithDriver { path: String, driver: String },
+    /// The conflicting lockfile at `path` was regenerated with `command`
+    LockfileRegenerated { path: String, command: String },
+    /// The submodule at `path` was checked out at `oid`
+    SubmoduleUpdated { path: String, oid: Oid },
+    /// The LFS files at `paths` were checked out with their content
+    LfsCheckout { paths: Vec<String> },
+    /// A merge commit was created
+    CommitCreated { oid: Oid, message: String },
+    /// The other worktree at `path`, having the branch checked out, was updated
+    WorktreeUpdated { path: PathBuf },
+    /// Pushing `refspecs` to `remote` started
+    PushStarted {
+        remote: String,
+        refspecs: Vec<String>,
+    },
+    /// Objects were sent during a push
+    PushProgress {
+        current: usize,
+        total: usize,
+        bytes: usize,
+    },
+    /// Pushing to `remote` finished
+    PushDone { remote: String },
+    /// The conflicts were commented on the pull request `number`
+    PullRequestCommented { number: u64, url: String },
+    /// The conflict comment was removed from the pull request `number`, which merges cleanly again
+    PullRequestCommentRemoved { number: u64 },
+    /// The forge merged the base branch into the pull request `number`, moving it to `oid`
+    ForgeMerged { number: u64, oid: Oid },
+    /// The forge rebased the pull request `number` onto the base branch, moving it to `oid`
+    ForgeRebased { number: u64, oid: Oid },
+    /// The forge could not merge the base branch, so it is merged locally
+    ForgeFallback { reason: String },
+    /// The tip of `base` did not pass its CI, so `oid`, the most recent commit that did, is merged instead
+    BaseCommitChosen {
+        base: String,
+        tip: Oid,
+        tip_status: CiStatus,
+        oid: Oid,
+    },
+    /// The tip of `base` did not pass its CI, and neither did the commits before it, so it is not merged
+    BaseSkipped {
+        base: String,
+        tip: Oid,
+        tip_status: CiStatus,
+    },
+    /// The branch is not updated, its pull request `number`, if any, not matching the filters
+    PullRequestSkipped { number: Option<u64>, reason: String },
+    /// Waiting for the checks of the pull request `number` at `oid` to pass
+    WaitingForChecks { number: u64, oid: Oid },
+    /// `base` moved to `oid` while waiting for the checks, so the branch is updated again
+    BaseMoved { base: String, oid: Oid },
+    /// The pull request `number` was merged on the forge
+    PullRequestMerged { number: u64, method: MergeMethod },
+    /// The pull request is not merged, for this reason
+    AutoMergeStopped { reason: String },
+    /// A call to the forge hosting the pull request failed, the update went on without it
+    ForgeFailed { message: String },
+    /// Waiting `duration` before the next attempt
+    Waiting { duration: Duration },
+    /// The wait before the next attempt is over
+    WaitDone,
+}
+
+/// Receives the [`Event`]s of an update, e.g. to display them
+///
+/// Reporters are shared with the callbacks of libgit2 and between threads, so
+/// they take `&self` and any state needs interior mutability.
+pub trait Reporter: Send + Sync {
+    fn report(&self, event: &Event);
+}
+
+impl<R: Reporter + ?Sized> Reporter for Arc<R> {
+    fn report(&self, event: &Event) {
+        (**self).report(event)
+    }
+}
+
+impl<R: Reporter + ?Sized> Reporter for Box<R> {
+    fn report(&self, event: &Event) {
+        (**self).report(event)
+    }
+}
+
+/// A reporter discarding every event
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SilentReporter;
+
+impl Reporter for SilentReporter {
+    fn report(&self, _event: &Event) {}
+}
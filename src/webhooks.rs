@@ -0,0 +1,551 @@
+//! Outbound webhooks notified of how updates went
+//!
+//! A [`Notification`] is built from the result of an update when it is worth
+//! telling someone about, then posted as JSON to every configured [`Webhook`],
+//! either as a generic payload or formatted for Slack or Microsoft Teams.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use git2::{ErrorCode, Oid};
+use serde_json::{json, Value};
+
+use crate::{
+    app::{MergeOutcome, UpdateOutcome},
+    utils::git::redact_credentials,
+};
+
+/// What a notification is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NotifyEvent {
+    /// The base branch was merged and pushed
+    Merged,
+    /// Merging the base branch stopped on conflicts
+    Conflict,
+    /// The remote refused the push
+    PushRejected,
+    /// The remote refused the credentials
+    AuthFailed,
+    /// The update failed for another reason
+    Error,
+}
+
+impl NotifyEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Merged => "merged",
+            Self::Conflict => "conflict",
+            Self::PushRejected => "push-rejected",
+            Self::AuthFailed => "auth-failed",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// How the body of a webhook is laid out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum WebhookFormat {
+    /// Every field of the notification, see [`Notification`]
+    #[default]
+    Json,
+    /// A Slack incoming webhook message
+    Slack,
+    /// A Microsoft Teams incoming webhook card
+    Teams,
+}
+
+/// Something that happened to a repository, as sent to webhooks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub event: NotifyEvent,
+    /// The name of the repository, as shown in the terminal
+    pub repository: String,
+    /// The updated branch, without `refs/heads/`, unknown when the update failed
+    pub branch: Option<String>,
+    pub base: Option<String>,
+    /// HEAD after the update
+    pub head: Option<Oid>,
+    /// The conflicting paths
+    pub paths: Vec<String>,
+    /// A sentence describing what happened
+    pub message: String,
+}
+
+impl Notification {
+    /// The notification for the result of updating `repository`, if anything is worth telling
+    pub fn from_result(
+        repository: &str,
+        result: &Result<UpdateOutcome, git2::Error>,
+    ) -> Option<Self> {
+        let notification = |event, message: String| Self {
+            event,
+            repository: repository.to_string(),
+            branch: None,
+            base: None,
+            head: None,
+            paths: vec![],
+            message: format!("{}: {}", repository, message),
+        };
+
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                let message = redact_credentials(e.message());
+                let event = match e.code() {
+                    ErrorCode::NotFastForward => NotifyEvent::PushRejected,
+                    ErrorCode::Auth => NotifyEvent::AuthFailed,
+                    _ => NotifyEvent::Error,
+                };
+
+                return Some(notification(event, message));
+            }
+        };
+
+        let branch = outcome.branch.trim_start_matches("refs/heads/");
+        let (event, message, paths) = match &outcome.merge {
+            MergeOutcome::Conflicts(paths) => (
+                NotifyEvent::Conflict,
+                format!(
+                    "conflicts merging {} into {} in {}",
+                    outcome.base,
+                    branch,
+                    paths.join(", ")
+                ),
+                paths.clone(),
+            ),
+            merge if outcome.is_updated() => (
+                NotifyEvent::Merged,
+                format!("{} into {}, {}", outcome.base, branch, merge),
+                vec![],
+            ),
+            _ => return None,
+        };
+
+        Some(Self {
+            branch: Some(branch.to_string()),
+            base: Some(outcome.base.clone()),
+            head: Some(outcome.head_after),
+            paths,
+            ..notification(event, message)
+        })
+    }
+
+    /// The body posted to a webhook of `format`
+    pub fn payload(&self, format: WebhookFormat) -> Value {
+        match format {
+            WebhookFormat::Json => json!({
+                "event": self.event.name(),
+                "repository": self.repository,
+                "branch": self.branch,
+                "base": self.base,
+                "head": self.head.map(|oid| oid.to_string()),
+                "paths": self.paths,
+                "message": self.message,
+            }),
+            WebhookFormat::Slack => json!({ "text": self.message }),
+            WebhookFormat::Teams => json!({
+                "@type": "MessageCard",
+                "@context": "https://schema.org/extensions",
+                "summary": self.message,
+                "themeColor": match self.event {
+                    NotifyEvent::Merged => "2EB886",
+                    NotifyEvent::Conflict => "DAA038",
+                    _ => "A30200",
+                },
+                "title": format!("update-pr: {}", self.event.name()),
+                "text": self.message,
+            }),
+        }
+    }
+}
+
+/// An url notified of updates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: String,
+    pub format: WebhookFormat,
+}
+
+/// Posts notifications to webhooks, retrying when they fail
+///
+/// Notifications are posted in the background so a slow or dead webhook
+/// doesn't hold updates back. Dropping the webhooks waits for the posts
+/// still running.
+pub struct Webhooks {
+    hooks: Vec<Webhook>,
+    events: Vec<NotifyEvent>,
+    poster: Poster,
+    pending: Mutex<Vec<JoinHandle<()>>>,
+    /// The last notification of each repository, not posted again while it stays the same
+    last: Mutex<HashMap<String, Notification>>,
+}
+
+/// Posts bodies to urls, retrying on failures
+#[derive(Clone)]
+struct Poster {
+    retries: u32,
+    retry_delay: Duration,
+    agent: ureq::Agent,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Webhooks {
+    /// No webhooks, notified of every event with a timeout of 10 seconds and 3 retries
+    pub fn new() -> Self {
+        Self {
+            hooks: vec![],
+            events: NotifyEvent::value_variants().to_vec(),
+            poster: Poster {
+                retries: 3,
+                retry_delay: Duration::from_secs(1),
+                agent: Self::agent(Duration::from_secs(10)),
+            },
+            pending: Mutex::new(vec![]),
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn agent(timeout: Duration) -> ureq::Agent {
+        ureq::AgentBuilder::new()
+            .timeout(timeout)
+            .user_agent(concat!("update-pr/", env!("CARGO_PKG_VERSION")))
+            .build()
+    }
+
+    /// Notify `url` with bodies laid out as `format`
+    pub fn add(mut self, url: impl Into<String>, format: WebhookFormat) -> Self {
+        self.hooks.push(Webhook {
+            url: url.into(),
+            format,
+        });
+        self
+    }
+
+    /// Only notify these events
+    pub fn events(mut self, events: &[NotifyEvent]) -> Self {
+        self.events = events.to_vec();
+        self
+    }
+
+    /// How long a webhook has to answer
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.poster.agent = Self::agent(timeout);
+        self
+    }
+
+    /// How many times a failed webhook is tried again
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.poster.retries = retries;
+        self
+    }
+
+    /// How long to wait before the first retry, doubled after each one
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.poster.retry_delay = delay;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Notify the result of updating `repository`, unless nothing changed since the last notification
+    ///
+    /// A conflict or an error lasting across updates is notified once, and
+    /// again only after an update of the repository went fine.
+    pub fn notify_result(&self, repository: &str, result: &Result<UpdateOutcome, git2::Error>) {
+        match Notification::from_result(repository, result) {
+            Some(notification) => self.notify(&notification),
+            None => {
+                self.last.lock().unwrap().remove(repository);
+            }
+        }
+    }
+
+    /// Post `notification` to every webhook in the background, logging the ones that failed
+    ///
+    /// Nothing is posted when it is the same as the last notification of its repository.
+    pub fn notify(&self, notification: &Notification) {
+        {
+            let mut last = self.last.lock().unwrap();
+            if last.get(&notification.repository) == Some(notification) {
+                tracing::debug!(event = notification.event.name(), "already notified");
+                return;
+            }
+            last.insert(notification.repository.clone(), notification.clone());
+        }
+        if !self.events.contains(&notification.event) {
+            return;
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|handle| !handle.is_finished());
+
+        for hook in &self.hooks {
+            let url = hook.url.clone();
+            let body = notification.payload(hook.format);
+            let event = notification.event.name();
+            let poster = self.poster.clone();
+
+            pending.push(thread::spawn(move || {
+                let _span = tracing::info_span!(
+                    "webhook",
+                    url = %redact_credentials(&url),
+                    event,
+                )
+                .entered();
+
+                match poster.post(&url, &body) {
+                    Ok(()) => tracing::info!("webhook notified"),
+                    Err(e) => tracing::warn!("webhook failed: {}", e),
+                }
+            }));
+        }
+    }
+
+    /// Wait for the notifications still being posted
+    pub fn wait(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        for handle in pending {
+            let _ = handle.join();
+        }
+    }
+
+    /// Post `body` to `url` now, retrying on network errors and server errors
+    pub fn post(&self, url: &str, body: &Value) -> Result<(), String> {
+        self.poster.post(url, body)
+    }
+}
+
+impl Drop for Webhooks {
+    fn drop(&mut self) {
+        self.wait();
+    }
+}
+
+impl Poster {
+    fn post(&self, url: &str, body: &Value) -> Result<(), String> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+
+        loop {
+            let error = match self.agent.post(url).send_json(body) {
+                Ok(_) => return Ok(()),
+                Err(ureq::Error::Status(code, _)) if code != 429 && code < 500 => {
+                    return Err(format!("the webhook answered {}", code));
+                }
+                Err(ureq::Error::Status(code, _)) => format!("the webhook answered {}", code),
+                Err(e) => redact_credentials(&e.to_string()),
+            };
+
+            if attempt >= self.retries {
+                return Err(error);
+            }
+
+            tracing::debug!(attempt, "webhook failed, retrying: {}", error);
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    use color_eyre::eyre::Result;
+
+    use super::*;
+
+    /// Answers the requests it receives with `statuses` in turn, sending their bodies back
+    fn receiver(statuses: Vec<u16>) -> Result<(String, mpsc::Receiver<Value>)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(statuses) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                tx.send(serde_json::from_slice(&body).unwrap()).unwrap();
+
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+
+        Ok((url, rx))
+    }
+
+    fn conflict() -> Result<UpdateOutcome, git2::Error> {
+        Ok(UpdateOutcome {
+            branch: "refs/heads/pr".to_string(),
+            base: "main".to_string(),
+            head_before: Oid::zero(),
+            head_after: Oid::zero(),
+            pull: MergeOutcome::UpToDate,
+            merge: MergeOutcome::Conflicts(vec!["a.txt".to_string()]),
+            pushed: false,
+        })
+    }
+
+    #[test]
+    fn test_notification() {
+        let notification = Notification::from_result("api", &conflict()).unwrap();
+
+        assert_eq!(notification.event, NotifyEvent::Conflict);
+        assert_eq!(
+            notification.message,
+            "api: conflicts merging main into pr in a.txt"
+        );
+        assert_eq!(
+            notification.payload(WebhookFormat::Slack),
+            json!({ "text": "api: conflicts merging main into pr in a.txt" })
+        );
+        assert_eq!(
+            notification.payload(WebhookFormat::Teams)["@type"],
+            "MessageCard"
+        );
+
+        let rejected = git2::Error::new(
+            ErrorCode::NotFastForward,
+            git2::ErrorClass::Reference,
+            "the remote rejected refs/heads/pr",
+        );
+        let notification = Notification::from_result("api", &Err(rejected)).unwrap();
+        assert_eq!(notification.event, NotifyEvent::PushRejected);
+        assert_eq!(notification.branch, None);
+
+        let mut up_to_date = conflict().unwrap();
+        up_to_date.merge = MergeOutcome::UpToDate;
+        assert_eq!(Notification::from_result("api", &Ok(up_to_date)), None);
+    }
+
+    #[test]
+    fn test_notify_retries() -> Result<()> {
+        let (url, bodies) = receiver(vec![503, 200])?;
+        let webhooks = Webhooks::new()
+            .add(&url, WebhookFormat::Json)
+            .retry_delay(Duration::from_millis(10));
+
+        webhooks.notify(&Notification::from_result("api", &conflict()).unwrap());
+
+        let first = bodies.recv_timeout(Duration::from_secs(5))?;
+        let second = bodies.recv_timeout(Duration::from_secs(5))?;
+        assert_eq!(first, second);
+        assert_eq!(first["event"], "conflict");
+        assert_eq!(first["branch"], "pr");
+        assert_eq!(first["paths"], json!(["a.txt"]));
+
+        // Client errors are not retried
+        let (url, _bodies) = receiver(vec![404, 200])?;
+        assert_eq!(
+            webhooks.post(&url, &json!({})),
+            Err("the webhook answered 404".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_notify_changes_only() -> Result<()> {
+        let (url, bodies) = receiver(vec![200; 3])?;
+        let webhooks = Webhooks::new().add(&url, WebhookFormat::Json);
+        let mut up_to_date = conflict().unwrap();
+        up_to_date.merge = MergeOutcome::UpToDate;
+
+        // The same conflict at every update, solved, then back
+        webhooks.notify_result("api", &conflict());
+        webhooks.notify_result("api", &conflict());
+        webhooks.notify_result("web", &conflict());
+        webhooks.notify_result("api", &Ok(up_to_date));
+        webhooks.notify_result("api", &conflict());
+        webhooks.wait();
+
+        let repositories: Vec<_> = bodies
+            .try_iter()
+            .map(|body| body["repository"].clone())
+            .collect();
+        assert_eq!(repositories.len(), 3);
+        assert_eq!(repositories.iter().filter(|name| *name == "api").count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_notify_in_background() -> Result<()> {
+        // Accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+
+        let webhooks = Webhooks::new()
+            .add(&url, WebhookFormat::Json)
+            .timeout(Duration::from_millis(300))
+            .retries(2)
+            .retry_delay(Duration::from_millis(10));
+
+        let start = std::time::Instant::now();
+        webhooks.notify(&Notification::from_result("api", &conflict()).unwrap());
+        assert!(start.elapsed() < Duration::from_millis(300));
+
+        webhooks.wait();
+        assert!(start.elapsed() >= Duration::from_millis(900));
+
+        drop(listener);
+        Ok(())
+    }
+
+    #[test]
+    fn test_notify_timeout() -> Result<()> {
+        // Accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+
+        let webhooks = Webhooks::new()
+            .timeout(Duration::from_millis(200))
+            .retries(1)
+            .retry_delay(Duration::from_millis(10));
+
+        assert!(webhooks.post(&url, &json!({})).is_err());
+
+        drop(listener);
+        Ok(())
+    }
+}